@@ -0,0 +1,530 @@
+use index::{_checked_op};
+use nn::{TensorRef, tensor_adjoint};
+use num::{Float};
+use tensor::*;
+
+/// Parameters of a 2D convolution over `[N, C, H, W]` inputs with
+/// `[O, C / groups, KH, KW]` weights.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Conv2dConfig {
+  pub stride:   (usize, usize),
+  pub padding:  (usize, usize),
+  pub dilation: (usize, usize),
+  pub groups:   usize,
+}
+
+impl Default for Conv2dConfig {
+  fn default() -> Conv2dConfig {
+    Conv2dConfig{
+      stride:   (1, 1),
+      padding:  (0, 0),
+      dilation: (1, 1),
+      groups:   1,
+    }
+  }
+}
+
+/// Parameters of a 1D convolution over `[N, C, L]` inputs with
+/// `[O, C / groups, K]` weights.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Conv1dConfig {
+  pub stride:   usize,
+  pub padding:  usize,
+  pub dilation: usize,
+  pub groups:   usize,
+}
+
+impl Default for Conv1dConfig {
+  fn default() -> Conv1dConfig {
+    Conv1dConfig{
+      stride:   1,
+      padding:  0,
+      dilation: 1,
+      groups:   1,
+    }
+  }
+}
+
+impl Conv2dConfig {
+  /// Whether the strides, dilations and groups are all nonzero.
+  pub fn _check(&self) -> bool {
+    self.stride.0 > 0 && self.stride.1 > 0 && self.dilation.0 > 0 && self.dilation.1 > 0 && self.groups > 0
+  }
+}
+
+impl Conv1dConfig {
+  fn _as_2d(&self) -> Conv2dConfig {
+    Conv2dConfig{
+      stride:   (1, self.stride),
+      padding:  (0, self.padding),
+      dilation: (1, self.dilation),
+      groups:   self.groups,
+    }
+  }
+}
+
+/// Parameters of a 2D pooling window. Padded positions never contribute:
+/// they are skipped by max pooling and not counted by average pooling.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Pool2dConfig {
+  pub kernel:   (usize, usize),
+  pub stride:   (usize, usize),
+  pub padding:  (usize, usize),
+}
+
+impl Pool2dConfig {
+  /// Non-overlapping windows of the given size.
+  pub fn new(kernel: (usize, usize)) -> Pool2dConfig {
+    Pool2dConfig{
+      kernel:   kernel,
+      stride:   kernel,
+      padding:  (0, 0),
+    }
+  }
+
+  /// Whether the kernel and strides are nonzero, and the padding is smaller
+  /// than the kernel, so that no window lies entirely in the padding.
+  pub fn _check(&self) -> bool {
+    self.kernel.0 > 0 && self.kernel.1 > 0 && self.stride.0 > 0 && self.stride.1 > 0 &&
+        self.padding.0 < self.kernel.0 && self.padding.1 < self.kernel.1
+  }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Pool1dConfig {
+  pub kernel:   usize,
+  pub stride:   usize,
+  pub padding:  usize,
+}
+
+impl Pool1dConfig {
+  pub fn new(kernel: usize) -> Pool1dConfig {
+    Pool1dConfig{
+      kernel:   kernel,
+      stride:   kernel,
+      padding:  0,
+    }
+  }
+
+  fn _as_2d(&self) -> Pool2dConfig {
+    Pool2dConfig{
+      kernel:   (1, self.kernel),
+      stride:   (1, self.stride),
+      padding:  (0, self.padding),
+    }
+  }
+}
+
+/// The number of windows along an axis, or `None` if the input is empty,
+/// the window is empty, or the window is larger than the padded input.
+fn _out_dim(size: usize, kernel: usize, stride: usize, padding: usize, dilation: usize) -> Option<usize> {
+  if size == 0 || kernel == 0 || stride == 0 {
+    return None;
+  }
+  let span = dilation * (kernel - 1) + 1;
+  if size + 2 * padding < span {
+    return None;
+  }
+  Some((size + 2 * padding - span) / stride + 1)
+}
+
+fn _dims4(shape: &[usize]) -> Option<(usize, usize, usize, usize)> {
+  if shape.len() != 4 {
+    return None;
+  }
+  Some((shape[0], shape[1], shape[2], shape[3]))
+}
+
+fn _conv2d_out_shape(x: &[usize], w: &[usize], cfg: &Conv2dConfig) -> Option<Vec<usize>> {
+  let (n, c, h, wd) = _dims4(x)?;
+  let (o, cg, kh, kw) = _dims4(w)?;
+  if !cfg._check() || c % cfg.groups != 0 || o % cfg.groups != 0 || c / cfg.groups != cg {
+    return None;
+  }
+  let oh = _out_dim(h, kh, cfg.stride.0, cfg.padding.0, cfg.dilation.0)?;
+  let ow = _out_dim(wd, kw, cfg.stride.1, cfg.padding.1, cfg.dilation.1)?;
+  Some(vec![n, o, oh, ow])
+}
+
+/// Calls `f(xi, wi, yi)` with the flat indices of every input, weight and
+/// output element which meet in one multiply-add of the convolution; `y` is
+/// the checked output shape.
+fn _conv2d_for_each<F: FnMut(usize, usize, usize)>(x: &[usize], w: &[usize], y: &[usize], cfg: &Conv2dConfig, mut f: F) {
+  let (n, c, h, wd) = (x[0], x[1], x[2], x[3]);
+  let (o, cg, kh, kw) = (w[0], w[1], w[2], w[3]);
+  let (oh, ow) = (y[2], y[3]);
+  let og = o / cfg.groups;
+  for b in 0 .. n {
+    for oc in 0 .. o {
+      let g = oc / og;
+      for ic in 0 .. cg {
+        let xc = g * cg + ic;
+        for ky in 0 .. kh {
+          for kx in 0 .. kw {
+            let wi = ((oc * cg + ic) * kh + ky) * kw + kx;
+            for oy in 0 .. oh {
+              let iy = (oy * cfg.stride.0 + ky * cfg.dilation.0) as isize - cfg.padding.0 as isize;
+              if iy < 0 || iy >= h as isize {
+                continue;
+              }
+              for ox in 0 .. ow {
+                let ix = (ox * cfg.stride.1 + kx * cfg.dilation.1) as isize - cfg.padding.1 as isize;
+                if ix < 0 || ix >= wd as isize {
+                  continue;
+                }
+                let xi = ((b * c + xc) * h + iy as usize) * wd + ix as usize;
+                let yi = ((b * o + oc) * oh + oy) * ow + ox;
+                f(xi, wi, yi);
+              }
+            }
+          }
+        }
+      }
+    }
+  }
+}
+
+/// The convolution of `x` with `w`, or `None` if the shapes or the config
+/// are invalid.
+pub fn conv2d<T: Float>(x: &Tensor<T>, w: &Tensor<T>, cfg: &Conv2dConfig) -> Option<Tensor<T>> {
+  let y_shape = _conv2d_out_shape(x.shape(), w.shape(), cfg)?;
+  let mut y = Tensor::zeros(y_shape.clone());
+  {
+    let ys = y.data_mut();
+    _conv2d_for_each(x.shape(), w.shape(), &y_shape, cfg, |xi, wi, yi| {
+      ys[yi] = ys[yi] + x.data()[xi] * w.data()[wi];
+    });
+  }
+  Some(y)
+}
+
+pub fn conv2d_grad_input<T: Float>(dy: &Tensor<T>, w: &Tensor<T>, x_shape: &[usize], cfg: &Conv2dConfig) -> Option<Tensor<T>> {
+  let y_shape = _conv2d_out_shape(x_shape, w.shape(), cfg)?;
+  if dy.shape() != &y_shape[..] {
+    return None;
+  }
+  let mut dx = Tensor::zeros(x_shape.to_vec());
+  {
+    let dxs = dx.data_mut();
+    _conv2d_for_each(x_shape, w.shape(), &y_shape, cfg, |xi, wi, yi| {
+      dxs[xi] = dxs[xi] + dy.data()[yi] * w.data()[wi];
+    });
+  }
+  Some(dx)
+}
+
+pub fn conv2d_grad_weight<T: Float>(x: &Tensor<T>, dy: &Tensor<T>, w_shape: &[usize], cfg: &Conv2dConfig) -> Option<Tensor<T>> {
+  let y_shape = _conv2d_out_shape(x.shape(), w_shape, cfg)?;
+  if dy.shape() != &y_shape[..] {
+    return None;
+  }
+  let mut dw = Tensor::zeros(w_shape.to_vec());
+  {
+    let dws = dw.data_mut();
+    _conv2d_for_each(x.shape(), w_shape, &y_shape, cfg, |xi, wi, yi| {
+      dws[wi] = dws[wi] + x.data()[xi] * dy.data()[yi];
+    });
+  }
+  Some(dw)
+}
+
+/// Calls `f(yi, xis)` for every output element of the pooling, with the
+/// flat indices of the (unpadded) input elements in its window; `y` is the
+/// checked output shape, so no window is empty.
+fn _pool2d_for_each<F: FnMut(usize, &[usize])>(x: &[usize], y: &[usize], cfg: &Pool2dConfig, mut f: F) {
+  let (n, c, h, wd) = (x[0], x[1], x[2], x[3]);
+  let (oh, ow) = (y[2], y[3]);
+  let mut window = Vec::with_capacity(cfg.kernel.0 * cfg.kernel.1);
+  for bc in 0 .. n * c {
+    for oy in 0 .. oh {
+      for ox in 0 .. ow {
+        window.clear();
+        for ky in 0 .. cfg.kernel.0 {
+          let iy = (oy * cfg.stride.0 + ky) as isize - cfg.padding.0 as isize;
+          if iy < 0 || iy >= h as isize {
+            continue;
+          }
+          for kx in 0 .. cfg.kernel.1 {
+            let ix = (ox * cfg.stride.1 + kx) as isize - cfg.padding.1 as isize;
+            if ix < 0 || ix >= wd as isize {
+              continue;
+            }
+            window.push((bc * h + iy as usize) * wd + ix as usize);
+          }
+        }
+        f((bc * oh + oy) * ow + ox, &window);
+      }
+    }
+  }
+}
+
+fn _pool2d_out_shape(x: &[usize], cfg: &Pool2dConfig) -> Option<Vec<usize>> {
+  let (n, c, h, wd) = _dims4(x)?;
+  if !cfg._check() {
+    return None;
+  }
+  let oh = _out_dim(h, cfg.kernel.0, cfg.stride.0, cfg.padding.0, 1)?;
+  let ow = _out_dim(wd, cfg.kernel.1, cfg.stride.1, cfg.padding.1, 1)?;
+  Some(vec![n, c, oh, ow])
+}
+
+fn _argmax<T: Float>(x: &Tensor<T>, window: &[usize]) -> usize {
+  let mut best = window[0];
+  for &xi in window[1 ..].iter() {
+    if x.data()[xi] > x.data()[best] {
+      best = xi;
+    }
+  }
+  best
+}
+
+/// Max pooling of `x`, or `None` if its shape or the config are invalid.
+pub fn max_pool2d<T: Float>(x: &Tensor<T>, cfg: &Pool2dConfig) -> Option<Tensor<T>> {
+  let y_shape = _pool2d_out_shape(x.shape(), cfg)?;
+  let mut y = Tensor::zeros(y_shape.clone());
+  {
+    let ys = y.data_mut();
+    _pool2d_for_each(x.shape(), &y_shape, cfg, |yi, window| {
+      ys[yi] = x.data()[_argmax(x, window)];
+    });
+  }
+  Some(y)
+}
+
+pub fn max_pool2d_grad<T: Float>(x: &Tensor<T>, dy: &Tensor<T>, cfg: &Pool2dConfig) -> Option<Tensor<T>> {
+  let y_shape = _pool2d_out_shape(x.shape(), cfg)?;
+  if dy.shape() != &y_shape[..] {
+    return None;
+  }
+  let mut dx = Tensor::zeros(x.shape().to_vec());
+  {
+    let dxs = dx.data_mut();
+    _pool2d_for_each(x.shape(), &y_shape, cfg, |yi, window| {
+      let xi = _argmax(x, window);
+      dxs[xi] = dxs[xi] + dy.data()[yi];
+    });
+  }
+  Some(dx)
+}
+
+/// Picks `g` at the position of the maximum of each window of `x`; the
+/// transpose of `max_pool2d_grad`.
+pub fn max_pool2d_select<T: Float>(x: &Tensor<T>, g: &Tensor<T>, cfg: &Pool2dConfig) -> Option<Tensor<T>> {
+  let y_shape = _pool2d_out_shape(x.shape(), cfg)?;
+  if g.shape() != x.shape() {
+    return None;
+  }
+  let mut y = Tensor::zeros(y_shape.clone());
+  {
+    let ys = y.data_mut();
+    _pool2d_for_each(x.shape(), &y_shape, cfg, |yi, window| {
+      ys[yi] = g.data()[_argmax(x, window)];
+    });
+  }
+  Some(y)
+}
+
+pub fn avg_pool2d<T: Float>(x: &Tensor<T>, cfg: &Pool2dConfig) -> Option<Tensor<T>> {
+  let y_shape = _pool2d_out_shape(x.shape(), cfg)?;
+  let mut y = Tensor::zeros(y_shape.clone());
+  {
+    let ys = y.data_mut();
+    _pool2d_for_each(x.shape(), &y_shape, cfg, |yi, window| {
+      let sum = window.iter().fold(T::zero(), |s, &xi| s + x.data()[xi]);
+      ys[yi] = sum / T::from_f64(window.len() as f64);
+    });
+  }
+  Some(y)
+}
+
+pub fn avg_pool2d_grad<T: Float>(x_shape: &[usize], dy: &Tensor<T>, cfg: &Pool2dConfig) -> Option<Tensor<T>> {
+  let y_shape = _pool2d_out_shape(x_shape, cfg)?;
+  if dy.shape() != &y_shape[..] {
+    return None;
+  }
+  let mut dx = Tensor::zeros(x_shape.to_vec());
+  {
+    let dxs = dx.data_mut();
+    _pool2d_for_each(x_shape, &y_shape, cfg, |yi, window| {
+      let g = dy.data()[yi] / T::from_f64(window.len() as f64);
+      for &xi in window.iter() {
+        dxs[xi] = dxs[xi] + g;
+      }
+    });
+  }
+  Some(dx)
+}
+
+/// Adapts a `[N, C, L]` tensor to the 2D kernels when `is_1d`.
+fn _in_4d<T: Clone>(x: &Tensor<T>, is_1d: bool) -> Option<Tensor<T>> {
+  if is_1d { _as_4d(x) } else { Some(x.clone()) }
+}
+
+fn _out_4d<T: Clone>(y: Option<Tensor<T>>, is_1d: bool) -> Option<Tensor<T>> {
+  if is_1d { y.map(_as_3d) } else { y }
+}
+
+// The config is checked once when an op is built: with an invalid config,
+// forcing the op fails with `EvalError::EntryFailed` without running the
+// kernel. Shapes are checked by the kernels, which fail the same way.
+
+// The convolution and its two gradients are bilinear, and each one's
+// adjoint is built from the other two, so they can be differentiated
+// any number of times.
+
+fn _conv_op<T: Float>(x: TensorRef<T>, w: TensorRef<T>, cfg: Conv2dConfig, is_1d: bool) -> TensorRef<T> {
+  let (x_, w_) = (x.clone(), w.clone());
+  let ok = cfg._check();
+  _checked_op(if is_1d { "conv1d" } else { "conv2d" }, vec![x_, w_], None, move |xs, _| {
+    if !ok {
+      return None;
+    }
+    _out_4d(conv2d(&_in_4d(xs[0], is_1d)?, &_in_4d(xs[1], is_1d)?, &cfg), is_1d)
+  }, tensor_adjoint(move |_y, dy, sink| {
+    sink.put_adj(&x, _conv_grad_input_op(dy.clone(), w.clone(), x.clone(), cfg, is_1d));
+    sink.put_adj(&w, _conv_grad_weight_op(x.clone(), dy, w.clone(), cfg, is_1d));
+  }))
+}
+
+/// The cotangent of the input of a convolution; `x_like` only supplies the
+/// input shape.
+fn _conv_grad_input_op<T: Float>(dy: TensorRef<T>, w: TensorRef<T>, x_like: TensorRef<T>, cfg: Conv2dConfig, is_1d: bool) -> TensorRef<T> {
+  let (dy_, w_) = (dy.clone(), w.clone());
+  let ok = cfg._check();
+  _checked_op(if is_1d { "conv1d_grad_input" } else { "conv2d_grad_input" }, vec![dy_, w_, x_like], None, move |xs, _| {
+    if !ok {
+      return None;
+    }
+    _out_4d(conv2d_grad_input(&_in_4d(xs[0], is_1d)?, &_in_4d(xs[1], is_1d)?, _in_4d(xs[2], is_1d)?.shape(), &cfg), is_1d)
+  }, tensor_adjoint(move |_y, g, sink| {
+    sink.put_adj(&dy, _conv_op(g.clone(), w.clone(), cfg, is_1d));
+    sink.put_adj(&w, _conv_grad_weight_op(g, dy.clone(), w.clone(), cfg, is_1d));
+  }))
+}
+
+/// The cotangent of the weight of a convolution; `w_like` only supplies the
+/// weight shape.
+fn _conv_grad_weight_op<T: Float>(x: TensorRef<T>, dy: TensorRef<T>, w_like: TensorRef<T>, cfg: Conv2dConfig, is_1d: bool) -> TensorRef<T> {
+  let (x_, dy_) = (x.clone(), dy.clone());
+  let ok = cfg._check();
+  _checked_op(if is_1d { "conv1d_grad_weight" } else { "conv2d_grad_weight" }, vec![x_, dy_, w_like], None, move |xs, _| {
+    if !ok {
+      return None;
+    }
+    _out_4d(conv2d_grad_weight(&_in_4d(xs[0], is_1d)?, &_in_4d(xs[1], is_1d)?, _in_4d(xs[2], is_1d)?.shape(), &cfg), is_1d)
+  }, tensor_adjoint(move |_y, h, sink| {
+    sink.put_adj(&x, _conv_grad_input_op(dy.clone(), h.clone(), x.clone(), cfg, is_1d));
+    sink.put_adj(&dy, _conv_op(x.clone(), h, cfg, is_1d));
+  }))
+}
+
+// Pooling is linear in its input once the max positions are fixed; the max
+// positions are piecewise constant, so `x` gets no cotangent from the
+// gradient ops.
+
+fn _max_pool_op<T: Float>(x: TensorRef<T>, cfg: Pool2dConfig, is_1d: bool) -> TensorRef<T> {
+  let x_ = x.clone();
+  let ok = cfg._check();
+  _checked_op(if is_1d { "max_pool1d" } else { "max_pool2d" }, vec![x_], None, move |xs, _| {
+    if !ok {
+      return None;
+    }
+    _out_4d(max_pool2d(&_in_4d(xs[0], is_1d)?, &cfg), is_1d)
+  }, tensor_adjoint(move |_y, dy, sink| {
+    sink.put_adj(&x, _max_pool_grad_op(x.clone(), dy, cfg, is_1d));
+  }))
+}
+
+fn _max_pool_grad_op<T: Float>(x: TensorRef<T>, dy: TensorRef<T>, cfg: Pool2dConfig, is_1d: bool) -> TensorRef<T> {
+  let dy_ = dy.clone();
+  let ok = cfg._check();
+  _checked_op(if is_1d { "max_pool1d_grad" } else { "max_pool2d_grad" }, vec![x.clone(), dy_], None, move |xs, _| {
+    if !ok {
+      return None;
+    }
+    _out_4d(max_pool2d_grad(&_in_4d(xs[0], is_1d)?, &_in_4d(xs[1], is_1d)?, &cfg), is_1d)
+  }, tensor_adjoint(move |_y, g, sink| {
+    sink.put_adj(&dy, _max_pool_select_op(x.clone(), g, cfg, is_1d));
+  }))
+}
+
+fn _max_pool_select_op<T: Float>(x: TensorRef<T>, g: TensorRef<T>, cfg: Pool2dConfig, is_1d: bool) -> TensorRef<T> {
+  let g_ = g.clone();
+  let ok = cfg._check();
+  _checked_op(if is_1d { "max_pool1d_select" } else { "max_pool2d_select" }, vec![x.clone(), g_], None, move |xs, _| {
+    if !ok {
+      return None;
+    }
+    _out_4d(max_pool2d_select(&_in_4d(xs[0], is_1d)?, &_in_4d(xs[1], is_1d)?, &cfg), is_1d)
+  }, tensor_adjoint(move |_y, h, sink| {
+    sink.put_adj(&g, _max_pool_grad_op(x.clone(), h, cfg, is_1d));
+  }))
+}
+
+fn _avg_pool_op<T: Float>(x: TensorRef<T>, cfg: Pool2dConfig, is_1d: bool) -> TensorRef<T> {
+  let x_ = x.clone();
+  let ok = cfg._check();
+  _checked_op(if is_1d { "avg_pool1d" } else { "avg_pool2d" }, vec![x_], None, move |xs, _| {
+    if !ok {
+      return None;
+    }
+    _out_4d(avg_pool2d(&_in_4d(xs[0], is_1d)?, &cfg), is_1d)
+  }, tensor_adjoint(move |_y, dy, sink| {
+    sink.put_adj(&x, _avg_pool_grad_op(dy, x.clone(), cfg, is_1d));
+  }))
+}
+
+/// The cotangent of the input of average pooling; `x_like` only supplies
+/// the input shape.
+fn _avg_pool_grad_op<T: Float>(dy: TensorRef<T>, x_like: TensorRef<T>, cfg: Pool2dConfig, is_1d: bool) -> TensorRef<T> {
+  let dy_ = dy.clone();
+  let ok = cfg._check();
+  _checked_op(if is_1d { "avg_pool1d_grad" } else { "avg_pool2d_grad" }, vec![dy_, x_like], None, move |xs, _| {
+    if !ok {
+      return None;
+    }
+    _out_4d(avg_pool2d_grad(_in_4d(xs[1], is_1d)?.shape(), &_in_4d(xs[0], is_1d)?, &cfg), is_1d)
+  }, tensor_adjoint(move |_y, g, sink| {
+    sink.put_adj(&dy, _avg_pool_op(g, cfg, is_1d));
+  }))
+}
+
+/// 2D convolution; forcing fails with `EvalError::EntryFailed` if the
+/// config or the shapes are invalid.
+pub fn conv2d_op<T: Float>(x: TensorRef<T>, w: TensorRef<T>, cfg: Conv2dConfig) -> TensorRef<T> {
+  _conv_op(x, w, cfg, false)
+}
+
+pub fn max_pool2d_op<T: Float>(x: TensorRef<T>, cfg: Pool2dConfig) -> TensorRef<T> {
+  _max_pool_op(x, cfg, false)
+}
+
+pub fn avg_pool2d_op<T: Float>(x: TensorRef<T>, cfg: Pool2dConfig) -> TensorRef<T> {
+  _avg_pool_op(x, cfg, false)
+}
+
+/// Views a `[N, C, L]` tensor as `[N, C, 1, L]`.
+fn _as_4d<T: Clone>(x: &Tensor<T>) -> Option<Tensor<T>> {
+  if x.ndim() != 3 {
+    return None;
+  }
+  let s = x.shape();
+  Some(x.reshape(vec![s[0], s[1], 1, s[2]]))
+}
+
+fn _as_3d<T: Clone>(x: Tensor<T>) -> Tensor<T> {
+  let s = x.shape().to_vec();
+  x.reshape(vec![s[0], s[1], s[3]])
+}
+
+pub fn conv1d_op<T: Float>(x: TensorRef<T>, w: TensorRef<T>, cfg: Conv1dConfig) -> TensorRef<T> {
+  _conv_op(x, w, cfg._as_2d(), true)
+}
+
+pub fn max_pool1d_op<T: Float>(x: TensorRef<T>, cfg: Pool1dConfig) -> TensorRef<T> {
+  _max_pool_op(x, cfg._as_2d(), true)
+}
+
+pub fn avg_pool1d_op<T: Float>(x: TensorRef<T>, cfg: Pool1dConfig) -> TensorRef<T> {
+  _avg_pool_op(x, cfg._as_2d(), true)
+}
@@ -1,37 +1,159 @@
+use num::{Field, Ring};
+
 use parking_lot::{Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
 
-use std::any::{Any};
+use std::any::{Any, TypeId};
 use std::cell::{Cell, RefCell};
 use std::collections::{HashMap, HashSet};
+use std::env;
 use std::fmt::{Debug};
+use std::fs::{File};
+use std::io::{self, Write};
 use std::marker::{PhantomData};
-use std::ops::{Add};
+use std::mem::{size_of};
+use std::path::{PathBuf};
+use std::process;
 use std::rc::{Rc};
 use std::sync::{Arc};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 
 lazy_static! {
   static ref DEFAULT_CFG:   Mutex<DefaultConfig> = Mutex::new(DefaultConfig::new());
   static ref DEFAULT_CTX:   Mutex<Option<DefaultCtx>> = Mutex::new(None);
+  static ref TRACER:        Mutex<Option<Tracer>> = Mutex::new(None);
 }
 
+static TRACE_TIDS: AtomicUsize = AtomicUsize::new(0);
+
 thread_local! {
   static UID:   Cell<u64> = Cell::new(0);
   static HEAP:  RefCell<Heap> = RefCell::new(Heap::new_root());
   static CTXS:  RefCell<Vec<Rc<dyn ExecutionCtx>>> = RefCell::new(Vec::new());
+  static RESIDENT:  RefCell<Resident> = RefCell::new(Resident::default());
+  static CHECKPOINTS:   RefCell<HashSet<STag>> = RefCell::new(HashSet::new());
+  // The pass whose adjoints are currently building cotangent thunks, and
+  // the pass which built each such thunk.
+  static CURR_PASS:     Cell<Option<Pass>> = Cell::new(None);
+  static PASS_OF:       RefCell<HashMap<STag, Pass>> = RefCell::new(HashMap::new());
+  // The error which made the running entry fail, as seen by `RThunk::try_get`.
+  static ENTRY_ERROR:   RefCell<Option<EvalError>> = RefCell::new(None);
+  // The thunks whose entries are running, innermost last; see `EntryCtx`.
+  static ENTRY_CTXS:    RefCell<Vec<EntryCtx>> = RefCell::new(Vec::new());
+  // The objects put on the heap within each running `_build_retained`,
+  // innermost last.
+  static BUILT:         RefCell<Vec<Vec<STag>>> = RefCell::new(Vec::new());
+  static TRACE_TID: usize = TRACE_TIDS.fetch_add(1, Ordering::SeqCst) + 1;
 }
 
 #[derive(Clone)]
 pub struct DefaultConfig {
   pub default_opt_hint: Option<OptimizeHint>,
+  /// If set, thunk evaluations are appended to this file as Chrome
+  /// trace-event JSON (viewable in e.g. chrome://tracing).
+  /// Read from `HEBB_TRACE` by default.
+  pub trace_path:       Option<PathBuf>,
+  /// If set, payloads of valid thunks are evicted (and later recomputed on
+  /// demand) to keep the resident payload bytes of each thread under this
+  /// budget. Read from `HEBB_MEM_BUDGET` by default.
+  pub mem_budget:       Option<usize>,
 }
 
 impl DefaultConfig {
   fn new() -> DefaultConfig {
-    // TODO: read env vars.
+    // TODO: read more env vars.
     DefaultConfig{
       default_opt_hint: None,
+      trace_path:       env::var_os("HEBB_TRACE").map(PathBuf::from),
+      mem_budget:       env::var("HEBB_MEM_BUDGET").ok().and_then(|s| s.parse().ok()),
+    }
+  }
+}
+
+pub fn default_cfg() -> DefaultConfig {
+  DEFAULT_CFG.lock().clone()
+}
+
+/// Sets the default config. When `trace_path` changes, the new trace file is
+/// created immediately, and an error creating it is returned here; the rest
+/// of the config is applied either way.
+pub fn set_default_cfg(cfg: DefaultConfig) -> io::Result<()> {
+  let mut tracer = TRACER.lock();
+  let mut default_cfg = DEFAULT_CFG.lock();
+  let mut result = Ok(());
+  if tracer.as_ref().map(|t| &t.path) != cfg.trace_path.as_ref() {
+    *tracer = match cfg.trace_path {
+      None => None,
+      Some(ref path) => {
+        let t = Tracer::open(path.clone());
+        if let Err(ref e) = t.file {
+          result = Err(io::Error::new(e.kind(), format!("{}", e)));
+        }
+        Some(t)
+      }
+    };
+  }
+  *default_cfg = cfg;
+  result
+}
+
+struct Tracer {
+  path:     PathBuf,
+  // Tracing is off for the rest of this `path` once creating or writing the
+  // file fails; a trace never interrupts evaluation.
+  file:     io::Result<File>,
+  start:    Instant,
+}
+
+impl Tracer {
+  fn open(path: PathBuf) -> Tracer {
+    // The closing bracket is optional in the trace-event array format, so
+    // events can be appended until the process exits.
+    let file = File::create(&path).and_then(|mut file| {
+      file.write_all(b"[\n")?;
+      Ok(file)
+    });
+    Tracer{
+      path:     path,
+      file:     file,
+      start:    Instant::now(),
+    }
+  }
+
+  fn event(&mut self, ph: char, name: &str, stable: STag, txn: Txn) {
+    let ts = _duration_us(self.start.elapsed());
+    let tid = TRACE_TID.with(|tid| *tid);
+    let name: String = name.chars().filter(|&c| c != '"' && c != '\\').collect();
+    let line = format!(
+        "{{\"name\":\"{}\",\"cat\":\"thunk\",\"ph\":\"{}\",\"ts\":{},\"pid\":{},\"tid\":{},\"args\":{{\"stag\":{},\"txn\":{}}}}},\n",
+        name, ph, ts, process::id(), tid, stable.uid, txn.0);
+    let result = match self.file {
+      Err(_) => return,
+      Ok(ref mut file) => file.write_all(line.as_bytes()),
+    };
+    if let Err(e) = result {
+      eprintln!("WARNING: hebb: tracing to {:?} stopped: {}", self.path, e);
+      self.file = Err(e);
+    }
+  }
+}
+
+fn _trace_event(ph: char, name: &str, stable: STag, txn: Txn) {
+  let mut tracer = TRACER.lock();
+  if tracer.is_none() {
+    // Only a trace path from `HEBB_TRACE` is opened lazily here.
+    match DEFAULT_CFG.lock().trace_path {
+      None => return,
+      Some(ref path) => {
+        let t = Tracer::open(path.clone());
+        if let Err(ref e) = t.file {
+          eprintln!("WARNING: hebb: failed to create trace file {:?}: {}", path, e);
+        }
+        *tracer = Some(t);
+      }
     }
   }
+  tracer.as_mut().unwrap().event(ph, name, stable, txn);
 }
 
 fn default_ctx() -> impl ExecutionCtx {
@@ -54,7 +176,162 @@ pub fn thread_ctx() -> Rc<dyn ExecutionCtx> {
   })
 }
 
+pub fn push_ctx(ctx: Rc<dyn ExecutionCtx>) {
+  CTXS.with(|ctxs| {
+    let mut ctxs = ctxs.borrow_mut();
+    ctxs.push(ctx);
+  })
+}
+
+pub fn pop_ctx() -> Option<Rc<dyn ExecutionCtx>> {
+  CTXS.with(|ctxs| {
+    let mut ctxs = ctxs.borrow_mut();
+    ctxs.pop()
+  })
+}
+
 pub trait ExecutionCtx {
+  fn maybe_profiler(&self) -> Option<&Profiler> { None }
+}
+
+#[derive(Default)]
+pub struct ProfileCtx {
+  prof:     Profiler,
+}
+
+impl ProfileCtx {
+  pub fn new() -> ProfileCtx {
+    ProfileCtx::default()
+  }
+
+  pub fn profiler(&self) -> &Profiler {
+    &self.prof
+  }
+}
+
+impl ExecutionCtx for ProfileCtx {
+  fn maybe_profiler(&self) -> Option<&Profiler> { Some(&self.prof) }
+}
+
+#[derive(Clone, Default, Debug)]
+pub struct ProfileStats {
+  pub count:        u64,
+  pub time:         Duration,
+  pub alloc_count:  u64,
+  pub alloc_bytes:  usize,
+}
+
+impl ProfileStats {
+  fn _merge(&mut self, other: &ProfileStats) {
+    self.count += other.count;
+    self.time += other.time;
+    self.alloc_count += other.alloc_count;
+    self.alloc_bytes += other.alloc_bytes;
+  }
+}
+
+struct ProfileFrame {
+  stable:   STag,
+  name:     &'static str,
+  start:    Instant,
+  children: Duration,
+}
+
+/// Records per-thunk entry counts, self time spent inside `ThunkCode::entry`
+/// (time spent forcing nested thunks is attributed to those thunks), and
+/// bytes allocated by `DataCode::alloc` while an entry is running.
+#[derive(Default)]
+pub struct Profiler {
+  stack:    RefCell<Vec<ProfileFrame>>,
+  thunks:   RefCell<HashMap<STag, (&'static str, ProfileStats)>>,
+}
+
+impl Profiler {
+  pub fn _enter(&self, stable: STag, name: &'static str) {
+    let mut stack = self.stack.borrow_mut();
+    stack.push(ProfileFrame{
+      stable:   stable,
+      name:     name,
+      start:    Instant::now(),
+      children: Duration::new(0, 0),
+    });
+  }
+
+  pub fn _exit(&self, stable: STag) {
+    let mut stack = self.stack.borrow_mut();
+    let frame = match stack.pop() {
+      None => panic!("Profiler: _exit: empty stack"),
+      Some(frame) => frame,
+    };
+    assert_eq!(frame.stable, stable);
+    let elapsed = frame.start.elapsed();
+    if let Some(parent) = stack.last_mut() {
+      parent.children += elapsed;
+    }
+    let mut thunks = self.thunks.borrow_mut();
+    let entry = thunks.entry(stable).or_insert_with(|| (frame.name, ProfileStats::default()));
+    entry.1.count += 1;
+    entry.1.time += elapsed.checked_sub(frame.children).unwrap_or(Duration::new(0, 0));
+  }
+
+  pub fn _alloc(&self, nbytes: usize) {
+    let stack = self.stack.borrow();
+    if let Some(frame) = stack.last() {
+      let mut thunks = self.thunks.borrow_mut();
+      let entry = thunks.entry(frame.stable).or_insert_with(|| (frame.name, ProfileStats::default()));
+      entry.1.alloc_count += 1;
+      entry.1.alloc_bytes += nbytes;
+    }
+  }
+
+  pub fn reset(&self) {
+    self.thunks.borrow_mut().clear();
+  }
+
+  pub fn report(&self) -> ProfileReport {
+    let thunks = self.thunks.borrow();
+    let mut by_thunk: Vec<_> = thunks.iter()
+      .map(|(&stable, &(name, ref stats))| (stable, name, stats.clone()))
+      .collect();
+    let mut ops: HashMap<&'static str, ProfileStats> = HashMap::new();
+    for &(_, name, ref stats) in by_thunk.iter() {
+      ops.entry(name).or_insert_with(ProfileStats::default)._merge(stats);
+    }
+    let mut by_op: Vec<_> = ops.into_iter().collect();
+    by_thunk.sort_by(|a, b| b.2.time.cmp(&a.2.time).then(a.0.uid.cmp(&b.0.uid)));
+    by_op.sort_by(|a, b| b.1.time.cmp(&a.1.time).then(a.0.cmp(b.0)));
+    ProfileReport{by_thunk, by_op}
+  }
+}
+
+/// A profile snapshot; both tables are sorted by descending self time.
+#[derive(Clone, Debug)]
+pub struct ProfileReport {
+  pub by_thunk: Vec<(STag, &'static str, ProfileStats)>,
+  pub by_op:    Vec<(&'static str, ProfileStats)>,
+}
+
+impl ProfileReport {
+  pub fn print(&self) {
+    println!("{:<16} {:>8} {:>12} {:>8} {:>12}", "op", "count", "time (us)", "allocs", "bytes");
+    for &(name, ref stats) in self.by_op.iter() {
+      println!("{:<16} {:>8} {:>12} {:>8} {:>12}",
+          name, stats.count, _duration_us(stats.time), stats.alloc_count, stats.alloc_bytes);
+    }
+    println!("{:<16} {:>8} {:>12} {:>8} {:>12}", "thunk", "count", "time (us)", "allocs", "bytes");
+    for &(stable, name, ref stats) in self.by_thunk.iter() {
+      println!("{:<16} {:>8} {:>12} {:>8} {:>12}",
+          format!("{}#{}", name, stable.uid), stats.count, _duration_us(stats.time), stats.alloc_count, stats.alloc_bytes);
+    }
+  }
+}
+
+fn _duration_us(d: Duration) -> u64 {
+  d.as_secs() * 1_000_000 + (d.subsec_nanos() / 1_000) as u64
+}
+
+fn _duration_ns(d: Duration) -> u64 {
+  d.as_secs() * 1_000_000_000 + d.subsec_nanos() as u64
 }
 
 pub type DefaultCtx = DummyCtx;
@@ -94,15 +371,37 @@ fn next_uid() -> u64 {
   })
 }
 
-pub struct Pass(u64);
-
+/// A differentiation pass. Cotangents are ordinary thunks, so a pass over a
+/// graph containing the cotangents of an earlier pass (e.g. in `hvp`) simply
+/// differentiates them again; passes only need telling apart.
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Pass {
+  id:       u64,
+}
+
+pub fn pass() -> Pass {
+  Pass{id: next_uid()}
+}
+
+impl Pass {
+  pub fn _id(&self) -> u64 {
+    self.id
+  }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
 pub struct Txn(u64);
 
 pub fn txn() -> Txn {
   Txn(next_uid())
 }
 
+impl Txn {
+  pub fn _id(&self) -> u64 {
+    self.0
+  }
+}
+
 #[derive(Clone, PartialEq, Eq, Hash, Debug)]
 pub struct Sym {
   u:        String,
@@ -118,6 +417,10 @@ impl STag {
     STag{uid: next_uid()}
   }
 
+  pub fn _uid(&self) -> u64 {
+    self.uid
+  }
+
   /*// FIXME
   pub fn lookup::<T: Any + Clone + 'static>(&self) -> T {
     let obj = HEAP.with(|heap| {
@@ -180,12 +483,58 @@ impl Tag {
 pub trait Placement {
 }
 
+#[derive(Clone, Debug)]
+pub enum EvalError {
+  MissingObj(STag),
+  MissingEntry(STag),
+  MissingData(STag),
+  BlackHole(STag),
+  Cycle(STag),
+  EntryFailed(STag),
+  /// A thunk which depends on a batched input has no `ThunkCode::batch`
+  /// rule, or an unbatched input could not be broadcast.
+  MissingBatchRule(STag),
+  /// A reverse pass reached a thunk whose adjoint is `non_differentiable`,
+  /// e.g. the gradient of an op which has no higher-order rule.
+  NotDifferentiable(STag),
+  /// A value does not have the length its consumer expects, e.g. the
+  /// cotangent of an optimizer parameter.
+  ShapeMismatch(STag),
+}
+
 //#[derive(Clone)]
 pub struct TagVec {
   inner:    Vec<Tag>,
 }
 
+impl From<Vec<Tag>> for TagVec {
+  fn from(inner: Vec<Tag>) -> TagVec {
+    TagVec{inner}
+  }
+}
+
 impl TagVec {
+  pub fn new() -> TagVec {
+    TagVec{inner: Vec::new()}
+  }
+
+  pub fn push<V>(&mut self, x: &ThunkRef<V>) {
+    self.inner.push(x.tag.clone_ref());
+  }
+
+  pub fn len(&self) -> usize {
+    self.inner.len()
+  }
+
+  /// Forces all roots in a single topological schedule: each thunk reachable
+  /// from the roots is evaluated at most once per `txn`, and thunks which are
+  /// already valid in `txn` are skipped. Errors are propagated from a failed
+  /// thunk to every thunk downstream of it, so each root gets its own result.
+  pub fn force_all(&self, txn: Txn) -> Vec<Result<(), EvalError>> {
+    let roots: Vec<_> = self.inner.iter().map(|tag| tag.stable).collect();
+    _force_all(&roots, txn)
+  }
+
   pub fn optimize(&self) -> FrameRef {
     self.optimize_with_hint(OptimizeHint::default())
   }
@@ -197,8 +546,18 @@ impl TagVec {
   }
 }
 
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EvictPolicy {
+  Never,
+  LeastRecentlyUsed,
+  CheapestFirst,
+}
+
 #[derive(Clone, Debug)]
 pub struct OptimizeHint {
+  /// How to pick payloads to evict when over the memory budget; defaults to
+  /// `EvictPolicy::CheapestFirst`.
+  pub evict:    Option<EvictPolicy>,
 }
 
 impl Default for OptimizeHint {
@@ -213,7 +572,107 @@ impl Default for OptimizeHint {
 
 impl OptimizeHint {
   pub fn empty() -> OptimizeHint {
-    OptimizeHint{}
+    OptimizeHint{
+      evict:    None,
+    }
+  }
+}
+
+impl From<EvictPolicy> for OptimizeHint {
+  fn from(policy: EvictPolicy) -> OptimizeHint {
+    OptimizeHint{
+      evict:    Some(policy),
+    }
+  }
+}
+
+struct ResidentEntry {
+  nbytes:   usize,
+  stamp:    u64,
+  cost:     Duration,
+}
+
+/// Per-thread accounting of the payloads held by valid thunks.
+#[derive(Default)]
+struct Resident {
+  bytes:    usize,
+  clock:    u64,
+  entries:  HashMap<STag, ResidentEntry>,
+  pinned:   HashSet<STag>,
+}
+
+impl Resident {
+  fn update(&mut self, stable: STag, nbytes: usize, cost: Duration) {
+    self.clock += 1;
+    let stamp = self.clock;
+    if let Some(prev) = self.entries.insert(stable, ResidentEntry{nbytes, stamp, cost}) {
+      self.bytes -= prev.nbytes;
+    }
+    self.bytes += nbytes;
+  }
+
+  fn touch(&mut self, stable: STag) {
+    self.clock += 1;
+    let stamp = self.clock;
+    if let Some(entry) = self.entries.get_mut(&stable) {
+      entry.stamp = stamp;
+    }
+  }
+
+  fn remove(&mut self, stable: STag) {
+    if let Some(prev) = self.entries.remove(&stable) {
+      self.bytes -= prev.nbytes;
+    }
+  }
+
+  fn victims(&self, policy: EvictPolicy, keep: STag) -> Vec<STag> {
+    let mut victims: Vec<_> = self.entries.iter()
+      .filter(|&(stable, _)| *stable != keep && !self.pinned.contains(stable))
+      .collect();
+    match policy {
+      EvictPolicy::Never => return Vec::new(),
+      EvictPolicy::LeastRecentlyUsed => {
+        victims.sort_by_key(|&(_, entry)| entry.stamp);
+      }
+      EvictPolicy::CheapestFirst => {
+        // Order by recompute time per byte freed, breaking ties by recency.
+        victims.sort_by(|&(_, a), &(_, b)| {
+          let ca = _duration_ns(a.cost) * b.nbytes.max(1) as u64;
+          let cb = _duration_ns(b.cost) * a.nbytes.max(1) as u64;
+          ca.cmp(&cb).then(a.stamp.cmp(&b.stamp))
+        });
+      }
+    }
+    victims.into_iter().map(|(&stable, _)| stable).collect()
+  }
+}
+
+/// Returns the payload bytes currently held by valid thunks on this thread.
+pub fn resident_bytes() -> usize {
+  RESIDENT.with(|res| res.borrow().bytes)
+}
+
+fn _maybe_evict(keep: STag) {
+  let budget = match DEFAULT_CFG.lock().mem_budget {
+    None => return,
+    Some(budget) => budget,
+  };
+  if resident_bytes() <= budget {
+    return;
+  }
+  let policy = OptimizeHint::default().evict.unwrap_or(EvictPolicy::CheapestFirst);
+  let victims = RESIDENT.with(|res| res.borrow().victims(policy, keep));
+  for stable in victims {
+    if resident_bytes() <= budget {
+      break;
+    }
+    let evicted = match _lookup_obj(stable) {
+      None => true,
+      Some(obj) => obj._as_thunk().map(|thunk| thunk._evict()).unwrap_or(true),
+    };
+    if evicted {
+      RESIDENT.with(|res| res.borrow_mut().remove(stable));
+    }
   }
 }
 
@@ -226,6 +685,217 @@ pub enum HeapObjKind {
 
 pub trait HeapObj: Any {
   fn _obj_kind(&self) -> HeapObjKind;
+  fn _as_any(&self) -> &dyn Any;
+
+  fn _as_thunk(&self) -> Option<&dyn ThunkObj> {
+    None
+  }
+}
+
+/// Type-erased view of a `Thunk<V>`, used by the runtime to schedule and
+/// force thunks without knowing their value type.
+pub trait ThunkObj {
+  fn _stable(&self) -> STag;
+  fn _freevars(&self) -> Vec<STag>;
+  /// The free variables which the entry always forces, i.e. `_freevars`
+  /// except the lazy ones.
+  fn _strict_freevars(&self) -> Vec<STag>;
+  fn _is_valid(&self, txn: Txn) -> bool;
+  fn _try_force_eval(&self, txn: Txn) -> Result<(), EvalError>;
+  /// Drops the payload of a valid thunk and resets it to `Empty`, so that it
+  /// is recomputed when next needed. Fails if the payload is in use.
+  fn _evict(&self) -> bool;
+  fn _adjoint(&self, pass: Pass, sink: &mut Sink);
+  /// The thunks of all outputs of this thunk's multi-output op, if any.
+  fn _outputs(&self) -> Vec<STag>;
+  fn _batch(&self, batcher: &mut Batcher) -> Result<(), EvalError>;
+}
+
+/// Returns the number of objects on the heap of this thread.
+pub fn heap_len() -> usize {
+  HEAP.with(|heap| heap.borrow().objs.len())
+}
+
+/// Owns heap objects on behalf of the handles sharing it: when the last
+/// `Rc` to it drops, the objects are removed from the heap, along with the
+/// runtime's bookkeeping for them.
+struct _HeapRetain {
+  objs:     Vec<STag>,
+}
+
+impl _HeapRetain {
+  fn new() -> _HeapRetain {
+    _HeapRetain{objs: Vec::new()}
+  }
+}
+
+impl Drop for _HeapRetain {
+  fn drop(&mut self) {
+    // The heap may already be gone at thread exit.
+    let removed: Vec<HeapEntry> = HEAP.try_with(|heap| {
+      let mut heap = heap.borrow_mut();
+      self.objs.iter().filter_map(|s| heap.objs.remove(s)).collect()
+    }).unwrap_or_default();
+    let _ = RESIDENT.try_with(|res| {
+      let mut res = res.borrow_mut();
+      for &s in self.objs.iter() {
+        res.remove(s);
+        res.pinned.remove(&s);
+      }
+    });
+    let _ = CHECKPOINTS.try_with(|ckpts| {
+      let mut ckpts = ckpts.borrow_mut();
+      for s in self.objs.iter() {
+        ckpts.remove(s);
+      }
+    });
+    let _ = PASS_OF.try_with(|pass_of| {
+      let mut pass_of = pass_of.borrow_mut();
+      for s in self.objs.iter() {
+        pass_of.remove(s);
+      }
+    });
+    // Dropping the objects may release further retains, which need the heap.
+    drop(removed);
+  }
+}
+
+fn _record_built(stable: STag) {
+  BUILT.with(|built| {
+    if let Some(objs) = built.borrow_mut().last_mut() {
+      objs.push(stable);
+    }
+  });
+}
+
+/// Pops the innermost `BUILT` frame, also when the builder panics.
+struct _BuiltFrame;
+
+impl Drop for _BuiltFrame {
+  fn drop(&mut self) {
+    let _ = BUILT.try_with(|built| built.borrow_mut().pop());
+  }
+}
+
+/// Runs `f`, adding the objects which it puts on the heap to `retain`.
+fn _build_retained<R, F: FnOnce() -> R>(retain: &mut _HeapRetain, f: F) -> R {
+  BUILT.with(|built| built.borrow_mut().push(Vec::new()));
+  let frame = _BuiltFrame;
+  let r = f();
+  BUILT.with(|built| retain.objs.extend(built.borrow_mut().last_mut().unwrap().drain(..)));
+  drop(frame);
+  r
+}
+
+fn _lookup_obj(stable: STag) -> Option<Rc<dyn HeapObj>> {
+  HEAP.with(|heap| {
+    let heap = heap.borrow();
+    heap.objs.get(&stable).map(|entry| entry.content.clone())
+  })
+}
+
+fn _force_all(roots: &[STag], txn: Txn) -> Vec<Result<(), EvalError>> {
+  let order = match _topo_sort(roots) {
+    // Cyclic free variables (see `letrec`) are fine unless the thunks are
+    // forced cyclically, so force each root on demand instead; its entry
+    // then forces only what it needs, and a cyclic force is a `BlackHole`.
+    Err(EvalError::Cycle(_)) => return roots.iter().map(|&r| _force_on_demand(r, txn)).collect(),
+    Err(e) => return roots.iter().map(|_| Err(e.clone())).collect(),
+    Ok(order) => order,
+  };
+  // Only invalid thunks need their free variables, so e.g. an evicted
+  // payload is not recomputed when its consumers are still valid. Lazy free
+  // variables are left to the entry, which forces them if it needs them.
+  let mut needed: HashSet<STag> = roots.iter().cloned().collect();
+  for &stable in order.iter().rev() {
+    if !needed.contains(&stable) {
+      continue;
+    }
+    if let Some(thunk) = _lookup_obj(stable).as_ref().and_then(|obj| obj._as_thunk()) {
+      if !thunk._is_valid(txn) {
+        needed.extend(thunk._strict_freevars());
+      }
+    }
+  }
+  let mut failed: HashMap<STag, EvalError> = HashMap::new();
+  for &stable in order.iter().filter(|s| needed.contains(s)) {
+    let thunk = match _lookup_obj(stable) {
+      None => {
+        failed.insert(stable, EvalError::MissingObj(stable));
+        continue;
+      }
+      Some(obj) => obj,
+    };
+    let thunk = thunk._as_thunk().unwrap();
+    let upstream = thunk._strict_freevars().into_iter()
+      .filter_map(|v| failed.get(&v).cloned())
+      .next();
+    if let Some(e) = upstream {
+      failed.insert(stable, e);
+      continue;
+    }
+    if thunk._is_valid(txn) {
+      continue;
+    }
+    if let Err(e) = thunk._try_force_eval(txn) {
+      failed.insert(stable, e);
+    }
+  }
+  roots.iter().map(|r| match failed.get(r) {
+    None => Ok(()),
+    Some(e) => Err(e.clone()),
+  }).collect()
+}
+
+fn _force_on_demand(stable: STag, txn: Txn) -> Result<(), EvalError> {
+  let obj = match _lookup_obj(stable) {
+    None => return Err(EvalError::MissingObj(stable)),
+    Some(obj) => obj,
+  };
+  match obj._as_thunk() {
+    None => Err(EvalError::MissingObj(stable)),
+    Some(thunk) => if thunk._is_valid(txn) { Ok(()) } else { thunk._try_force_eval(txn) },
+  }
+}
+
+/// Returns the thunks reachable from `roots` (through `freevars`) in
+/// dependency order, i.e. every thunk appears after all of its free variables.
+fn _topo_sort(roots: &[STag]) -> Result<Vec<STag>, EvalError> {
+  let mut order = Vec::new();
+  let mut done = HashSet::new();
+  let mut active = HashSet::new();
+  let mut stack: Vec<(STag, bool)> = roots.iter().rev().map(|&r| (r, false)).collect();
+  while let Some((stable, expanded)) = stack.pop() {
+    if expanded {
+      active.remove(&stable);
+      if done.insert(stable) {
+        order.push(stable);
+      }
+      continue;
+    }
+    if done.contains(&stable) {
+      continue;
+    }
+    if active.contains(&stable) {
+      return Err(EvalError::Cycle(stable));
+    }
+    let obj = match _lookup_obj(stable) {
+      None => return Err(EvalError::MissingObj(stable)),
+      Some(obj) => obj,
+    };
+    let freevars = match obj._as_thunk() {
+      None => return Err(EvalError::MissingObj(stable)),
+      Some(thunk) => thunk._freevars(),
+    };
+    active.insert(stable);
+    stack.push((stable, true));
+    for &v in freevars.iter().rev() {
+      if !done.contains(&v) {
+        stack.push((v, false));
+      }
+    }
+  }
+  Ok(order)
 }
 
 pub struct FrameRef<'scope> {
@@ -237,12 +907,11 @@ pub struct FrameRef<'scope> {
 
 pub struct HeapEntry {
   sym:      Option<Sym>,
-  content:  Rc<dyn Any>,
-  //content:  Rc<dyn HeapObj>,
+  content:  Rc<dyn HeapObj>,
 }
 
 impl HeapEntry {
-  pub fn anonymous<Obj: 'static>(obj: Obj) -> HeapEntry {
+  pub fn anonymous<Obj: HeapObj>(obj: Obj) -> HeapEntry {
     HeapEntry{
       sym:      None,
       content:  Rc::new(obj),
@@ -278,6 +947,10 @@ impl HeapObj for Heap {
   fn _obj_kind(&self) -> HeapObjKind {
     HeapObjKind::Heap
   }
+
+  fn _as_any(&self) -> &dyn Any {
+    self
+  }
 }
 
 pub struct LDataRef<V> {
@@ -302,7 +975,7 @@ impl<V: 'static> LDataRef<V> {
       let heap = heap.borrow();
       heap.objs[&self.stable].content.clone()
     });
-    if let Some(ref data) = (&*data_obj).downcast_ref::<Data<V>>() {
+    if let Some(ref data) = data_obj._as_any().downcast_ref::<Data<V>>() {
       let cloned_data = data._clone_exact();
       LData{
         stable:     self.stable,
@@ -324,11 +997,47 @@ pub struct LData<V> {
 }
 
 impl<V> LData<V> {
-  /*pub fn get(&self, txn: Txn) -> RwLockReadGuard<V> {
-    // TODO
-    unimplemented!();
-  }*/
+  pub fn _stable(&self) -> STag {
+    self.stable
+  }
+
+  pub fn get(&self, _txn: Txn) -> RwLockReadGuard<V> {
+    let cell = self.synccell.read();
+    RwLockReadGuard::map(cell, |cell| match cell.payload {
+      None => panic!("LData: get: missing payload"),
+      Some(ref payload) => &**payload,
+    })
+  }
+
+  /// The data whose payload this data shares, if its last entry redirected
+  /// to another thunk instead of filling its own payload.
+  pub fn alias(&self) -> Option<STag> {
+    self.synccell.read().alias
+  }
+}
+
+impl<V: 'static> LData<V> {
+  /// Declares that the result of the thunk being evaluated is the value of
+  /// `src`, instead of filling its own payload: `src` is forced and its
+  /// payload is shared, not copied. A later `get_mut` on either side copies
+  /// the shared payload first. Fails if `src` fails to evaluate.
+  pub fn redirect(&self, src: &RThunk<V>, txn: Txn) -> Result<(), EvalError> {
+    let _ = src.try_get(txn)?;
+    let (payload, alias) = match src.data {
+      None => panic!("LData: redirect: no data"),
+      Some(ref data) => {
+        let cell = data.synccell.read();
+        (cell.payload.clone(), cell.alias.unwrap_or(data.stable))
+      }
+    };
+    let mut cell = self.synccell.write();
+    cell.payload = payload;
+    cell.alias = Some(alias);
+    Ok(())
+  }
+}
 
+impl<V: Clone> LData<V> {
   pub fn get_mut(&self, txn: Txn) -> RwLockWriteGuard<V> {
     /*// TODO: want to avoid forcing an eval here;
     // see the commented out datastate condition below.
@@ -352,15 +1061,7 @@ impl<V> LData<V> {
       }
     }*/
     let cell = self.synccell.write();
-    RwLockWriteGuard::map(cell, |cell| {
-      if cell.payload.is_none() {
-        cell.payload = match self.code.alloc {
-          None => panic!("Data: get_mut: missing alloc"),
-          Some(ref alloc) => Some((alloc)(txn)),
-        };
-      }
-      cell.payload.as_mut().unwrap()
-    })
+    RwLockWriteGuard::map(cell, |cell| cell._get_mut(&self.code, txn))
   }
 }
 
@@ -379,6 +1080,7 @@ impl<V: 'static> Data<V> {
       //let retain = RTag::new();
       let mut heap = heap.borrow_mut();
       heap.objs.insert(stable, HeapEntry::anonymous(self));
+      _record_built(stable);
       /*ThunkRef{
         tag:    Tag{stable, retain},
         _mrk:   PhantomData,
@@ -412,21 +1114,19 @@ impl<V> Data<V> {
     let cell = self.synccell.read();
     RwLockReadGuard::map(cell, |cell| match cell.payload {
       None => panic!("Data: get: missing payload"),
-      Some(ref payload) => payload,
+      Some(ref payload) => &**payload,
     })
   }
 
+  pub fn _curr_txn(&self) -> Option<Txn> {
+    self.synccell.read().curr_txn
+  }
+}
+
+impl<V: Clone> Data<V> {
   pub fn _get_mut(&self, txn: Txn) -> RwLockWriteGuard<V> {
     let cell = self.synccell.write();
-    RwLockWriteGuard::map(cell, |cell| {
-      if cell.payload.is_none() {
-        cell.payload = match self.code.alloc {
-          None => panic!("Data: get_mut: missing alloc"),
-          Some(ref alloc) => Some((alloc)(txn)),
-        };
-      }
-      cell.payload.as_mut().unwrap()
-    })
+    RwLockWriteGuard::map(cell, |cell| cell._get_mut(&self.code, txn))
   }
 }
 
@@ -434,6 +1134,10 @@ impl<V: 'static> HeapObj for Data<V> {
   fn _obj_kind(&self) -> HeapObjKind {
     HeapObjKind::Data
   }
+
+  fn _as_any(&self) -> &dyn Any {
+    self
+  }
 }
 
 pub struct DataCell<V> {
@@ -444,7 +1148,10 @@ pub struct DataCell<V> {
   d_sproducers: HashSet<STag>,
   l_producers:  HashSet<Tag>,
   d_producers:  HashSet<Tag>,
-  payload:      Option<V>,
+  /// Shared with the data in `alias` after a redirect, and copied on write
+  /// if still shared.
+  payload:      Option<Arc<V>>,
+  alias:        Option<STag>,
 }
 
 impl<V> Default for DataCell<V> {
@@ -458,19 +1165,56 @@ impl<V> Default for DataCell<V> {
       l_producers:  HashSet::new(),
       d_producers:  HashSet::new(),
       payload:      None,
+      alias:        None,
+    }
+  }
+}
+
+impl<V: Clone> DataCell<V> {
+  fn _get_mut(&mut self, code: &DataCode<V>, txn: Txn) -> &mut V {
+    // A redirected payload stops being an alias once it is written, and is
+    // copied below if still shared; callers may read it before writing.
+    self.alias = None;
+    if self.payload.is_none() {
+      self.payload = match code.alloc {
+        None => panic!("Data: get_mut: missing alloc"),
+        Some(ref alloc) => Some(Arc::new(code._alloc(alloc, txn))),
+      };
     }
+    Arc::make_mut(self.payload.as_mut().unwrap())
   }
 }
 
 pub struct DataCode<V> {
   pub alloc:    Option<Arc<Fn(Txn) -> V>>,
+  /// Size in bytes of a payload, including any buffers it owns; defaults to
+  /// `size_of::<V>()` when missing.
+  pub nbytes:   Option<Arc<Fn(&V) -> usize>>,
 }
 
 impl<V> Clone for DataCode<V> {
   fn clone(&self) -> DataCode<V> {
     DataCode{
       alloc:    self.alloc.clone(),
+      nbytes:   self.nbytes.clone(),
+    }
+  }
+}
+
+impl<V> DataCode<V> {
+  pub fn _nbytes(&self, payload: &V) -> usize {
+    match self.nbytes {
+      None => size_of::<V>(),
+      Some(ref nbytes) => (nbytes)(payload),
+    }
+  }
+
+  fn _alloc(&self, alloc: &Arc<Fn(Txn) -> V>, txn: Txn) -> V {
+    let payload = (alloc)(txn);
+    if let Some(prof) = thread_ctx().maybe_profiler() {
+      prof._alloc(self._nbytes(&payload));
     }
+    payload
   }
 }
 
@@ -505,6 +1249,36 @@ impl<V> ThunkRef<V> {
       _mrk:     PhantomData,
     }
   }
+
+  pub fn _into_tag(self) -> Tag {
+    self.tag
+  }
+
+  pub fn _stable(&self) -> STag {
+    self.tag.stable
+  }
+
+  /// Exempts this thunk's payload from eviction, e.g. because it holds state
+  /// which its entry cannot recompute.
+  pub fn pin(&self) {
+    RESIDENT.with(|res| res.borrow_mut().pinned.insert(self.tag.stable));
+  }
+
+  pub fn unpin(&self) {
+    RESIDENT.with(|res| res.borrow_mut().pinned.remove(&self.tag.stable));
+  }
+
+  /// Marks this thunk as a checkpoint for `backward`: its payload is kept
+  /// after the forward pass, while non-checkpoint payloads are recomputed.
+  pub fn checkpoint(&self) {
+    CHECKPOINTS.with(|ckpts| ckpts.borrow_mut().insert(self.tag.stable));
+  }
+
+  /// The differentiation pass whose adjoints built this thunk, while that
+  /// pass's `Sink` is alive.
+  pub fn pass(&self) -> Option<Pass> {
+    PASS_OF.with(|pass_of| pass_of.borrow().get(&self.tag.stable).cloned())
+  }
 }
 
 impl<V: 'static> ThunkRef<V> {
@@ -513,7 +1287,7 @@ impl<V: 'static> ThunkRef<V> {
       let mut heap = heap.borrow_mut();
       heap.objs[&self.tag.stable].content.clone()
     });
-    if let Some(ref thunk) = (&*thunk_obj).downcast_ref::<Thunk<V>>() {
+    if let Some(ref thunk) = thunk_obj._as_any().downcast_ref::<Thunk<V>>() {
       let cloned_thunk = thunk._clone_exact();
       let cloned_data = match cloned_thunk.data {
         None => None,
@@ -522,7 +1296,7 @@ impl<V: 'static> ThunkRef<V> {
             let mut heap = heap.borrow_mut();
             heap.objs[&s].content.clone()
           });
-          if let Some(ref data) = (&*data_obj).downcast_ref::<Data<V>>() {
+          if let Some(ref data) = data_obj._as_any().downcast_ref::<Data<V>>() {
             let cloned_data = data._clone_exact();
             Some(cloned_data)
           } else {
@@ -543,12 +1317,45 @@ impl<V: 'static> ThunkRef<V> {
     }
   }
 
-  pub fn force_eval(&self, txn: Txn) {
-    let obj = HEAP.with(|heap| {
+  pub fn _get_data(&self) -> LData<V> {
+    let obj = match _lookup_obj(self.tag.stable) {
+      None => panic!("ThunkRef: _get_data: missing thunk"),
+      Some(obj) => obj,
+    };
+    match obj._as_any().downcast_ref::<Thunk<V>>() {
+      None => panic!("ThunkRef: _get_data: type mismatch"),
+      Some(thunk) => match thunk.data {
+        None => panic!("ThunkRef: _get_data: no data"),
+        Some(data) => LDataRef::_from_stag(data)._get_obj(),
+      },
+    }
+  }
+
+  pub fn _set_code(&self, code: ThunkCode<V>) {
+    HEAP.with(|heap| {
+      let mut heap = heap.borrow_mut();
+      let thunk = match heap.objs.get(&self.tag.stable) {
+        None => panic!("ThunkRef: _set_code: missing thunk"),
+        Some(entry) => match entry.content._as_any().downcast_ref::<Thunk<V>>() {
+          None => panic!("ThunkRef: _set_code: type mismatch"),
+          Some(thunk) => {
+            let mut thunk = thunk._clone_exact();
+            thunk.code = code;
+            thunk
+          }
+        },
+      };
+      let entry = heap.objs.get_mut(&self.tag.stable).unwrap();
+      entry.content = Rc::new(thunk);
+    });
+  }
+
+  pub fn force_eval(&self, txn: Txn) {
+    let obj = HEAP.with(|heap| {
       let heap = heap.borrow();
       heap.objs[&self.tag.stable].content.clone()
     });
-    if let Some(ref thunk) = (&*obj).downcast_ref::<Thunk<V>>() {
+    if let Some(ref thunk) = obj._as_any().downcast_ref::<Thunk<V>>() {
       println!("ThunkRef: force_eval: success");
       thunk._force_eval(txn);
     } else {
@@ -600,29 +1407,64 @@ impl<V: 'static> RThunk<V> {
   }
 
   pub fn get(&self, txn: Txn) -> RwLockReadGuard<V> {
+    match self.try_get(txn) {
+      Err(e) => panic!("RThunk: get: {:?}", e),
+      Ok(value) => value,
+    }
+  }
+
+  /// Like `get`, but returns the error when forcing fails, e.g. a
+  /// `BlackHole` when this thunk is already being evaluated. Inside an entry,
+  /// returning false after a failed `try_get` reports this error for the
+  /// entry's own thunk too.
+  pub fn try_get(&self, txn: Txn) -> Result<RwLockReadGuard<V>, EvalError> {
     // TODO: want to avoid strictly forcing an eval here,
     // i.e. the following kind of line:
     //      /*self.eval(txn);*/
-    match self.data {
-      None => panic!("RThunk: get: no data"),
+    let result = match self.data {
+      None => Err(EvalError::MissingData(self.tag.stable)),
       Some(ref data) => {
         match self.state.get() {
           ThunkState::Empty => {
             println!("RThunk: get: force eval...");
-            self.force_eval(txn);
+            self._try_force_eval(txn)
           }
           ThunkState::BlackHole => {
-            panic!();
+            Err(EvalError::BlackHole(self.tag.stable))
           }
           ThunkState::Valid => {
-            println!("RThunk: get: already valid");
+            RESIDENT.with(|res| res.borrow_mut().touch(self.tag.stable));
+            if data._curr_txn() != Some(txn) {
+              self._try_force_eval(txn)
+            } else {
+              println!("RThunk: get: already valid");
+              Ok(())
+            }
           }
         }
+      }
+    };
+    match result {
+      Err(e) => {
+        _record_entry_error(&e);
+        Err(e)
+      }
+      Ok(()) => {
         assert_eq!(ThunkState::Valid, self.state.get());
-        data._get(txn)
+        Ok(self.data.as_ref().unwrap()._get(txn))
       }
     }
   }
+
+  fn _try_force_eval(&self, txn: Txn) -> Result<(), EvalError> {
+    match _lookup_obj(self.tag.stable) {
+      None => Err(EvalError::MissingObj(self.tag.stable)),
+      Some(obj) => match obj._as_thunk() {
+        None => Err(EvalError::MissingObj(self.tag.stable)),
+        Some(thunk) => thunk._try_force_eval(txn),
+      },
+    }
+  }
 }
 
 pub struct Thunk<V> {
@@ -630,10 +1472,33 @@ pub struct Thunk<V> {
   data:     Option<STag>,
   state:    Rc<Cell<ThunkState>>,
   freevars: Vec<Tag>,
+  /// For the first output of a multi-output op, the thunks of all its
+  /// outputs (including itself); empty otherwise. See `MultiOp`.
+  outputs:  Vec<STag>,
+  /// Free variables which the entry forces only on demand, e.g. the
+  /// branches of a switch; `force_all` does not force them up front.
+  lazy:     Vec<STag>,
   code:     ThunkCode<V>,
   plc:      Option<Rc<dyn Placement>>,
 }
 
+impl<V: 'static> Thunk<V> {
+  pub fn new(datacode: DataCode<V>, freevars: Vec<Tag>, code: ThunkCode<V>) -> Thunk<V> {
+    let data = Data::new(datacode);
+    let dataref = data._put_obj();
+    Thunk{
+      stable:   STag::new(),
+      data:     Some(dataref),
+      state:    Rc::new(Cell::new(ThunkState::Empty)),
+      freevars: freevars,
+      outputs:  Vec::new(),
+      lazy:     Vec::new(),
+      code:     code,
+      plc:      None,
+    }
+  }
+}
+
 impl<V> Thunk<V> {
   pub fn _clone_exact(&self) -> Thunk<V> {
     Thunk{
@@ -641,6 +1506,8 @@ impl<V> Thunk<V> {
       data:     self.data,
       state:    self.state.clone(),
       freevars: self.freevars.iter().map(|v| v._clone_exact()).collect(),
+      outputs:  self.outputs.clone(),
+      lazy:     self.lazy.clone(),
       code:     self.code.clone(),
       plc:      self.plc.clone(),
     }
@@ -651,6 +1518,122 @@ impl<V: 'static> HeapObj for Thunk<V> {
   fn _obj_kind(&self) -> HeapObjKind {
     HeapObjKind::Thunk
   }
+
+  fn _as_any(&self) -> &dyn Any {
+    self
+  }
+
+  fn _as_thunk(&self) -> Option<&dyn ThunkObj> {
+    Some(self)
+  }
+}
+
+impl<V: 'static> ThunkObj for Thunk<V> {
+  fn _stable(&self) -> STag {
+    self.stable
+  }
+
+  fn _freevars(&self) -> Vec<STag> {
+    self.freevars.iter().map(|v| v.stable).collect()
+  }
+
+  fn _strict_freevars(&self) -> Vec<STag> {
+    self.freevars.iter().map(|v| v.stable).filter(|s| !self.lazy.contains(s)).collect()
+  }
+
+  fn _is_valid(&self, txn: Txn) -> bool {
+    match (self.state.get(), self.data) {
+      (ThunkState::Valid, Some(data)) => {
+        let dataref = LDataRef::<V>::_from_stag(data);
+        dataref._get_obj().synccell.read().curr_txn == Some(txn)
+      }
+      _ => false,
+    }
+  }
+
+  fn _try_force_eval(&self, txn: Txn) -> Result<(), EvalError> {
+    Thunk::_try_force_eval(self, txn)
+  }
+
+  fn _evict(&self) -> bool {
+    if self.state.get() != ThunkState::Valid || self.code.entry.is_none() {
+      return false;
+    }
+    if RESIDENT.with(|res| res.borrow().pinned.contains(&self.stable)) {
+      return false;
+    }
+    // The outputs of a multi-output op are evicted together, with the first.
+    if self.outputs.first().map_or(false, |&s| s != self.stable) {
+      return false;
+    }
+    let datas: Vec<_> = self._output_data().into_iter().map(|d| LDataRef::<V>::_from_stag(d)._get_obj()).collect();
+    if datas.is_empty() {
+      return false;
+    }
+    let mut cells = Vec::with_capacity(datas.len());
+    for data in datas.iter() {
+      match data.synccell.try_write() {
+        None => return false,
+        Some(cell) => cells.push(cell),
+      }
+    }
+    for cell in cells.iter_mut() {
+      cell.payload = None;
+      cell.alias = None;
+      cell.curr_txn = None;
+    }
+    self.state.set(ThunkState::Empty);
+    true
+  }
+
+  fn _outputs(&self) -> Vec<STag> {
+    self.outputs.clone()
+  }
+
+  fn _adjoint(&self, pass: Pass, sink: &mut Sink) {
+    if let Some(ref adjoint) = self.code.adjoint {
+      (adjoint)(pass, ThunkRef::_from_tag(Tag::new(self.stable)), sink);
+    }
+  }
+
+  fn _batch(&self, batcher: &mut Batcher) -> Result<(), EvalError> {
+    match self.code.batch {
+      None => Err(EvalError::MissingBatchRule(self.stable)),
+      Some(ref batch) => (batch)(ThunkRef::_from_tag(Tag::new(self.stable)), batcher),
+    }
+  }
+}
+
+impl<V: 'static> Thunk<V> {
+  /// The data of every output: all outputs' data for the first output of a
+  /// multi-output op, none for its other outputs, and otherwise `data`.
+  fn _output_data(&self) -> Vec<STag> {
+    match self.outputs.first() {
+      None => self.data.into_iter().collect(),
+      Some(&first) if first != self.stable => Vec::new(),
+      Some(_) => self.outputs.iter().map(|&s| {
+        if s == self.stable {
+          return self.data;
+        }
+        _lookup_obj(s).and_then(|obj| obj._as_any().downcast_ref::<Thunk<V>>().and_then(|thunk| thunk.data))
+      }).filter_map(|d| d).collect(),
+    }
+  }
+}
+
+impl<V> Thunk<V> {
+  pub fn with_batch(mut self, batch: Arc<Fn(ThunkRef<V>, &mut Batcher) -> Result<(), EvalError>>) -> Thunk<V> {
+    self.code.batch = Some(batch);
+    self
+  }
+
+  /// Adds `lazy` to the free variables, as ones which the entry forces only
+  /// on demand.
+  pub fn with_lazy(mut self, lazy: Vec<Tag>) -> Thunk<V> {
+    self.lazy.extend(lazy.iter().map(|v| v.stable));
+    self.freevars.extend(lazy);
+    self
+  }
 }
 
 impl<V: 'static> Thunk<V> {
@@ -660,6 +1643,10 @@ impl<V: 'static> Thunk<V> {
       let retain = RTag::new();
       let mut heap = heap.borrow_mut();
       heap.objs.insert(stable, HeapEntry::anonymous(self));
+      _record_built(stable);
+      if let Some(pass) = CURR_PASS.with(|p| p.get()) {
+        PASS_OF.with(|pass_of| pass_of.borrow_mut().insert(stable, pass));
+      }
       ThunkRef{
         tag:    Tag{stable, retain},
         _mrk:   PhantomData,
@@ -668,40 +1655,297 @@ impl<V: 'static> Thunk<V> {
   }
 
   pub fn _force_eval(&self, txn: Txn) {
+    if let Err(e) = self._try_force_eval(txn) {
+      panic!("Thunk: _force_eval: {:?}", e);
+    }
+  }
+
+  pub fn _try_force_eval(&self, txn: Txn) -> Result<(), EvalError> {
     match self.code.entry {
-      None => panic!("Thunk: _force_eval: missing entry"),
+      None => Err(EvalError::MissingEntry(self.stable)),
       Some(ref entry) => {
+        if self.state.get() == ThunkState::BlackHole {
+          return Err(EvalError::BlackHole(self.stable));
+        }
         self.state.set(ThunkState::BlackHole);
-        // TODO: For extra laziness, can pass `Option<LDataRef<V>>` to the
-        // entry code, and turn into an object there.
-        //
-        // This might be necessary for thunks which do not mutate their "owned"
-        // data and instead simply redirect to another thunk's data.
+        // The entry either fills its own data, or redirects it to another
+        // thunk's data with `LData::redirect`.
         let dataref = LDataRef::<V>::_from_stag(match self.data {
-          None => panic!(),
+          None => {
+            self.state.set(ThunkState::Empty);
+            return Err(EvalError::MissingData(self.stable));
+          }
           Some(stable) => stable,
         });
         let data = dataref._get_obj();
-        (entry)(txn, data);
+        let synccell = data.synccell.clone();
+        let datacode = data.code.clone();
+        let ctx = thread_ctx();
+        if let Some(prof) = ctx.maybe_profiler() {
+          prof._enter(self.stable, self.code.name);
+        }
+        _trace_event('B', self.code.name, self.stable, txn);
+        let start = Instant::now();
+        let guard = _EntryGuard::enter(EntryCtx{thunk: self.stable, txn: txn});
+        let success = (entry)(txn, data);
+        let error = guard.error();
+        drop(guard);
+        let cost = start.elapsed();
+        _trace_event('E', self.code.name, self.stable, txn);
+        if let Some(prof) = ctx.maybe_profiler() {
+          prof._exit(self.stable);
+        }
+        if !success {
+          self.state.set(ThunkState::Empty);
+          return Err(error.unwrap_or(EvalError::EntryFailed(self.stable)));
+        }
+        synccell.write().curr_txn = Some(txn);
+        let nbytes = self._output_data().into_iter().map(|d| {
+          let data = LDataRef::<V>::_from_stag(d)._get_obj();
+          let cell = data.synccell.read();
+          // A redirected payload is accounted to the data it aliases.
+          match (cell.alias, cell.payload.as_ref()) {
+            (None, Some(p)) => datacode._nbytes(p),
+            _ => 0,
+          }
+        }).sum();
         self.state.set(ThunkState::Valid);
+        RESIDENT.with(|res| res.borrow_mut().update(self.stable, nbytes, cost));
+        _maybe_evict(self.stable);
+        Ok(())
+      }
+    }
+  }
+}
+
+impl<V: 'static> Thunk<V> {
+  /// A thunk without code, to be filled in later by `ThunkRef::_fill`.
+  fn _placeholder() -> Thunk<V> {
+    Thunk::new(DataCode{
+      alloc:    None,
+      nbytes:   None,
+    }, Vec::new(), ThunkCode{
+      name:     "placeholder",
+      entry:    None,
+      adjoint:  None,
+      batch:    None,
+    })
+  }
+}
+
+impl<V: 'static> ThunkRef<V> {
+  /// Replaces the placeholder thunk behind this handle by `thunk`, keeping
+  /// the placeholder's data and state, so that handles and `RThunk`s taken
+  /// from the placeholder see the new code.
+  fn _fill(&self, thunk: Thunk<V>) {
+    assert!(thunk.outputs.is_empty(), "ThunkRef: _fill: multi-output thunks are not supported");
+    let (data, datacode) = match self._get_obj().data {
+      None => panic!("ThunkRef: _fill: no data"),
+      Some(data) => (data.stable, thunk.data.and_then(|d| _lookup_obj(d)).map(|obj| match obj._as_any().downcast_ref::<Data<V>>() {
+        None => panic!("ThunkRef: _fill: type mismatch"),
+        Some(d) => d.code.clone(),
+      })),
+    };
+    HEAP.with(|heap| {
+      let mut heap = heap.borrow_mut();
+      if let Some(datacode) = datacode {
+        let filled = match heap.objs[&data].content._as_any().downcast_ref::<Data<V>>() {
+          None => panic!("ThunkRef: _fill: type mismatch"),
+          Some(d) => Data{
+            stable:   d.stable,
+            synccell: d.synccell.clone(),
+            code:     datacode,
+            plc:      d.plc.clone(),
+          },
+        };
+        heap.objs.get_mut(&data).unwrap().content = Rc::new(filled);
       }
+      if let Some(orphan) = thunk.data {
+        heap.objs.remove(&orphan);
+      }
+      let entry = heap.objs.get_mut(&self.tag.stable).unwrap();
+      let state = match entry.content._as_any().downcast_ref::<Thunk<V>>() {
+        None => panic!("ThunkRef: _fill: type mismatch"),
+        Some(prev) => prev.state.clone(),
+      };
+      entry.content = Rc::new(Thunk{
+        stable:   self.tag.stable,
+        data:     Some(data),
+        state:    state,
+        freevars: thunk.freevars,
+        outputs:  Vec::new(),
+        lazy:     thunk.lazy,
+        code:     thunk.code,
+        plc:      thunk.plc,
+      });
+    });
+  }
+}
+
+/// Builds a group of `n` mutually recursive thunks: handles to all of them
+/// are allocated first and passed to `build`, which returns the thunks, in
+/// the same order, whose free variables may then refer to any handle of the
+/// group, including their own. Such cycles only fail when the thunks are
+/// actually forced cyclically, with `EvalError::BlackHole`.
+///
+/// Ops built by `build` may force a handle of the group while it is being
+/// evaluated; their entries should read values with `RThunk::try_get`.
+pub fn letrec<V, F>(n: usize, build: F) -> Vec<ThunkRef<V>>
+where V: 'static, F: FnOnce(&[ThunkRef<V>]) -> Vec<Thunk<V>> {
+  let handles: Vec<ThunkRef<V>> = (0 .. n).map(|_| Thunk::_placeholder()._put_obj()).collect();
+  let thunks = build(&handles);
+  assert_eq!(n, thunks.len(), "letrec: expected {} thunks", n);
+  for (x, thunk) in handles.iter().zip(thunks.into_iter()) {
+    x._fill(thunk);
+  }
+  handles
+}
+
+/// Handle for building and forcing sub-thunks from inside a running entry,
+/// so that the shape of a graph can depend on values, e.g. a recursion
+/// whose depth is decided by an input. Entries get theirs from `current`.
+#[derive(Clone, Copy, Debug)]
+pub struct EntryCtx {
+  thunk:    STag,
+  txn:      Txn,
+}
+
+impl EntryCtx {
+  /// The context of the innermost running entry, if any.
+  pub fn current() -> Option<EntryCtx> {
+    ENTRY_CTXS.with(|ctxs| ctxs.borrow().last().cloned())
+  }
+
+  /// The thunk whose entry is running.
+  pub fn thunk(&self) -> STag {
+    self.thunk
+  }
+
+  pub fn txn(&self) -> Txn {
+    self.txn
+  }
+
+  /// Builds sub-thunks with `f`. They belong to the graph being evaluated,
+  /// not to any differentiation pass which happens to be running.
+  pub fn build<R, F: FnOnce() -> R>(&self, f: F) -> R {
+    let prev_pass = CURR_PASS.with(|p| p.replace(None));
+    let r = f();
+    CURR_PASS.with(|p| p.set(prev_pass));
+    r
+  }
+
+  /// Forces `x` in the entry's `Txn`. On failure, the entry should return
+  /// false; the error is then reported for the entry's thunk.
+  pub fn force<V>(&self, x: &ThunkRef<V>) -> Result<(), EvalError> {
+    let result = _force_all(&[x.tag.stable], self.txn).pop().unwrap();
+    if let Err(ref e) = result {
+      _record_entry_error(e);
+    }
+    result
+  }
+
+  /// Forces `x` and returns a copy of its value.
+  pub fn get<V: Clone + 'static>(&self, x: &ThunkRef<V>) -> Result<V, EvalError> {
+    self.force(x)?;
+    let x = x._get_obj();
+    let v = x.get(self.txn).clone();
+    Ok(v)
+  }
+}
+
+/// Records `e` as the error of the running entry, unless one is recorded
+/// already: the first failure is the one reported for the entry's thunk.
+fn _record_entry_error(e: &EvalError) {
+  ENTRY_ERROR.with(|err| {
+    let mut err = err.borrow_mut();
+    if err.is_none() {
+      *err = Some(e.clone());
     }
+  });
+}
+
+/// Pushes the context of an entry and clears the error slot while the entry
+/// runs; dropping it pops the context and restores the enclosing entry's
+/// error, also when the entry panics.
+struct _EntryGuard {
+  prev_error:   Option<EvalError>,
+}
+
+impl _EntryGuard {
+  fn enter(ctx: EntryCtx) -> _EntryGuard {
+    let prev_error = ENTRY_ERROR.with(|err| err.borrow_mut().take());
+    ENTRY_CTXS.with(|ctxs| ctxs.borrow_mut().push(ctx));
+    _EntryGuard{prev_error}
+  }
+
+  /// The first error recorded by the entry.
+  fn error(&self) -> Option<EvalError> {
+    ENTRY_ERROR.with(|err| err.borrow().clone())
+  }
+}
+
+impl Drop for _EntryGuard {
+  fn drop(&mut self) {
+    let prev_error = self.prev_error.take();
+    let _ = ENTRY_CTXS.try_with(|ctxs| ctxs.borrow_mut().pop());
+    let _ = ENTRY_ERROR.try_with(|err| *err.borrow_mut() = prev_error);
   }
 }
 
+/// Builds a thunk whose entry calls `f` with an `EntryCtx` and the values of
+/// `xs` to build a subgraph, then forces the subgraph's result and shares
+/// its data. `f` runs on every evaluation, so the subgraph may differ from
+/// one `Txn` to the next. The op has no adjoint.
+pub fn dynamic_op<V, F>(name: &'static str, xs: Vec<ThunkRef<V>>, f: F) -> ThunkRef<V>
+where V: Clone + Default + 'static, F: Fn(&EntryCtx, &[&V]) -> Result<ThunkRef<V>, EvalError> + 'static {
+  let code = ThunkCode{
+    name:     name,
+    entry:    {
+      let xs: Vec<_> = xs.iter().map(|x| x._get_obj()).collect();
+      Some(Arc::new(move |txn, y| {
+        let ctx = EntryCtx::current().unwrap();
+        let r = {
+          let xs = match xs.iter().map(|x| x.try_get(txn)).collect::<Result<Vec<_>, _>>() {
+            Err(_) => return false,
+            Ok(xs) => xs,
+          };
+          let xs: Vec<&V> = xs.iter().map(|x| &**x).collect();
+          match ctx.build(|| (f)(&ctx, &xs)) {
+            Err(_) => return false,
+            Ok(r) => r,
+          }
+        };
+        ctx.force(&r).is_ok() && y.redirect(&r._get_obj(), txn).is_ok()
+      }))
+    },
+    adjoint:  None,
+    batch:    None,
+  };
+  let thunk = Thunk::new(DataCode{
+    alloc:    Some(Arc::new(|_txn| V::default())),
+    nbytes:   None,
+  }, xs.into_iter().map(|x| x._into_tag()).collect(), code);
+  thunk._put_obj()
+}
+
 pub struct ThunkCode<V> {
   // TODO
   //pub alloc:    Option<Arc<Fn(Txn) -> V>>,
+  pub name:     &'static str,
   pub entry:    Option<Arc<Fn(Txn, LData<V>) -> bool>>,
   pub adjoint:  Option<Arc<Fn(Pass, ThunkRef<V>, &mut Sink)>>,
+  /// Rebuilds the op over batched inputs; see `Batcher`.
+  pub batch:    Option<Arc<Fn(ThunkRef<V>, &mut Batcher) -> Result<(), EvalError>>>,
 }
 
 impl<V> Clone for ThunkCode<V> {
   fn clone(&self) -> ThunkCode<V> {
     ThunkCode{
       //alloc:    self.alloc.clone(),
+      name:     self.name,
       entry:    self.entry.clone(),
       adjoint:  self.adjoint.clone(),
+      batch:    self.batch.clone(),
     }
   }
 }
@@ -709,7 +1953,352 @@ impl<V> Clone for ThunkCode<V> {
 /*pub trait ThunkPlacement {
 }*/
 
+/// Accumulates cotangents during a reverse pass. Cotangents are themselves
+/// thunks, so an adjoint only builds graph; nothing is evaluated until the
+/// cotangents are forced.
 pub struct Sink {
+  pass:     Pass,
+  adjs:     HashMap<STag, (STag, Box<dyn Any>)>,
+  // The thunk whose adjoint is running, and the cotangent terms which each
+  // adjoint has contributed (before accumulation).
+  curr:     Option<STag>,
+  terms:    HashMap<STag, Vec<STag>>,
+  error:    Option<EvalError>,
+}
+
+impl Sink {
+  pub fn new(pass: Pass) -> Sink {
+    Sink{
+      pass:     pass,
+      adjs:     HashMap::new(),
+      curr:     None,
+      terms:    HashMap::new(),
+      error:    None,
+    }
+  }
+
+  /// Fails the pass; the reverse pass which owns this sink returns `e`.
+  pub fn fail(&mut self, e: EvalError) {
+    if self.error.is_none() {
+      self.error = Some(e);
+    }
+  }
+
+  pub fn pass(&self) -> Pass {
+    self.pass
+  }
+
+  pub fn get_adj<V: 'static>(&self, x: &ThunkRef<V>) -> Option<ThunkRef<V>> {
+    self.adjs.get(&x.tag.stable).map(|&(_, ref adj)| {
+      match adj.downcast_ref::<ThunkRef<V>>() {
+        None => panic!("Sink: get_adj: type mismatch"),
+        Some(adj) => adj.clone(),
+      }
+    })
+  }
+
+  pub fn put_adj<V: Ring>(&mut self, x: &ThunkRef<V>, dx: ThunkRef<V>) {
+    if let Some(curr) = self.curr {
+      self.terms.entry(curr).or_insert_with(Vec::new).push(dx.tag.stable);
+    }
+    let dx = match self.get_adj(x) {
+      None => dx,
+      Some(prev_dx) => add_op(prev_dx, dx),
+    };
+    self.adjs.insert(x.tag.stable, (dx.tag.stable, Box::new(dx)));
+  }
+
+  fn _adj_stag(&self, stable: STag) -> Option<STag> {
+    self.adjs.get(&stable).map(|&(adj, _)| adj)
+  }
+
+  fn _term_stags(&self, stable: STag) -> Vec<STag> {
+    self.terms.get(&stable).cloned().unwrap_or_default()
+  }
+}
+
+impl Drop for Sink {
+  fn drop(&mut self) {
+    let pass = self.pass;
+    let _ = PASS_OF.try_with(|pass_of| pass_of.borrow_mut().retain(|_, p| *p != pass));
+  }
+}
+
+/// Maps the thunks of a graph written for a single example to their
+/// batched counterparts, whose leading axis is the batch axis. Batching
+/// rules (`ThunkCode::batch`) look up their inputs with `get` or
+/// `get_or_broadcast` and record the rebuilt op with `put`, much as adjoints
+/// use a `Sink`. A thunk has a batch axis if and only if it is in the
+/// batcher; ops never guess it from the shapes of their values.
+pub struct Batcher {
+  batched:  HashMap<STag, Box<dyn Any>>,
+  bcasts:   HashMap<TypeId, Box<dyn Any>>,
+}
+
+impl Batcher {
+  pub fn new() -> Batcher {
+    Batcher{
+      batched:  HashMap::new(),
+      bcasts:   HashMap::new(),
+    }
+  }
+
+  /// Registers how to broadcast an unbatched value of type `V` along the
+  /// batch axis. `bcast` is only applied to values without one, so it always
+  /// adds the axis.
+  pub fn with_broadcast<V: 'static>(mut self, bcast: Arc<Fn(ThunkRef<V>) -> ThunkRef<V>>) -> Batcher {
+    self.bcasts.insert(TypeId::of::<V>(), Box::new(bcast));
+    self
+  }
+
+  pub fn is_batched<V>(&self, x: &ThunkRef<V>) -> bool {
+    self.batched.contains_key(&x.tag.stable)
+  }
+
+  pub fn get<V: 'static>(&self, x: &ThunkRef<V>) -> Option<ThunkRef<V>> {
+    self.batched.get(&x.tag.stable).map(|xb| {
+      match xb.downcast_ref::<ThunkRef<V>>() {
+        None => panic!("Batcher: get: type mismatch"),
+        Some(xb) => xb.clone(),
+      }
+    })
+  }
+
+  /// Returns the batched counterpart of `x`, broadcasting `x` if it does not
+  /// depend on the batched input.
+  pub fn get_or_broadcast<V: 'static>(&self, x: &ThunkRef<V>) -> Result<ThunkRef<V>, EvalError> {
+    if let Some(xb) = self.get(x) {
+      return Ok(xb);
+    }
+    match self.bcasts.get(&TypeId::of::<V>()).and_then(|b| b.downcast_ref::<Arc<Fn(ThunkRef<V>) -> ThunkRef<V>>>()) {
+      None => Err(EvalError::MissingBatchRule(x.tag.stable)),
+      Some(bcast) => Ok((bcast)(x.clone())),
+    }
+  }
+
+  pub fn put<V: 'static>(&mut self, x: &ThunkRef<V>, xb: ThunkRef<V>) {
+    self.batched.insert(x.tag.stable, Box::new(xb));
+  }
+}
+
+/// A batching rule for ops which act independently on each element (or
+/// broadcast like elementwise ops): rebuilds the op with `build` over the
+/// batched, or else broadcast, inputs.
+pub fn _map_batch_rule<V, F>(xs: Vec<ThunkRef<V>>, build: F) -> Arc<Fn(ThunkRef<V>, &mut Batcher) -> Result<(), EvalError>>
+where V: 'static, F: Fn(&[ThunkRef<V>]) -> ThunkRef<V> + 'static {
+  Arc::new(move |y, batcher| {
+    let mut xbs = Vec::with_capacity(xs.len());
+    for x in xs.iter() {
+      xbs.push(batcher.get_or_broadcast(x)?);
+    }
+    let yb = (build)(&xbs);
+    batcher.put(&y, yb);
+    Ok(())
+  })
+}
+
+/// Rewrites the graph of `y` over the single-example input `x` into a graph
+/// over the batched input `xb`, by applying the batching rule of every thunk
+/// which depends on `x`. Returns `None` if `y` does not depend on `x`.
+pub fn batch_graph<V: 'static, W: 'static>(y: &ThunkRef<W>, x: &ThunkRef<V>, xb: ThunkRef<V>, batcher: &mut Batcher) -> Result<Option<ThunkRef<W>>, EvalError> {
+  let order = _topo_sort(&[y.tag.stable])?;
+  batcher.put(x, xb);
+  for &stable in order.iter() {
+    if batcher.batched.contains_key(&stable) {
+      continue;
+    }
+    let obj = match _lookup_obj(stable) {
+      None => return Err(EvalError::MissingObj(stable)),
+      Some(obj) => obj,
+    };
+    if let Some(thunk) = obj._as_thunk() {
+      if thunk._freevars().iter().any(|s| batcher.batched.contains_key(s)) {
+        thunk._batch(batcher)?;
+      }
+    }
+  }
+  Ok(batcher.get(y))
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CheckpointPolicy {
+  /// Keep every forward payload alive for the backward pass.
+  KeepAll,
+  /// Keep only thunks marked with `ThunkRef::checkpoint` (and sources).
+  Marked,
+  /// Additionally checkpoint every `ceil(sqrt(N))`-th of the `N` thunks.
+  Sqrt,
+}
+
+/// Builds the reverse pass of `y` with seed cotangent `dy`, calling
+/// `ThunkCode::adjoint` on every thunk reachable from `y` in reverse
+/// topological order. The returned `Sink` maps thunks to their cotangents.
+pub fn reverse<V: 'static>(y: &ThunkRef<V>, dy: ThunkRef<V>) -> Result<Sink, EvalError> {
+  let order = _topo_sort(&[y.tag.stable])?;
+  let mut sink = Sink::new(pass());
+  sink.adjs.insert(y.tag.stable, (dy.tag.stable, Box::new(dy)));
+  _reverse(&order, &mut sink)?;
+  Ok(sink)
+}
+
+fn _reverse(order: &[STag], sink: &mut Sink) -> Result<(), EvalError> {
+  let pass = sink.pass;
+  let prev_pass = CURR_PASS.with(|p| p.replace(Some(pass)));
+  for &stable in order.iter().rev() {
+    match _lookup_obj(stable) {
+      None => continue,
+      Some(obj) => if let Some(thunk) = obj._as_thunk() {
+        // The first output of a multi-output op runs the op's adjoint when
+        // any of its outputs has a cotangent.
+        let outputs = thunk._outputs();
+        let has_adj = if outputs.first() == Some(&stable) {
+          outputs.iter().any(|s| sink.adjs.contains_key(s))
+        } else {
+          sink.adjs.contains_key(&stable)
+        };
+        if !has_adj {
+          continue;
+        }
+        sink.curr = Some(stable);
+        thunk._adjoint(pass, sink);
+        sink.curr = None;
+        if sink.error.is_some() {
+          break;
+        }
+      },
+    }
+  }
+  CURR_PASS.with(|p| p.set(prev_pass));
+  match sink.error {
+    None => Ok(()),
+    Some(ref e) => Err(e.clone()),
+  }
+}
+
+/// An adjoint which fails the reverse pass, for ops which have no
+/// derivative rule: differentiating through them is then an error instead
+/// of silently contributing a zero cotangent.
+pub fn non_differentiable<V: 'static>() -> Arc<Fn(Pass, ThunkRef<V>, &mut Sink)> {
+  Arc::new(|_pass, y, sink| {
+    sink.fail(EvalError::NotDifferentiable(y._stable()));
+  })
+}
+
+/// Like `reverse`, but seeds several outputs at once; `ys` pairs each output
+/// with its seed cotangent.
+pub fn reverse_many<V: Ring>(ys: &[(ThunkRef<V>, ThunkRef<V>)]) -> Result<Sink, EvalError> {
+  let roots: Vec<_> = ys.iter().map(|&(ref y, _)| y.tag.stable).collect();
+  let order = _topo_sort(&roots)?;
+  let mut sink = Sink::new(pass());
+  for &(ref y, ref dy) in ys.iter() {
+    sink.put_adj(y, dy.clone());
+  }
+  _reverse(&order, &mut sink)?;
+  Ok(sink)
+}
+
+/// Returns the gradient of `y` with respect to each of `xs`, seeded by `dy`,
+/// as thunks; inputs which `y` does not depend on get a zero gradient.
+/// Because the gradient is itself a graph, it can be differentiated again.
+pub fn grad<V: Ring>(y: &ThunkRef<V>, dy: ThunkRef<V>, xs: &[ThunkRef<V>]) -> Result<Vec<ThunkRef<V>>, EvalError> {
+  let sink = reverse(y, dy)?;
+  Ok(xs.iter().map(|x| sink.get_adj(x).unwrap_or_else(|| constant_op(V::zero()))).collect())
+}
+
+/// Hessian-vector product of the scalar `loss` with respect to `params`,
+/// in the direction `v`: differentiates the gradient graph `g` once more,
+/// seeding each `g[i]` with `v[i]`.
+pub fn hvp<V: Ring>(loss: &ThunkRef<V>, params: &[ThunkRef<V>], v: &[ThunkRef<V>]) -> Result<Vec<ThunkRef<V>>, EvalError> {
+  assert_eq!(params.len(), v.len(), "hvp: params and v differ in length");
+  let gs = grad(loss, constant_op(V::one()), params)?;
+  let seeds: Vec<_> = gs.into_iter().zip(v.iter().cloned()).collect();
+  let sink = reverse_many(&seeds)?;
+  Ok(params.iter().map(|x| sink.get_adj(x).unwrap_or_else(|| constant_op(V::zero()))).collect())
+}
+
+fn _evict_stag(stable: STag) {
+  let evicted = match _lookup_obj(stable) {
+    None => false,
+    Some(obj) => obj._as_thunk().map(|thunk| thunk._evict()).unwrap_or(false),
+  };
+  if evicted {
+    RESIDENT.with(|res| res.borrow_mut().remove(stable));
+  }
+}
+
+/// Runs the forward and backward passes of `y` in `txn`, forcing the
+/// cotangents of every thunk reachable from `y`.
+///
+/// Unless the policy is `KeepAll`, non-checkpoint payloads are discarded
+/// after the forward pass. The backward pass then walks the segments
+/// between checkpoints from last to first, forcing the cotangent terms
+/// contributed by each segment's adjoints; this recomputes the segment from
+/// the preceding checkpoint, and the recomputed payloads are discarded again
+/// before moving on to the previous segment.
+pub fn backward<V: 'static>(y: &ThunkRef<V>, dy: ThunkRef<V>, txn: Txn, policy: CheckpointPolicy) -> Result<Sink, EvalError> {
+  let order = _topo_sort(&[y.tag.stable])?;
+  _force_all(&[y.tag.stable], txn).pop().unwrap()?;
+  let mut ckpts: HashSet<STag> = CHECKPOINTS.with(|ckpts| {
+    let ckpts = ckpts.borrow();
+    order.iter().cloned().filter(|s| ckpts.contains(s)).collect()
+  });
+  ckpts.insert(y.tag.stable);
+  for &stable in order.iter() {
+    let is_source = _lookup_obj(stable)
+      .and_then(|obj| obj._as_thunk().map(|thunk| thunk._freevars().is_empty()))
+      .unwrap_or(true);
+    if is_source {
+      ckpts.insert(stable);
+    }
+  }
+  match policy {
+    CheckpointPolicy::KeepAll => {
+      ckpts.extend(order.iter().cloned());
+    }
+    CheckpointPolicy::Marked => {}
+    CheckpointPolicy::Sqrt => {
+      let stride = (order.len() as f64).sqrt().ceil() as usize;
+      for (idx, &stable) in order.iter().enumerate() {
+        if (idx + 1) % stride.max(1) == 0 {
+          ckpts.insert(stable);
+        }
+      }
+    }
+  }
+  for &stable in order.iter() {
+    if !ckpts.contains(&stable) {
+      _evict_stag(stable);
+    }
+  }
+  let mut sink = Sink::new(pass());
+  sink.adjs.insert(y.tag.stable, (dy.tag.stable, Box::new(dy)));
+  _reverse(&order, &mut sink)?;
+  // Split the forward order into segments, each ending at a checkpoint.
+  let mut segments: Vec<Vec<STag>> = vec![Vec::new()];
+  for &stable in order.iter() {
+    segments.last_mut().unwrap().push(stable);
+    if ckpts.contains(&stable) {
+      segments.push(Vec::new());
+    }
+  }
+  for segment in segments.iter().rev() {
+    let terms: Vec<_> = segment.iter().flat_map(|&s| sink._term_stags(s)).collect();
+    for result in _force_all(&terms, txn) {
+      result?;
+    }
+    // Forcing the terms may also recompute thunks of earlier segments.
+    for &stable in order.iter() {
+      if !ckpts.contains(&stable) {
+        _evict_stag(stable);
+      }
+    }
+  }
+  // What remains are the sums of the already forced terms.
+  let adjs: Vec<_> = order.iter().filter_map(|&s| sink._adj_stag(s)).collect();
+  for result in _force_all(&adjs, txn) {
+    result?;
+  }
+  Ok(sink)
 }
 
 pub struct ConstantOp<V> {
@@ -728,31 +2317,42 @@ impl<V: Clone + Debug + 'static> ConstantOp<V> {
           v.clone()
         }))
       },
+      nbytes:   None,
     });
     let dataref = data._put_obj();
-    let code = ThunkCode{
-      entry:    {
-        let value = value.clone();
-        Some(Arc::new(move |txn, y| {
-          // TODO: this should write something to `data`.
-          println!("ConstantOp: entry");
-          let mut y = y.get_mut(txn);
-          *y = value.clone();
-          println!("ConstantOp:   result: {:?}", *y);
-          true
-        }))
-      },
-      adjoint:  None,
-    };
     Thunk{
       stable:   stable,
       data:     Some(dataref),
       state:    Rc::new(Cell::new(ThunkState::Empty)),
       freevars: Vec::new(),
-      code:     code,
+      outputs:  Vec::new(),
+      lazy:     Vec::new(),
+      code:     ConstantOp::_build_code(value),
       plc:      None,
     }
   }
+
+  fn _build_code(value: V) -> ThunkCode<V> {
+    ThunkCode{
+      name:     "constant",
+      entry:    Some(Arc::new(move |txn, y| {
+        // TODO: this should write something to `data`.
+        println!("ConstantOp: entry");
+        let mut y = y.get_mut(txn);
+        *y = value.clone();
+        println!("ConstantOp:   result: {:?}", *y);
+        true
+      })),
+      adjoint:  None,
+      batch:    None,
+    }
+  }
+
+  /// Replaces the value of the constant thunk `x` in place. Downstream thunks
+  /// see the new value once they are re-forced in a fresh `Txn`.
+  pub fn reset(x: &ThunkRef<V>, value: V) {
+    x._set_code(ConstantOp::_build_code(value));
+  }
 }
 
 pub fn constant_op<V: Clone + Debug + 'static>(value: V) -> ThunkRef<V> {
@@ -762,11 +2362,136 @@ pub fn constant_op<V: Clone + Debug + 'static>(value: V) -> ThunkRef<V> {
   thunkref
 }
 
+pub struct VariableOp<V> {
+  _mrk: PhantomData<V>,
+}
+
+impl<V: Clone + Debug + 'static> VariableOp<V> {
+  pub fn build_thunk(init: V) -> Thunk<V> {
+    let code = ThunkCode{
+      name:     "variable",
+      entry:    Some(Arc::new(move |txn, y| {
+        // Unlike a constant, re-entering a variable keeps its current value;
+        // the payload is only initialized when first allocated.
+        let _ = y.get_mut(txn);
+        true
+      })),
+      adjoint:  None,
+      batch:    None,
+    };
+    Thunk::new(DataCode{
+      alloc:    Some(Arc::new(move |_txn| init.clone())),
+      nbytes:   None,
+    }, Vec::new(), code)
+  }
+}
+
+/// Builds a mutable leaf thunk, e.g. a model parameter. Its payload is
+/// updated in place through `LData::get_mut` and is never evicted.
+pub fn variable_op<V: Clone + Debug + 'static>(init: V) -> ThunkRef<V> {
+  let thunk = VariableOp::build_thunk(init);
+  let thunkref = thunk._put_obj();
+  thunkref.pin();
+  thunkref
+}
+
+/// Ordering token threaded through effect ops; counts the effects which
+/// were performed before it.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct Token {
+  seq:  u64,
+}
+
+impl Token {
+  pub fn seq(&self) -> u64 {
+    self.seq
+  }
+}
+
+/// Returns a fresh token, the start of a sequence of effects.
+pub fn token_op() -> ThunkRef<Token> {
+  constant_op(Token::default())
+}
+
+pub struct EffectOp<V> {
+  _mrk: PhantomData<V>,
+}
+
+impl<V: 'static> EffectOp<V> {
+  /// Builds an effect which runs `f` on the values of `xs` after the effects
+  /// ordered before `token`, and returns the token for the effects after
+  /// it. If `f` returns false the entry fails.
+  pub fn build_thunk<F>(name: &'static str, token: ThunkRef<Token>, xs: Vec<ThunkRef<V>>, f: F) -> Thunk<Token>
+  where F: Fn(Txn, &[&V]) -> bool + 'static {
+    let code = ThunkCode{
+      name:     name,
+      entry:    {
+        let token = token._get_obj();
+        let xs: Vec<_> = xs.iter().map(|x| x._get_obj()).collect();
+        Some(Arc::new(move |txn, y| {
+          let prev = match token.try_get(txn) {
+            Err(_) => return false,
+            Ok(prev) => *prev,
+          };
+          let xs = match xs.iter().map(|x| x.try_get(txn)).collect::<Result<Vec<_>, _>>() {
+            Err(_) => return false,
+            Ok(xs) => xs,
+          };
+          let xs: Vec<&V> = xs.iter().map(|x| &**x).collect();
+          if !(f)(txn, &xs) {
+            return false;
+          }
+          *y.get_mut(txn) = Token{seq: prev.seq + 1};
+          true
+        }))
+      },
+      adjoint:  None,
+      batch:    None,
+    };
+    let mut freevars = vec![token._into_tag()];
+    freevars.extend(xs.into_iter().map(|x| x._into_tag()));
+    Thunk::new(DataCode{
+      alloc:    Some(Arc::new(|_txn| Token::default())),
+      nbytes:   None,
+    }, freevars, code)
+  }
+}
+
+/// Builds a side-effecting op ordered after `token`, e.g. logging; see
+/// `EffectOp`. Effects run when a later token is forced, at most once per
+/// `Txn`: the returned token is pinned, so neither eviction nor `backward`
+/// recomputation can repeat or drop the effect.
+pub fn effect_op<V, F>(name: &'static str, token: ThunkRef<Token>, xs: Vec<ThunkRef<V>>, f: F) -> ThunkRef<Token>
+where V: 'static, F: Fn(Txn, &[&V]) -> bool + 'static {
+  let thunk = EffectOp::build_thunk(name, token, xs, f);
+  let thunkref = thunk._put_obj();
+  thunkref.pin();
+  thunkref
+}
+
+/// An effect which writes the value of `value` into the variable `var`.
+pub fn assign_op<V: Clone + 'static>(token: ThunkRef<Token>, var: &ThunkRef<V>, value: ThunkRef<V>) -> ThunkRef<Token> {
+  let var = var.clone();
+  effect_op("assign", token, vec![value], move |txn, xs| {
+    *var._get_data().get_mut(txn) = xs[0].clone();
+    true
+  })
+}
+
+/// Forces `token`, performing every effect ordered before it in sequence,
+/// and returns it.
+pub fn run_effects(token: &ThunkRef<Token>, txn: Txn) -> Result<Token, EvalError> {
+  _force_all(&[token.tag.stable], txn).pop().unwrap()?;
+  let token = token._get_obj();
+  let token = *token.get(txn);
+  Ok(token)
+}
+
 pub struct AddOp<V> {
   _mrk: PhantomData<V>,
 }
 
-impl<V: Add<Output=V> + Clone + Default + Debug + 'static> AddOp<V> {
+impl<V: Ring> AddOp<V> {
   pub fn build_thunk(x1: ThunkRef<V>, x2: ThunkRef<V>) -> Thunk<V> {
     // TODO
     let stable = STag::new();
@@ -776,9 +2501,11 @@ impl<V: Add<Output=V> + Clone + Default + Debug + 'static> AddOp<V> {
         // TODO
         V::default()
       })),
+      nbytes:   None,
     });
     let dataref = data._put_obj();
     let code = ThunkCode{
+      name:     "add",
       entry:    {
         /*let x1 = x1._clone_exact();
         let x2 = x2._clone_exact();*/
@@ -787,34 +2514,233 @@ impl<V: Add<Output=V> + Clone + Default + Debug + 'static> AddOp<V> {
         Some(Arc::new(move |txn, y| {
           // TODO
           println!("AddOp: entry");
-          let x1 = x1.get(txn);
-          let x2 = x2.get(txn);
+          let (x1, x2) = match (x1.try_get(txn), x2.try_get(txn)) {
+            (Ok(x1), Ok(x2)) => (x1, x2),
+            _ => return false,
+          };
           let mut y = y.get_mut(txn);
           *y = x1.clone() + x2.clone();
           println!("AddOp:   result: {:?}", *y);
           true
         }))
       },
-      adjoint:  None,
+      adjoint:  {
+        let x1 = x1.clone();
+        let x2 = x2.clone();
+        Some(Arc::new(move |_pass, y, sink| {
+          let dy = sink.get_adj(&y).unwrap();
+          sink.put_adj(&x1, reduce_like_op(dy.clone(), x1.clone()));
+          sink.put_adj(&x2, reduce_like_op(dy, x2.clone()));
+        }))
+      },
+      batch:    Some(_map_batch_rule(vec![x1.clone(), x2.clone()], |xs| add_op(xs[0].clone(), xs[1].clone()))),
     };
     Thunk{
       stable:   stable,
       data:     Some(dataref),
       state:    Rc::new(Cell::new(ThunkState::Empty)),
       freevars: vec![x1.tag, x2.tag],
+      outputs:  Vec::new(),
+      lazy:     Vec::new(),
       code:     code,
       plc:      None,
     }
   }
 }
 
-pub fn add_op<V: Add<Output=V> + Clone + Default + Debug + 'static>(x1: ThunkRef<V>, x2: ThunkRef<V>) -> ThunkRef<V> {
+pub fn add_op<V: Ring>(x1: ThunkRef<V>, x2: ThunkRef<V>) -> ThunkRef<V> {
   // TODO
   let thunk = AddOp::build_thunk(x1, x2);
   let thunkref = thunk._put_obj();
   thunkref
 }
 
+pub struct SubOp<V> {
+  _mrk: PhantomData<V>,
+}
+
+impl<V: Ring> SubOp<V> {
+  pub fn build_thunk(x1: ThunkRef<V>, x2: ThunkRef<V>) -> Thunk<V> {
+    let adjoint: Arc<Fn(Pass, ThunkRef<V>, &mut Sink)> = {
+      let x1 = x1.clone();
+      let x2 = x2.clone();
+      Arc::new(move |_pass, y, sink| {
+        let dy = sink.get_adj(&y).unwrap();
+        sink.put_adj(&x1, reduce_like_op(dy.clone(), x1.clone()));
+        sink.put_adj(&x2, reduce_like_op(sub_op(constant_op(V::zero()), dy), x2.clone()));
+      })
+    };
+    let batch = _map_batch_rule(vec![x1.clone(), x2.clone()], |xs| sub_op(xs[0].clone(), xs[1].clone()));
+    MapOp::build_thunk("sub", vec![x1, x2], |xs| xs[0].clone() - xs[1].clone(), Some(adjoint), None)
+      .with_batch(batch)
+  }
+}
+
+pub fn sub_op<V: Ring>(x1: ThunkRef<V>, x2: ThunkRef<V>) -> ThunkRef<V> {
+  let thunk = SubOp::build_thunk(x1, x2);
+  let thunkref = thunk._put_obj();
+  thunkref
+}
+
+pub struct MulOp<V> {
+  _mrk: PhantomData<V>,
+}
+
+impl<V: Ring> MulOp<V> {
+  pub fn build_thunk(x1: ThunkRef<V>, x2: ThunkRef<V>) -> Thunk<V> {
+    let adjoint: Arc<Fn(Pass, ThunkRef<V>, &mut Sink)> = {
+      let x1 = x1.clone();
+      let x2 = x2.clone();
+      Arc::new(move |_pass, y, sink| {
+        let dy = sink.get_adj(&y).unwrap();
+        sink.put_adj(&x1, reduce_like_op(mul_op(dy.clone(), conj_op(x2.clone())), x1.clone()));
+        sink.put_adj(&x2, reduce_like_op(mul_op(dy, conj_op(x1.clone())), x2.clone()));
+      })
+    };
+    let batch = _map_batch_rule(vec![x1.clone(), x2.clone()], |xs| mul_op(xs[0].clone(), xs[1].clone()));
+    MapOp::build_thunk("mul", vec![x1, x2], |xs| xs[0].clone() * xs[1].clone(), Some(adjoint), None)
+      .with_batch(batch)
+  }
+}
+
+pub fn mul_op<V: Ring>(x1: ThunkRef<V>, x2: ThunkRef<V>) -> ThunkRef<V> {
+  let thunk = MulOp::build_thunk(x1, x2);
+  let thunkref = thunk._put_obj();
+  thunkref
+}
+
+pub struct ConjOp<V> {
+  _mrk: PhantomData<V>,
+}
+
+impl<V: Ring> ConjOp<V> {
+  pub fn build_thunk(x: ThunkRef<V>) -> Thunk<V> {
+    let adjoint: Arc<Fn(Pass, ThunkRef<V>, &mut Sink)> = {
+      let x = x.clone();
+      Arc::new(move |_pass, y, sink| {
+        let dy = sink.get_adj(&y).unwrap();
+        sink.put_adj(&x, conj_op(dy));
+      })
+    };
+    let batch = _map_batch_rule(vec![x.clone()], |xs| conj_op(xs[0].clone()));
+    MapOp::build_thunk("conj", vec![x], |xs| xs[0].clone().conj(), Some(adjoint), None)
+      .with_batch(batch)
+  }
+}
+
+pub fn conj_op<V: Ring>(x: ThunkRef<V>) -> ThunkRef<V> {
+  let thunk = ConjOp::build_thunk(x);
+  let thunkref = thunk._put_obj();
+  thunkref
+}
+
+/// Sums `dx` down to the shape of `x`; broadcasting binary ops apply this
+/// to the cotangents of their operands.
+pub struct ReduceLikeOp<V> {
+  _mrk: PhantomData<V>,
+}
+
+impl<V: Ring> ReduceLikeOp<V> {
+  pub fn build_thunk(dx: ThunkRef<V>, x: ThunkRef<V>) -> Thunk<V> {
+    let adjoint: Arc<Fn(Pass, ThunkRef<V>, &mut Sink)> = {
+      let dx = dx.clone();
+      Arc::new(move |_pass, y, sink| {
+        let dy = sink.get_adj(&y).unwrap();
+        sink.put_adj(&dx, expand_like_op(dy, dx.clone()));
+      })
+    };
+    let batch = _map_batch_rule(vec![dx.clone(), x.clone()], |xs| reduce_like_op(xs[0].clone(), xs[1].clone()));
+    MapOp::build_thunk("reduce_like", vec![dx, x], |xs| xs[0].clone().reduce_like(xs[1]), Some(adjoint), None)
+      .with_batch(batch)
+  }
+}
+
+pub fn reduce_like_op<V: Ring>(dx: ThunkRef<V>, x: ThunkRef<V>) -> ThunkRef<V> {
+  let thunk = ReduceLikeOp::build_thunk(dx, x);
+  let thunkref = thunk._put_obj();
+  thunkref
+}
+
+/// Broadcasts `x` up to the shape of `like`.
+pub struct ExpandLikeOp<V> {
+  _mrk: PhantomData<V>,
+}
+
+impl<V: Ring> ExpandLikeOp<V> {
+  pub fn build_thunk(x: ThunkRef<V>, like: ThunkRef<V>) -> Thunk<V> {
+    let adjoint: Arc<Fn(Pass, ThunkRef<V>, &mut Sink)> = {
+      let x = x.clone();
+      Arc::new(move |_pass, y, sink| {
+        let dy = sink.get_adj(&y).unwrap();
+        sink.put_adj(&x, reduce_like_op(dy, x.clone()));
+      })
+    };
+    let batch = _map_batch_rule(vec![x.clone(), like.clone()], |xs| expand_like_op(xs[0].clone(), xs[1].clone()));
+    MapOp::build_thunk("expand_like", vec![x, like], |xs| xs[0].clone().expand_like(xs[1]), Some(adjoint), None)
+      .with_batch(batch)
+  }
+}
+
+pub fn expand_like_op<V: Ring>(x: ThunkRef<V>, like: ThunkRef<V>) -> ThunkRef<V> {
+  let thunk = ExpandLikeOp::build_thunk(x, like);
+  let thunkref = thunk._put_obj();
+  thunkref
+}
+
+pub struct NegOp<V> {
+  _mrk: PhantomData<V>,
+}
+
+impl<V: Field> NegOp<V> {
+  pub fn build_thunk(x: ThunkRef<V>) -> Thunk<V> {
+    let adjoint: Arc<Fn(Pass, ThunkRef<V>, &mut Sink)> = {
+      let x = x.clone();
+      Arc::new(move |_pass, y, sink| {
+        let dy = sink.get_adj(&y).unwrap();
+        sink.put_adj(&x, neg_op(dy));
+      })
+    };
+    let batch = _map_batch_rule(vec![x.clone()], |xs| neg_op(xs[0].clone()));
+    MapOp::build_thunk("neg", vec![x], |xs| -xs[0].clone(), Some(adjoint), None)
+      .with_batch(batch)
+  }
+}
+
+pub fn neg_op<V: Field>(x: ThunkRef<V>) -> ThunkRef<V> {
+  let thunk = NegOp::build_thunk(x);
+  let thunkref = thunk._put_obj();
+  thunkref
+}
+
+pub struct DivOp<V> {
+  _mrk: PhantomData<V>,
+}
+
+impl<V: Field> DivOp<V> {
+  pub fn build_thunk(x1: ThunkRef<V>, x2: ThunkRef<V>) -> Thunk<V> {
+    let adjoint: Arc<Fn(Pass, ThunkRef<V>, &mut Sink)> = {
+      let x1 = x1.clone();
+      let x2 = x2.clone();
+      Arc::new(move |_pass, y, sink| {
+        // With `y = x1 / x2`: `dx1 = dy / x2` and `dx2 = -dy * y / x2`.
+        let dy = sink.get_adj(&y).unwrap();
+        let dx1 = div_op(dy, conj_op(x2.clone()));
+        sink.put_adj(&x1, reduce_like_op(dx1.clone(), x1.clone()));
+        sink.put_adj(&x2, reduce_like_op(neg_op(mul_op(dx1, conj_op(y))), x2.clone()));
+      })
+    };
+    let batch = _map_batch_rule(vec![x1.clone(), x2.clone()], |xs| div_op(xs[0].clone(), xs[1].clone()));
+    MapOp::build_thunk("div", vec![x1, x2], |xs| xs[0].clone() / xs[1].clone(), Some(adjoint), None)
+      .with_batch(batch)
+  }
+}
+
+pub fn div_op<V: Field>(x1: ThunkRef<V>, x2: ThunkRef<V>) -> ThunkRef<V> {
+  let thunk = DivOp::build_thunk(x1, x2);
+  let thunkref = thunk._put_obj();
+  thunkref
+}
+
 pub struct SwitchOp<V> {
   _mrk: PhantomData<V>,
 }
@@ -829,9 +2755,12 @@ impl<V: Clone + Default + Debug + 'static> SwitchOp<V> {
         // TODO
         V::default()
       })),
+      nbytes:   None,
     });
     let dataref = data._put_obj();
+    let lazy = vec![x1.tag.stable, x2.tag.stable];
     let code = ThunkCode{
+      name:     "switch",
       entry:    {
         /*let cond = cond._clone_exact();
         let x1 = x1._clone_exact();
@@ -840,31 +2769,35 @@ impl<V: Clone + Default + Debug + 'static> SwitchOp<V> {
         let x1 = x1._get_obj();
         let x2 = x2._get_obj();
         Some(Arc::new(move |txn, y| {
-          // TODO
           println!("SwitchOp: entry");
-          let cond = cond.get(txn);
-          let x1 = x1.get(txn);
-          let x2 = x2.get(txn);
-          let mut y = y.get_mut(txn);
-          match *cond {
-            false   => {
-              *y = x1.clone();
-            }
-            true    => {
-              *y = x2.clone();
-            }
+          // Only the selected branch is forced, and its data is shared; both
+          // branches are lazy free variables, so `force_all` leaves them to
+          // this entry too.
+          let cond = match cond.try_get(txn) {
+            Err(_) => return false,
+            Ok(cond) => *cond,
+          };
+          let result = match cond {
+            false   => y.redirect(&x1, txn),
+            true    => y.redirect(&x2, txn),
+          };
+          if result.is_err() {
+            return false;
           }
-          println!("SwitchOp:   result: {:?}", *y);
+          println!("SwitchOp:   result: {:?}", *y.get(txn));
           true
         }))
       },
       adjoint:  None,
+      batch:    None,
     };
     Thunk{
       stable:   stable,
       data:     Some(dataref),
       state:    Rc::new(Cell::new(ThunkState::Empty)),
-      freevars: vec![x1.tag, x2.tag],
+      freevars: vec![cond.tag, x1.tag, x2.tag],
+      outputs:  Vec::new(),
+      lazy:     lazy,
       code:     code,
       plc:      None,
     }
@@ -877,3 +2810,873 @@ pub fn switch_op<V: Clone + Default + Debug + 'static>(cond: ThunkRef<bool>, x1:
   let thunkref = thunk._put_obj();
   thunkref
 }
+
+/// One evaluation of a loop: for each iteration, the placeholders holding
+/// its inputs and the thunks of its outputs. The thunks which the
+/// evaluation built are removed from the heap when it is dropped.
+struct LoopEval<V> {
+  iters:    Vec<(Vec<ThunkRef<V>>, Vec<ThunkRef<V>>)>,
+  built:    _HeapRetain,
+}
+
+/// The evaluations of a loop, by `Txn`.
+struct LoopTrace<V> {
+  evals:    HashMap<Txn, LoopEval<V>>,
+}
+
+impl<V> LoopTrace<V> {
+  fn new() -> Rc<RefCell<LoopTrace<V>>> {
+    Rc::new(RefCell::new(LoopTrace{evals: HashMap::new()}))
+  }
+}
+
+/// Records the evaluation of a loop in `txn`. The loop's data now holds the
+/// value of `txn`, and a cotangent forced in another `Txn` forces the loop
+/// again first, so the evaluations of other `Txn`s are dropped.
+fn _record_eval<V>(trace: &RefCell<LoopTrace<V>>, txn: Txn, eval: LoopEval<V>) {
+  let stale: Vec<_> = {
+    let mut trace = trace.borrow_mut();
+    let stale = trace.evals.drain().map(|(_, eval)| eval).collect();
+    trace.evals.insert(txn, eval);
+    stale
+  };
+  // Dropping removes their thunks from the heap, which may in turn drop the
+  // traces of loops nested in them.
+  drop(stale);
+}
+
+/// Builds the subgraph of one iteration over fresh placeholders, once, when
+/// the loop is built, and returns the thunks of type `V` which it captures
+/// from the enclosing graph, i.e. the free variables of its thunks which it
+/// did not build itself.
+fn _probe_captured<V, F>(n: usize, build: F) -> Vec<ThunkRef<V>>
+where V: Ring, F: FnOnce(Vec<ThunkRef<V>>) -> Vec<ThunkRef<V>> {
+  let mut probe = _HeapRetain::new();
+  let outs = _build_retained(&mut probe, || build((0 .. n).map(|_| constant_op(V::default())).collect()));
+  let built: HashSet<STag> = probe.objs.iter().cloned().collect();
+  let mut frontier: Vec<STag> = outs.iter().map(|y| y.tag.stable).filter(|s| !built.contains(s)).collect();
+  for &s in probe.objs.iter() {
+    if let Some(thunk) = _lookup_obj(s).as_ref().and_then(|obj| obj._as_thunk()) {
+      frontier.extend(thunk._freevars().into_iter().filter(|v| !built.contains(v)));
+    }
+  }
+  let mut seen = HashSet::new();
+  frontier.into_iter()
+    .filter(|&s| seen.insert(s))
+    .filter(|&s| _lookup_obj(s).map(|obj| obj._as_any().is::<Thunk<V>>()).unwrap_or(false))
+    .map(|s| ThunkRef::_from_tag(Tag::new(s)))
+    .collect()
+}
+
+/// Replays the iterations of `eval` in reverse. Returns the cotangents of
+/// the placeholders of each iteration (outermost vector indexed like the
+/// iterations), and those of `captured`, summed over the iterations.
+/// `seeds(k, douts)` returns the seeds of the outputs of iteration `k`,
+/// given the cotangents `douts` flowing back from iteration `k + 1`. The
+/// thunks which the replay builds are added to `replayed`.
+///
+/// Each reverse pass stops at the thunks of `eval`. A cotangent reaching any
+/// other thunk must be passed on to the enclosing reverse pass, so it fails
+/// with `EvalError::NotDifferentiable` unless that thunk is in `captured`.
+fn _replay_reverse<V, F>(eval: &LoopEval<V>, captured: &[ThunkRef<V>], replayed: &mut _HeapRetain, mut seeds: F) -> Result<(Vec<Vec<Option<ThunkRef<V>>>>, Vec<Option<ThunkRef<V>>>), EvalError>
+where V: Ring, F: FnMut(usize, Option<&[Option<ThunkRef<V>>]>) -> Vec<Option<ThunkRef<V>>> {
+  let built: HashSet<STag> = eval.built.objs.iter().cloned().collect();
+  let passed: HashSet<STag> = captured.iter().map(|c| c.tag.stable).collect();
+  let mut dins: Vec<Vec<Option<ThunkRef<V>>>> = Vec::with_capacity(eval.iters.len());
+  let mut dcaptured: Vec<Option<ThunkRef<V>>> = captured.iter().map(|_| None).collect();
+  for k in (0 .. eval.iters.len()).rev() {
+    let (ref ins, ref outs) = eval.iters[k];
+    let ds = seeds(k, dins.last().map(|d| &d[..]));
+    let ys: Vec<_> = outs.iter().cloned().zip(ds)
+      .filter_map(|(y, dy)| dy.map(|dy| (y, dy)))
+      .collect();
+    if ys.is_empty() {
+      dins.push(ins.iter().map(|_| None).collect());
+      continue;
+    }
+    let roots: Vec<_> = ys.iter().map(|(y, _)| y.tag.stable).collect();
+    let order: Vec<_> = _topo_sort(&roots)?.into_iter().filter(|s| built.contains(s)).collect();
+    let sink = _build_retained(replayed, || {
+      let mut sink = Sink::new(pass());
+      for (y, dy) in ys.iter() {
+        sink.put_adj(y, dy.clone());
+      }
+      _reverse(&order, &mut sink).map(|_| sink)
+    })?;
+    if let Some(&s) = sink.adjs.keys().find(|s| !built.contains(s) && !passed.contains(s)) {
+      return Err(EvalError::NotDifferentiable(s));
+    }
+    dins.push(ins.iter().map(|p| sink.get_adj(p)).collect());
+    for (dc, c) in dcaptured.iter_mut().zip(captured.iter()) {
+      if let Some(d) = sink.get_adj(c) {
+        *dc = Some(match dc.take() {
+          None => d,
+          Some(prev) => _build_retained(replayed, || add_op(prev, d)),
+        });
+      }
+    }
+  }
+  dins.reverse();
+  Ok((dins, dcaptured))
+}
+
+/// Forces each of `grads` (a zero if `None`) and shares its data with the
+/// matching output of a loop's cotangent op.
+fn _put_grads<V: Ring>(ctx: &EntryCtx, txn: Txn, dxs: &[LData<V>], grads: Vec<Option<ThunkRef<V>>>, replayed: &mut _HeapRetain) -> bool {
+  for (dx, g) in dxs.iter().zip(grads) {
+    let g = g.unwrap_or_else(|| _build_retained(replayed, || constant_op(V::zero())));
+    if ctx.force(&g).is_err() {
+      return false;
+    }
+    if dx.redirect(&g._get_obj(), txn).is_err() {
+      return false;
+    }
+  }
+  true
+}
+
+/// Builds the cotangent op of a loop with output `y`: `entry(txn, replayed)`
+/// returns the cotangents of its `n` outputs, and the thunks in `replayed`
+/// are kept until its next evaluation. The cotangents are not
+/// differentiable again.
+fn _build_loop_grads<V, F>(name: &'static str, y: ThunkRef<V>, dys: Vec<ThunkRef<V>>, n: usize, entry: F) -> Vec<ThunkRef<V>>
+where V: Ring, F: Fn(&EntryCtx, &mut _HeapRetain) -> Result<Vec<Option<ThunkRef<V>>>, EvalError> + 'static {
+  let replay = Rc::new(RefCell::new(_HeapRetain::new()));
+  let entry: Arc<Fn(Txn, &[LData<V>]) -> bool> = {
+    let (y, dys) = (y.clone(), dys.clone());
+    Arc::new(move |txn, dxs| {
+      let ctx = EntryCtx::current().unwrap();
+      // Forcing `y` evaluates the loop in `txn` if it has not been already.
+      if ctx.force(&y).is_err() || !dys.iter().all(|dy| ctx.force(dy).is_ok()) {
+        return false;
+      }
+      let mut replayed = _HeapRetain::new();
+      let grads = match (entry)(&ctx, &mut replayed) {
+        Err(e) => {
+          _record_entry_error(&e);
+          return false;
+        }
+        Ok(grads) => grads,
+      };
+      if !_put_grads(&ctx, txn, dxs, grads, &mut replayed) {
+        return false;
+      }
+      let prev = ::std::mem::replace(&mut *replay.borrow_mut(), replayed);
+      drop(prev);
+      true
+    })
+  };
+  let mut freevars = vec![y];
+  freevars.extend(dys);
+  MultiOp::build_thunks(name, freevars, n, DataCode{
+    alloc:    Some(Arc::new(|_txn| V::default())),
+    nbytes:   None,
+  }, entry, Some(Arc::new(|_pass, ys: &[ThunkRef<V>], _dys: &[Option<ThunkRef<V>>], sink: &mut Sink| {
+    sink.fail(EvalError::NotDifferentiable(ys[0]._stable()));
+  }))).into_iter().map(|thunk| thunk._put_obj()).collect()
+}
+
+/// Builds a boolean thunk which tests the value of `x` with `f`, e.g. the
+/// condition of a `switch_op` or `while_op`.
+pub fn predicate_op<V, F>(name: &'static str, x: ThunkRef<V>, f: F) -> ThunkRef<bool>
+where V: 'static, F: Fn(&V) -> bool + 'static {
+  let code = ThunkCode{
+    name:     name,
+    entry:    {
+      let x = x._get_obj();
+      Some(Arc::new(move |txn, y| {
+        let x = match x.try_get(txn) {
+          Err(_) => return false,
+          Ok(x) => x,
+        };
+        *y.get_mut(txn) = (f)(&*x);
+        true
+      }))
+    },
+    adjoint:  None,
+    batch:    None,
+  };
+  let thunk = Thunk::new(DataCode{
+    alloc:    Some(Arc::new(|_txn| false)),
+    nbytes:   None,
+  }, vec![x._into_tag()], code);
+  thunk._put_obj()
+}
+
+pub struct WhileOp<V> {
+  _mrk: PhantomData<V>,
+}
+
+impl<V: Ring> WhileOp<V> {
+  /// Builds a loop which, starting from the value of `init`, applies
+  /// `body_fn` while `cond_fn` holds; see `while_op`.
+  pub fn build_thunk<C, B>(cond_fn: C, body_fn: B, init: ThunkRef<V>) -> Thunk<V>
+  where C: Fn(ThunkRef<V>) -> ThunkRef<bool> + 'static, B: Fn(ThunkRef<V>) -> ThunkRef<V> + 'static {
+    let captured = _probe_captured(1, |ps| vec![(body_fn)(ps[0].clone())]);
+    let trace = LoopTrace::new();
+    let code = ThunkCode{
+      name:     "while",
+      entry:    {
+        let init = init._get_obj();
+        let trace = trace.clone();
+        Some(Arc::new(move |txn, y| {
+          let ctx = EntryCtx::current().unwrap();
+          let mut built = _HeapRetain::new();
+          let mut iters = Vec::new();
+          let mut state = match init.try_get(txn) {
+            Err(_) => return false,
+            Ok(init) => _build_retained(&mut built, || ctx.build(|| constant_op(init.clone()))),
+          };
+          loop {
+            let c = _build_retained(&mut built, || ctx.build(|| (cond_fn)(state.clone())));
+            match ctx.get(&c) {
+              Err(_) => return false,
+              Ok(false) => break,
+              Ok(true) => {}
+            }
+            let next = _build_retained(&mut built, || ctx.build(|| (body_fn)(state.clone())));
+            let value = match ctx.get(&next) {
+              Err(_) => return false,
+              Ok(value) => value,
+            };
+            iters.push((vec![state], vec![next]));
+            state = _build_retained(&mut built, || ctx.build(|| constant_op(value)));
+          }
+          if y.redirect(&state._get_obj(), txn).is_err() {
+            return false;
+          }
+          _record_eval(&trace, txn, LoopEval{iters, built});
+          true
+        }))
+      },
+      adjoint:  {
+        let (init, captured) = (init.clone(), captured.clone());
+        Some(Arc::new(move |_pass, y, sink| {
+          let dy = match sink.get_adj(&y) {
+            None => return,
+            Some(dy) => dy,
+          };
+          let dxs = WhileOp::_build_grads(y, dy, captured.clone(), trace.clone());
+          sink.put_adj(&init, dxs[0].clone());
+          for (c, dc) in captured.iter().zip(dxs[1 ..].iter()) {
+            sink.put_adj(c, dc.clone());
+          }
+        }))
+      },
+      batch:    None,
+    };
+    Thunk::new(DataCode{
+      alloc:    Some(Arc::new(|_txn| V::default())),
+      nbytes:   None,
+    }, vec![init._into_tag()], code)
+      .with_lazy(captured.into_iter().map(|c| c._into_tag()).collect())
+  }
+
+  /// The cotangents of the loop's `init` and of the thunks captured by its
+  /// body, computed when forced by replaying the loop's iterations in `txn`
+  /// in reverse, starting from `dy`.
+  fn _build_grads(y: ThunkRef<V>, dy: ThunkRef<V>, captured: Vec<ThunkRef<V>>, trace: Rc<RefCell<LoopTrace<V>>>) -> Vec<ThunkRef<V>> {
+    let n = captured.len() + 1;
+    let dy_ = dy.clone();
+    _build_loop_grads("while_grad", y, vec![dy_], n, move |ctx, replayed| {
+      let trace = trace.borrow();
+      let eval = match trace.evals.get(&ctx.txn()) {
+        None => return Err(EvalError::MissingData(ctx.thunk())),
+        Some(eval) => eval,
+      };
+      let (dins, dcaptured) = _replay_reverse(eval, &captured, replayed, |_k, dnext| match dnext {
+        None => vec![Some(dy.clone())],
+        Some(dnext) => dnext.to_vec(),
+      })?;
+      let mut grads = vec![match dins.first() {
+        None => Some(dy.clone()),
+        Some(d) => d[0].clone(),
+      }];
+      grads.extend(dcaptured);
+      Ok(grads)
+    })
+  }
+}
+
+/// Repeatedly applies `body_fn` to the state, starting from `init`, while
+/// `cond_fn` of the state is true, and returns the final state.
+///
+/// Each evaluation builds and forces a small subgraph per iteration, over a
+/// placeholder holding that iteration's state, instead of unrolling the loop
+/// into the enclosing graph; the subgraphs of an evaluation are removed from
+/// the heap when the loop is next evaluated. Cotangents replay the
+/// iterations of the evaluation in their own `Txn` in reverse, and can be
+/// taken in any number of reverse passes, but not differentiated again.
+///
+/// `body_fn` is also called once when the loop is built, over a fresh
+/// placeholder, to find the thunks which it captures from the enclosing
+/// graph; their cotangents are passed on to the enclosing reverse pass. A
+/// cotangent reaching a thunk which only some iterations capture fails with
+/// `EvalError::NotDifferentiable`.
+pub fn while_op<V, C, B>(cond_fn: C, body_fn: B, init: ThunkRef<V>) -> ThunkRef<V>
+where V: Ring, C: Fn(ThunkRef<V>) -> ThunkRef<bool> + 'static, B: Fn(ThunkRef<V>) -> ThunkRef<V> + 'static {
+  let thunk = WhileOp::build_thunk(cond_fn, body_fn, init);
+  let thunkref = thunk._put_obj();
+  thunkref
+}
+
+pub struct ScanOp<V> {
+  _mrk: PhantomData<V>,
+}
+
+impl<V: Ring> ScanOp<V> {
+  /// Builds the outputs of a scan: the final carry, then one output per
+  /// element of `xs`; see `scan_op`.
+  pub fn build_thunks<F>(step_fn: F, init: ThunkRef<V>, xs: Vec<ThunkRef<V>>) -> Vec<Thunk<V>>
+  where F: Fn(ThunkRef<V>, ThunkRef<V>) -> (ThunkRef<V>, ThunkRef<V>) + 'static {
+    let n = xs.len();
+    let captured = _probe_captured(2, |ps| {
+      let (next, y) = (step_fn)(ps[0].clone(), ps[1].clone());
+      vec![next, y]
+    });
+    let trace = LoopTrace::new();
+    let entry: Arc<Fn(Txn, &[LData<V>]) -> bool> = {
+      let init = init._get_obj();
+      let xs: Vec<_> = xs.iter().map(|x| x._get_obj()).collect();
+      let trace = trace.clone();
+      Arc::new(move |txn, ys| {
+        let ctx = EntryCtx::current().unwrap();
+        let mut built = _HeapRetain::new();
+        let mut iters = Vec::with_capacity(xs.len());
+        let mut carry = match init.try_get(txn) {
+          Err(_) => return false,
+          Ok(init) => _build_retained(&mut built, || ctx.build(|| constant_op(init.clone()))),
+        };
+        for (k, x) in xs.iter().enumerate() {
+          let x = match x.try_get(txn) {
+            Err(_) => return false,
+            Ok(x) => _build_retained(&mut built, || ctx.build(|| constant_op(x.clone()))),
+          };
+          let (next, y) = _build_retained(&mut built, || ctx.build(|| (step_fn)(carry.clone(), x.clone())));
+          let value = match ctx.get(&next) {
+            Err(_) => return false,
+            Ok(value) => value,
+          };
+          if ctx.force(&y).is_err() {
+            return false;
+          }
+          if ys[k + 1].redirect(&y._get_obj(), txn).is_err() {
+            return false;
+          }
+          iters.push((vec![carry, x], vec![next, y]));
+          carry = _build_retained(&mut built, || ctx.build(|| constant_op(value)));
+        }
+        if ys[0].redirect(&carry._get_obj(), txn).is_err() {
+          return false;
+        }
+        _record_eval(&trace, txn, LoopEval{iters, built});
+        true
+      })
+    };
+    let adjoint: MultiAdjoint<V> = {
+      let (init, xs, captured) = (init.clone(), xs.clone(), captured.clone());
+      Arc::new(move |_pass, ys, dys, sink| {
+        let dxs = ScanOp::_build_grads(ys[0].clone(), dys, captured.clone(), trace.clone());
+        sink.put_adj(&init, dxs[0].clone());
+        for (x, dx) in xs.iter().zip(dxs[1 .. n + 1].iter()) {
+          sink.put_adj(x, dx.clone());
+        }
+        for (c, dc) in captured.iter().zip(dxs[n + 1 ..].iter()) {
+          sink.put_adj(c, dc.clone());
+        }
+      })
+    };
+    let mut inputs = vec![init];
+    inputs.extend(xs);
+    let mut thunks = MultiOp::build_thunks("scan", inputs, n + 1, DataCode{
+      alloc:    Some(Arc::new(|_txn| V::default())),
+      nbytes:   None,
+    }, entry, Some(adjoint));
+    let first = thunks.remove(0).with_lazy(captured.into_iter().map(|c| c._into_tag()).collect());
+    thunks.insert(0, first);
+    thunks
+  }
+
+  /// The cotangents of the scan's `init`, `xs` and of the thunks captured by
+  /// its step, computed when forced by replaying the scan's iterations in
+  /// reverse.
+  fn _build_grads(y: ThunkRef<V>, dys: &[Option<ThunkRef<V>>], captured: Vec<ThunkRef<V>>, trace: Rc<RefCell<LoopTrace<V>>>) -> Vec<ThunkRef<V>> {
+    let n = dys.len() + captured.len();
+    let present: Vec<_> = dys.iter().filter_map(|dy| dy.clone()).collect();
+    let dys = dys.to_vec();
+    _build_loop_grads("scan_grad", y, present, n, move |ctx, replayed| {
+      let trace = trace.borrow();
+      let eval = match trace.evals.get(&ctx.txn()) {
+        None => return Err(EvalError::MissingData(ctx.thunk())),
+        Some(eval) => eval,
+      };
+      let (dins, dcaptured) = _replay_reverse(eval, &captured, replayed, |k, dnext| {
+        let dcarry = match dnext {
+          None => dys[0].clone(),
+          Some(dnext) => dnext[0].clone(),
+        };
+        vec![dcarry, dys[k + 1].clone()]
+      })?;
+      let mut grads = Vec::with_capacity(n);
+      grads.push(match dins.first() {
+        None => dys[0].clone(),
+        Some(d) => d[0].clone(),
+      });
+      grads.extend(dins.iter().map(|d| d[1].clone()));
+      grads.extend(dcaptured);
+      Ok(grads)
+    })
+  }
+}
+
+/// Threads a carry through `xs`, starting from `init`: each step maps the
+/// carry and an element to the next carry and an output. Returns the final
+/// carry and the outputs, which share one evaluation. Like `while_op`, each
+/// step is a subgraph built and forced per evaluation, the cotangents of
+/// `init`, `xs` and of the thunks which `step_fn` captures replay the steps
+/// in reverse, and they cannot be differentiated again.
+pub fn scan_op<V, F>(step_fn: F, init: ThunkRef<V>, xs: Vec<ThunkRef<V>>) -> (ThunkRef<V>, Vec<ThunkRef<V>>)
+where V: Ring, F: Fn(ThunkRef<V>, ThunkRef<V>) -> (ThunkRef<V>, ThunkRef<V>) + 'static {
+  let mut outputs: Vec<_> = ScanOp::build_thunks(step_fn, init, xs).into_iter().map(|thunk| thunk._put_obj()).collect();
+  let carry = outputs.remove(0);
+  (carry, outputs)
+}
+
+pub struct MapOp<V> {
+  _mrk: PhantomData<V>,
+}
+
+impl<V: Clone + Default + Debug + 'static> MapOp<V> {
+  pub fn build_thunk<F>(name: &'static str, xs: Vec<ThunkRef<V>>, f: F, adjoint: Option<Arc<Fn(Pass, ThunkRef<V>, &mut Sink)>>, nbytes: Option<Arc<Fn(&V) -> usize>>) -> Thunk<V>
+  where F: Fn(&[&V]) -> V + 'static {
+    let code = ThunkCode{
+      name:     name,
+      entry:    {
+        let xs: Vec<_> = xs.iter().map(|x| x._get_obj()).collect();
+        Some(Arc::new(move |txn, y| {
+          let xs = match xs.iter().map(|x| x.try_get(txn)).collect::<Result<Vec<_>, _>>() {
+            Err(_) => return false,
+            Ok(xs) => xs,
+          };
+          let xs: Vec<&V> = xs.iter().map(|x| &**x).collect();
+          let mut y = y.get_mut(txn);
+          *y = (f)(&xs);
+          true
+        }))
+      },
+      adjoint:  adjoint,
+      batch:    None,
+    };
+    Thunk::new(DataCode{
+      alloc:    Some(Arc::new(|_txn| V::default())),
+      nbytes:   nbytes,
+    }, xs.into_iter().map(|x| x._into_tag()).collect(), code)
+  }
+}
+
+/// Builds a thunk which applies `f` to the values of `xs`. This is the
+/// simplest way to define a new op outside of this module; `adjoint` is
+/// called with the op's own thunk during the reverse pass.
+pub fn map_op<V, F>(name: &'static str, xs: Vec<ThunkRef<V>>, f: F, adjoint: Option<Arc<Fn(Pass, ThunkRef<V>, &mut Sink)>>) -> ThunkRef<V>
+where V: Clone + Default + Debug + 'static, F: Fn(&[&V]) -> V + 'static {
+  let thunk = MapOp::build_thunk(name, xs, f, adjoint, None);
+  let thunkref = thunk._put_obj();
+  thunkref
+}
+
+/// Adjoint of a multi-output op: receives the op's outputs and the
+/// cotangent of each output, `None` for outputs without one.
+pub type MultiAdjoint<V> = Arc<Fn(Pass, &[ThunkRef<V>], &[Option<ThunkRef<V>>], &mut Sink)>;
+
+pub struct MultiOp<V> {
+  _mrk: PhantomData<V>,
+}
+
+impl<V: 'static> MultiOp<V> {
+  /// Builds the `n` output thunks of an op whose `entry` fills the data of
+  /// every output in a single evaluation.
+  ///
+  /// The first output depends on `xs` and runs `entry`; each other output
+  /// depends only on the first, and forcing it forces the first. All outputs
+  /// share the first output's adjoint, which runs once in a reverse pass.
+  pub fn build_thunks(name: &'static str, xs: Vec<ThunkRef<V>>, n: usize, datacode: DataCode<V>, entry: Arc<Fn(Txn, &[LData<V>]) -> bool>, adjoint: Option<MultiAdjoint<V>>) -> Vec<Thunk<V>> {
+    assert!(n >= 1, "MultiOp: expected at least one output");
+    let datas: Vec<STag> = (0 .. n).map(|_| Data::new(datacode.clone())._put_obj()).collect();
+    let stables: Vec<STag> = (0 .. n).map(|_| STag::new()).collect();
+    let first = stables[0];
+    let code = ThunkCode{
+      name:     name,
+      entry:    {
+        let datas = datas.clone();
+        Some(Arc::new(move |txn, _y| {
+          let ys: Vec<_> = datas.iter().map(|&d| LDataRef::<V>::_from_stag(d)._get_obj()).collect();
+          (entry)(txn, &ys)
+        }))
+      },
+      adjoint:  adjoint.map(|adjoint| {
+        let stables = stables.clone();
+        let adjoint: Arc<Fn(Pass, ThunkRef<V>, &mut Sink)> = Arc::new(move |pass, _y, sink| {
+          let ys: Vec<ThunkRef<V>> = stables.iter().map(|&s| ThunkRef::_from_tag(Tag::new(s))).collect();
+          let dys: Vec<_> = ys.iter().map(|y| sink.get_adj(y)).collect();
+          (adjoint)(pass, &ys, &dys, sink)
+        });
+        adjoint
+      }),
+      batch:    None,
+    };
+    let mut thunks = Vec::with_capacity(n);
+    thunks.push(Thunk{
+      stable:   first,
+      data:     Some(datas[0]),
+      state:    Rc::new(Cell::new(ThunkState::Empty)),
+      freevars: xs.into_iter().map(|x| x._into_tag()).collect(),
+      outputs:  stables.clone(),
+      lazy:     Vec::new(),
+      code:     code,
+      plc:      None,
+    });
+    for i in 1 .. n {
+      thunks.push(Thunk{
+        stable:   stables[i],
+        data:     Some(datas[i]),
+        state:    Rc::new(Cell::new(ThunkState::Empty)),
+        freevars: vec![Tag::new(first)],
+        outputs:  stables.clone(),
+        lazy:     Vec::new(),
+        code:     ThunkCode{
+          name:     name,
+          // The first output's entry already filled this output's data.
+          entry:    Some(Arc::new(move |txn, _y| {
+            ThunkRef::<V>::_from_tag(Tag::new(first))._get_obj().try_get(txn).is_ok()
+          })),
+          adjoint:  None,
+          batch:    None,
+        },
+        plc:      None,
+      });
+    }
+    thunks
+  }
+}
+
+/// Builds an op with several outputs, computed together by `f` from the
+/// values of `xs`; returns one thunk per output.
+pub fn multi_op<V, F>(name: &'static str, xs: Vec<ThunkRef<V>>, n: usize, f: F, adjoint: Option<MultiAdjoint<V>>) -> Vec<ThunkRef<V>>
+where V: Clone + Default + 'static, F: Fn(&[&V]) -> Vec<V> + 'static {
+  let entry: Arc<Fn(Txn, &[LData<V>]) -> bool> = {
+    let xs: Vec<_> = xs.iter().map(|x| x._get_obj()).collect();
+    Arc::new(move |txn, ys| {
+      let xs = match xs.iter().map(|x| x.try_get(txn)).collect::<Result<Vec<_>, _>>() {
+        Err(_) => return false,
+        Ok(xs) => xs,
+      };
+      let xs: Vec<&V> = xs.iter().map(|x| &**x).collect();
+      let values = (f)(&xs);
+      assert_eq!(ys.len(), values.len(), "multi_op: wrong number of outputs");
+      for (y, value) in ys.iter().zip(values.into_iter()) {
+        *y.get_mut(txn) = value;
+      }
+      true
+    })
+  };
+  MultiOp::build_thunks(name, xs, n, DataCode{
+    alloc:    Some(Arc::new(|_txn| V::default())),
+    nbytes:   None,
+  }, entry, adjoint).into_iter().map(|thunk| thunk._put_obj()).collect()
+}
+
+pub struct IdentityOp<V> {
+  _mrk: PhantomData<V>,
+}
+
+impl<V: Clone + Default + 'static> IdentityOp<V> {
+  /// Builds a thunk whose value is that of `x`; its entry redirects to the
+  /// data of `x` rather than copying it.
+  pub fn build_thunk(name: &'static str, x: ThunkRef<V>, adjoint: Option<Arc<Fn(Pass, ThunkRef<V>, &mut Sink)>>) -> Thunk<V> {
+    let code = ThunkCode{
+      name:     name,
+      entry:    {
+        let x = x._get_obj();
+        Some(Arc::new(move |txn, y| {
+          y.redirect(&x, txn).is_ok()
+        }))
+      },
+      adjoint:  adjoint,
+      batch:    None,
+    };
+    Thunk::new(DataCode{
+      alloc:    Some(Arc::new(|_txn| V::default())),
+      nbytes:   None,
+    }, vec![x._into_tag()], code)
+  }
+}
+
+/// The identity; its value shares the data of `x`.
+pub fn identity_op<V: Ring>(x: ThunkRef<V>) -> ThunkRef<V> {
+  let adjoint: Arc<Fn(Pass, ThunkRef<V>, &mut Sink)> = {
+    let x = x.clone();
+    Arc::new(move |_pass, y, sink| {
+      if let Some(dy) = sink.get_adj(&y) {
+        sink.put_adj(&x, dy);
+      }
+    })
+  };
+  let batch = _map_batch_rule(vec![x.clone()], |xs| identity_op(xs[0].clone()));
+  let thunk = IdentityOp::build_thunk("identity", x, Some(adjoint)).with_batch(batch);
+  let thunkref = thunk._put_obj();
+  thunkref
+}
+
+pub struct StopGradientOp<V> {
+  _mrk: PhantomData<V>,
+}
+
+impl<V: Clone + Default + Debug + 'static> StopGradientOp<V> {
+  pub fn build_thunk(x: ThunkRef<V>) -> Thunk<V> {
+    let batch = _map_batch_rule(vec![x.clone()], |xs| stop_gradient(xs[0].clone()));
+    IdentityOp::build_thunk("stop_gradient", x, None)
+      .with_batch(batch)
+  }
+}
+
+/// The identity, except that no cotangent flows back through it to `x`.
+pub fn stop_gradient<V: Clone + Default + Debug + 'static>(x: ThunkRef<V>) -> ThunkRef<V> {
+  let thunk = StopGradientOp::build_thunk(x);
+  let thunkref = thunk._put_obj();
+  thunkref
+}
+
+pub struct CustomGradOp<V> {
+  _mrk: PhantomData<V>,
+}
+
+impl<V: Clone + Default + 'static> CustomGradOp<V> {
+  /// Builds a thunk whose value is that of `y` and whose adjoint is
+  /// `adjoint`. It redirects to the data of `y` rather than copying the
+  /// entry of `y`, which may fill other data, e.g. a `MultiOp` output.
+  pub fn build_thunk(y: ThunkRef<V>, adjoint: Option<Arc<Fn(Pass, ThunkRef<V>, &mut Sink)>>) -> Thunk<V> {
+    IdentityOp::build_thunk("custom_grad", y, adjoint)
+  }
+}
+
+/// Builds `forward_fn(x)` with its adjoint replaced by `adjoint_fn(pass, x,
+/// y, dy, sink)`, where `y` is the result and `dy` its cotangent. The forward
+/// entries are unchanged; cotangents reach `x` only through `adjoint_fn`, not
+/// through the subgraph built by `forward_fn`. Useful for e.g.
+/// straight-through estimators or gradient clipping.
+pub fn custom_grad<V, W, F, G>(x: ThunkRef<V>, forward_fn: F, adjoint_fn: G) -> ThunkRef<W>
+where V: 'static, W: Clone + Default + 'static,
+      F: FnOnce(ThunkRef<V>) -> ThunkRef<W>,
+      G: Fn(Pass, ThunkRef<V>, ThunkRef<W>, ThunkRef<W>, &mut Sink) + 'static {
+  let y = forward_fn(x.clone());
+  let adjoint: Arc<Fn(Pass, ThunkRef<W>, &mut Sink)> = Arc::new(move |pass, y, sink| {
+    let dy = match sink.get_adj(&y) {
+      None => return,
+      Some(dy) => dy,
+    };
+    (adjoint_fn)(pass, x.clone(), y, dy, sink);
+  });
+  let thunk = CustomGradOp::build_thunk(y, Some(adjoint));
+  let thunkref = thunk._put_obj();
+  thunkref
+}
+
+/// A forced cell of a `LazyStream`: its head value and the rest of the
+/// stream, which is not forced yet.
+pub struct StreamCell<V> {
+  pub head: V,
+  pub tail: LazyStream<V>,
+}
+
+impl<V: Clone> Clone for StreamCell<V> {
+  fn clone(&self) -> StreamCell<V> {
+    StreamCell{
+      head: self.head.clone(),
+      tail: self.tail.clone(),
+    }
+  }
+}
+
+/// A lazy, possibly infinite stream: a chain of thunks, each of which is
+/// either empty (the end of the stream) or a `StreamCell`. The thunk of each
+/// tail is only built when its cell is forced, so streams are unbounded.
+///
+/// The thunk and data of a cell are removed from the heap when the last
+/// `LazyStream` handle to the cell drops, so a consumed prefix is reclaimed
+/// unless something still holds its head.
+pub struct LazyStream<V> {
+  cell:   ThunkRef<Option<StreamCell<V>>>,
+  retain: Rc<_HeapRetain>,
+}
+
+impl<V> Clone for LazyStream<V> {
+  fn clone(&self) -> LazyStream<V> {
+    LazyStream{
+      cell:   self.cell.clone(),
+      retain: self.retain.clone(),
+    }
+  }
+}
+
+impl<V: Clone + 'static> LazyStream<V> {
+  fn _build<F>(name: &'static str, freevars: Vec<Tag>, f: F) -> LazyStream<V>
+  where F: Fn(Txn) -> Result<Option<StreamCell<V>>, EvalError> + 'static {
+    let code = ThunkCode{
+      name:     name,
+      entry:    Some(Arc::new(move |txn, y| {
+        match (f)(txn) {
+          Err(_) => false,
+          Ok(cell) => {
+            *y.get_mut(txn) = cell;
+            true
+          }
+        }
+      })),
+      adjoint:  None,
+      batch:    None,
+    };
+    let thunk = Thunk::new(DataCode{
+      alloc:    Some(Arc::new(|_txn| None)),
+      nbytes:   None,
+    }, freevars, code);
+    let mut objs = vec![thunk.stable];
+    objs.extend(thunk.data);
+    LazyStream{
+      cell:   thunk._put_obj(),
+      retain: Rc::new(_HeapRetain{objs: objs}),
+    }
+  }
+
+  /// The thunk of the first cell; it is only valid while the stream is.
+  pub fn as_thunk(&self) -> &ThunkRef<Option<StreamCell<V>>> {
+    &self.cell
+  }
+
+  pub fn empty() -> LazyStream<V> {
+    LazyStream::_build("stream_empty", Vec::new(), |_txn| Ok(None))
+  }
+
+  pub fn cons(head: V, tail: LazyStream<V>) -> LazyStream<V> {
+    LazyStream::_build("stream_cons", Vec::new(), move |_txn| Ok(Some(StreamCell{
+      head: head.clone(),
+      tail: tail.clone(),
+    })))
+  }
+
+  /// The stream of values produced by repeatedly applying `step` to a seed,
+  /// ending when `step` returns `None`.
+  pub fn unfold<S, F>(seed: S, step: F) -> LazyStream<V>
+  where S: Clone + 'static, F: Fn(&S) -> Option<(V, S)> + 'static {
+    LazyStream::_unfold(seed, Rc::new(step))
+  }
+
+  fn _unfold<S: Clone + 'static>(seed: S, step: Rc<Fn(&S) -> Option<(V, S)>>) -> LazyStream<V> {
+    LazyStream::_build("stream_unfold", Vec::new(), move |_txn| Ok((step)(&seed).map(|(head, next)| StreamCell{
+      head: head,
+      tail: LazyStream::_unfold(next, step.clone()),
+    })))
+  }
+
+  /// The infinite stream `init, f(init), f(f(init)), ...`.
+  pub fn iterate<F: Fn(&V) -> V + 'static>(init: V, f: F) -> LazyStream<V> {
+    LazyStream::unfold(init, move |x| Some((x.clone(), f(x))))
+  }
+
+  /// Forces the first cell, returning `None` at the end of the stream.
+  pub fn force(&self, txn: Txn) -> Result<Option<StreamCell<V>>, EvalError> {
+    let cell = self.cell._get_obj();
+    let cell = cell.try_get(txn)?;
+    Ok(cell.clone())
+  }
+
+  pub fn map<W, F>(&self, f: F) -> LazyStream<W>
+  where W: Clone + 'static, F: Fn(&V) -> W + 'static {
+    self._map(Rc::new(f))
+  }
+
+  fn _map<W: Clone + 'static>(&self, f: Rc<Fn(&V) -> W>) -> LazyStream<W> {
+    let src = self.clone();
+    LazyStream::_build("stream_map", vec![self.cell.tag.clone_ref()], move |txn| Ok(src.force(txn)?.map(|cell| StreamCell{
+      head: (f)(&cell.head),
+      tail: cell.tail._map(f.clone()),
+    })))
+  }
+
+  /// Pairs up the elements of two streams, ending with the shorter one.
+  pub fn zip<W: Clone + 'static>(&self, other: &LazyStream<W>) -> LazyStream<(V, W)> {
+    let (xs, ys) = (self.clone(), other.clone());
+    LazyStream::_build("stream_zip", vec![self.cell.tag.clone_ref(), other.cell.tag.clone_ref()], move |txn| {
+      let x = match xs.force(txn)? {
+        None => return Ok(None),
+        Some(x) => x,
+      };
+      Ok(ys.force(txn)?.map(|y| StreamCell{
+        head: (x.head.clone(), y.head),
+        tail: x.tail.zip(&y.tail),
+      }))
+    })
+  }
+
+  /// The first `n` elements.
+  pub fn take(&self, n: usize) -> LazyStream<V> {
+    if n == 0 {
+      return LazyStream::empty();
+    }
+    let src = self.clone();
+    LazyStream::_build("stream_take", vec![self.cell.tag.clone_ref()], move |txn| Ok(src.force(txn)?.map(|cell| StreamCell{
+      head: cell.head,
+      tail: cell.tail.take(n - 1),
+    })))
+  }
+
+  /// The running accumulation `f(init, x0), f(f(init, x0), x1), ...`.
+  pub fn scan<S, F>(&self, init: S, f: F) -> LazyStream<S>
+  where S: Clone + 'static, F: Fn(&S, &V) -> S + 'static {
+    self._scan(init, Rc::new(f))
+  }
+
+  fn _scan<S: Clone + 'static>(&self, acc: S, f: Rc<Fn(&S, &V) -> S>) -> LazyStream<S> {
+    let src = self.clone();
+    LazyStream::_build("stream_scan", vec![self.cell.tag.clone_ref()], move |txn| Ok(src.force(txn)?.map(|cell| {
+      let next = (f)(&acc, &cell.head);
+      StreamCell{
+        head: next.clone(),
+        tail: cell.tail._scan(next, f.clone()),
+      }
+    })))
+  }
+
+  /// Iterates over the elements, forcing each cell in `txn` when it is
+  /// reached. The iterator only holds the current cell, so the consumed
+  /// prefix is reclaimed unless another handle holds on to it.
+  pub fn iter(&self, txn: Txn) -> StreamIter<V> {
+    StreamIter{
+      next: Some(self.clone()),
+      txn:  txn,
+    }
+  }
+}
+
+pub struct StreamIter<V> {
+  next: Option<LazyStream<V>>,
+  txn:  Txn,
+}
+
+impl<V: Clone + 'static> Iterator for StreamIter<V> {
+  type Item = Result<V, EvalError>;
+
+  fn next(&mut self) -> Option<Result<V, EvalError>> {
+    let stream = match self.next.take() {
+      None => return None,
+      Some(stream) => stream,
+    };
+    match stream.force(self.txn) {
+      Err(e) => Some(Err(e)),
+      Ok(None) => None,
+      Ok(Some(cell)) => {
+        self.next = Some(cell.tail);
+        Some(Ok(cell.head))
+      }
+    }
+  }
+}
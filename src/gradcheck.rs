@@ -0,0 +1,130 @@
+use experimental::rt1::*;
+use nn::{TensorRef};
+use num::{Float};
+use tensor::*;
+
+/// One element of one input, comparing the reverse-mode gradient against
+/// the central finite difference.
+#[derive(Clone, Debug)]
+pub struct GradCheckEntry {
+  pub input:    usize,
+  pub index:    usize,
+  pub analytic: f64,
+  pub numeric:  f64,
+  pub abs_err:  f64,
+  pub rel_err:  f64,
+  pub ok:       bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct GradCheckReport {
+  pub entries:  Vec<GradCheckEntry>,
+}
+
+impl GradCheckReport {
+  pub fn passed(&self) -> bool {
+    self.entries.iter().all(|e| e.ok)
+  }
+
+  pub fn failures(&self) -> Vec<&GradCheckEntry> {
+    self.entries.iter().filter(|e| !e.ok).collect()
+  }
+
+  pub fn max_abs_err(&self) -> f64 {
+    self.entries.iter().fold(0.0, |acc, e| acc.max(e.abs_err))
+  }
+
+  pub fn max_rel_err(&self) -> f64 {
+    self.entries.iter().fold(0.0, |acc, e| acc.max(e.rel_err))
+  }
+
+  pub fn print(&self) {
+    println!("{:>5} {:>7} {:>14} {:>14} {:>10} {:>10}", "input", "index", "analytic", "numeric", "abs_err", "rel_err");
+    for e in self.entries.iter() {
+      println!("{:>5} {:>7} {:>14.6e} {:>14.6e} {:>10.3e} {:>10.3e}{}",
+          e.input, e.index, e.analytic, e.numeric, e.abs_err, e.rel_err,
+          if e.ok { "" } else { "  FAIL" });
+    }
+  }
+}
+
+fn _force_scalar<T: Float>(y: &TensorRef<T>, txn: Txn) -> Result<f64, EvalError> {
+  let mut roots = TagVec::new();
+  roots.push(y);
+  for result in roots.force_all(txn) {
+    result?;
+  }
+  let y = y._get_obj();
+  let y = y.get(txn);
+  let y = y.contiguous();
+  assert_eq!(1, y.len(), "check_grad: expected a scalar output");
+  Ok(y.data()[0].to_f64())
+}
+
+/// Checks the gradient of the scalar-valued graph built by `f_builder`
+/// against central finite differences.
+///
+/// The inputs are wrapped in constant thunks and the graph is built once.
+/// The analytic gradient comes from `backward`; each numeric derivative
+/// re-forces the same graph in fresh `Txn`s with one element of one input
+/// perturbed by `+eps` and `-eps`. An element passes if its absolute or its
+/// relative error is within `tol`.
+pub fn check_grad<T, F>(f_builder: F, inputs: Vec<Tensor<T>>, eps: f64, tol: f64) -> Result<GradCheckReport, EvalError>
+where T: Float, F: Fn(&[TensorRef<T>]) -> TensorRef<T> {
+  let xs: Vec<_> = inputs.iter().map(|x| constant_op(x.clone())).collect();
+  let y = f_builder(&xs);
+  let t = txn();
+  let sink = backward(&y, constant_op(Tensor::scalar(T::one())), t, CheckpointPolicy::KeepAll)?;
+  let mut analytic = Vec::with_capacity(xs.len());
+  for (x, value) in xs.iter().zip(inputs.iter()) {
+    match sink.get_adj(x) {
+      // No cotangent reached this input, so its gradient is zero.
+      None => analytic.push(vec![0.0; value.len()]),
+      Some(dx) => {
+        let mut roots = TagVec::new();
+        roots.push(&dx);
+        for result in roots.force_all(t) {
+          result?;
+        }
+        let dx = dx._get_obj();
+        let dx = dx.get(t);
+        let dx = dx.contiguous();
+        // A scalar cotangent broadcasts over its input.
+        analytic.push((0 .. value.len()).map(|j| {
+          if dx.len() == 1 { dx.data()[0].to_f64() } else { dx.data()[j].to_f64() }
+        }).collect());
+      }
+    }
+  }
+  let mut entries = Vec::new();
+  for (i, (x, value)) in xs.iter().zip(inputs.iter()).enumerate() {
+    for j in 0 .. value.len() {
+      let x0 = value.data()[j];
+      let mut xp = value.clone();
+      xp.data_mut()[j] = x0 + T::from_f64(eps);
+      ConstantOp::reset(x, xp);
+      let yp = _force_scalar(&y, txn());
+      let mut xm = value.clone();
+      xm.data_mut()[j] = x0 - T::from_f64(eps);
+      ConstantOp::reset(x, xm);
+      let ym = _force_scalar(&y, txn());
+      ConstantOp::reset(x, value.clone());
+      let (yp, ym) = (yp?, ym?);
+      let a = analytic[i][j];
+      let n = (yp - ym) / (2.0 * eps);
+      let abs_err = (a - n).abs();
+      let scale = a.abs().max(n.abs());
+      let rel_err = if scale > 0.0 { abs_err / scale } else { 0.0 };
+      entries.push(GradCheckEntry{
+        input:    i,
+        index:    j,
+        analytic: a,
+        numeric:  n,
+        abs_err:  abs_err,
+        rel_err:  rel_err,
+        ok:       abs_err <= tol || rel_err <= tol,
+      });
+    }
+  }
+  Ok(GradCheckReport{entries})
+}
@@ -0,0 +1,304 @@
+use experimental::rt1::*;
+use nn::{TensorAdjoint, TensorRef, tensor_adjoint, tensor_op};
+use num::{Float};
+use tensor::*;
+
+use std::sync::{Arc};
+
+pub type IndexRef = ThunkRef<Tensor<usize>>;
+
+/// Splits `shape` around `axis` into `(outer, dim, inner)` extents.
+fn _split_axis(shape: &[usize], axis: usize) -> (usize, usize, usize) {
+  let outer = shape[.. axis].iter().product();
+  let inner = shape[axis + 1 ..].iter().product();
+  (outer, shape[axis], inner)
+}
+
+fn _unravel(mut flat: usize, shape: &[usize], coords: &mut [usize]) {
+  for d in (0 .. shape.len()).rev() {
+    coords[d] = flat % shape[d];
+    flat /= shape[d];
+  }
+}
+
+fn _ravel(coords: &[usize], shape: &[usize]) -> usize {
+  coords.iter().zip(shape.iter()).fold(0, |acc, (&c, &s)| acc * s + c)
+}
+
+/// The elements `start .. end` along `axis`, or `None` if out of bounds.
+pub fn slice<T: Float>(x: &Tensor<T>, axis: usize, start: usize, end: usize) -> Option<Tensor<T>> {
+  if axis >= x.ndim() || start > end || end > x.shape()[axis] {
+    return None;
+  }
+  let (outer, dim, inner) = _split_axis(x.shape(), axis);
+  let mut data = Vec::with_capacity(outer * (end - start) * inner);
+  for o in 0 .. outer {
+    data.extend_from_slice(&x.data()[(o * dim + start) * inner .. (o * dim + end) * inner]);
+  }
+  let mut shape = x.shape().to_vec();
+  shape[axis] = end - start;
+  Some(Tensor::new(shape, data))
+}
+
+/// The elements at positions `idx` along `axis`, or `None` if an index is
+/// out of bounds.
+pub fn index_select<T: Float>(x: &Tensor<T>, axis: usize, idx: &Tensor<usize>) -> Option<Tensor<T>> {
+  if axis >= x.ndim() || idx.ndim() != 1 {
+    return None;
+  }
+  let (outer, dim, inner) = _split_axis(x.shape(), axis);
+  if idx.data().iter().any(|&i| i >= dim) {
+    return None;
+  }
+  let mut data = Vec::with_capacity(outer * idx.len() * inner);
+  for o in 0 .. outer {
+    for &i in idx.data().iter() {
+      data.extend_from_slice(&x.data()[(o * dim + i) * inner .. (o * dim + i + 1) * inner]);
+    }
+  }
+  let mut shape = x.shape().to_vec();
+  shape[axis] = idx.len();
+  Some(Tensor::new(shape, data))
+}
+
+/// Adds the slices of `src` into `x` at positions `idx` along `axis`; the
+/// reverse of `index_select`.
+pub fn index_add<T: Float>(x: &Tensor<T>, axis: usize, idx: &Tensor<usize>, src: &Tensor<T>) -> Option<Tensor<T>> {
+  if axis >= x.ndim() || idx.ndim() != 1 || src.ndim() != x.ndim() {
+    return None;
+  }
+  let (outer, dim, inner) = _split_axis(x.shape(), axis);
+  let mut expected = x.shape().to_vec();
+  expected[axis] = idx.len();
+  if src.shape() != &expected[..] || idx.data().iter().any(|&i| i >= dim) {
+    return None;
+  }
+  let mut y = x.clone();
+  for o in 0 .. outer {
+    for (k, &i) in idx.data().iter().enumerate() {
+      for j in 0 .. inner {
+        let yi = (o * dim + i) * inner + j;
+        y.data_mut()[yi] = y.data()[yi] + src.data()[(o * idx.len() + k) * inner + j];
+      }
+    }
+  }
+  Some(y)
+}
+
+/// Checks that `idx` may index `x` along `axis` in `gather` and
+/// `scatter_add`: same rank, and no larger than `x` along other axes.
+fn _check_gather<U>(x: &Tensor<U>, axis: usize, idx: &Tensor<usize>) -> bool {
+  axis < x.ndim()
+      && idx.ndim() == x.ndim()
+      && (0 .. x.ndim()).all(|d| d == axis || idx.shape()[d] <= x.shape()[d])
+      && idx.data().iter().all(|&i| i < x.shape()[axis])
+}
+
+/// `y[p] = x[p with coordinate axis replaced by idx[p]]`, for every
+/// position `p` of `idx`.
+pub fn gather<T: Float>(x: &Tensor<T>, axis: usize, idx: &Tensor<usize>) -> Option<Tensor<T>> {
+  if !_check_gather(x, axis, idx) {
+    return None;
+  }
+  let mut coords = vec![0; idx.ndim()];
+  let mut data = Vec::with_capacity(idx.len());
+  for (p, &i) in idx.data().iter().enumerate() {
+    _unravel(p, idx.shape(), &mut coords);
+    coords[axis] = i;
+    data.push(x.data()[_ravel(&coords, x.shape())]);
+  }
+  Some(Tensor::new(idx.shape().to_vec(), data))
+}
+
+/// `x` with `src[p]` added at `p with coordinate axis replaced by idx[p]`,
+/// for every position `p` of `idx`; the reverse of `gather`.
+pub fn scatter_add<T: Float>(x: &Tensor<T>, axis: usize, idx: &Tensor<usize>, src: &Tensor<T>) -> Option<Tensor<T>> {
+  if !_check_gather(x, axis, idx) || src.shape() != idx.shape() {
+    return None;
+  }
+  let mut coords = vec![0; idx.ndim()];
+  let mut y = x.clone();
+  for (p, &i) in idx.data().iter().enumerate() {
+    _unravel(p, idx.shape(), &mut coords);
+    coords[axis] = i;
+    let yi = _ravel(&coords, x.shape());
+    y.data_mut()[yi] = y.data()[yi] + src.data()[p];
+  }
+  Some(y)
+}
+
+/// Builds a tensor op whose kernel may reject its inputs: when `f` returns
+/// `None` the entry fails, and forcing reports `EvalError::EntryFailed` for
+/// this thunk (and every thunk downstream of it).
+pub fn _checked_op<T, F>(name: &'static str, xs: Vec<TensorRef<T>>, idx: Option<IndexRef>, f: F, adjoint: TensorAdjoint<T>) -> TensorRef<T>
+where T: Float, F: Fn(&[&Tensor<T>], Option<&Tensor<usize>>) -> Option<Tensor<T>> + 'static {
+  let mut freevars: Vec<_> = xs.iter().map(|x| x.clone()._into_tag()).collect();
+  if let Some(ref idx) = idx {
+    freevars.push(idx.clone()._into_tag());
+  }
+  let code = ThunkCode{
+    name:     name,
+    entry:    {
+      let xs: Vec<_> = xs.iter().map(|x| x._get_obj()).collect();
+      let idx = idx.map(|idx| idx._get_obj());
+      Some(Arc::new(move |txn, y| {
+        let xs = match xs.iter().map(|x| x.try_get(txn)).collect::<Result<Vec<_>, _>>() {
+          Err(_) => return false,
+          Ok(xs) => xs,
+        };
+        let xs: Vec<_> = xs.iter().map(|x| x.contiguous()).collect();
+        let xs: Vec<&Tensor<T>> = xs.iter().map(|x| &**x).collect();
+        let idx = match idx.as_ref().map(|idx| idx.try_get(txn)) {
+          None => None,
+          Some(Err(_)) => return false,
+          Some(Ok(idx)) => Some(idx),
+        };
+        let idx = idx.as_ref().map(|idx| idx.contiguous());
+        match (f)(&xs, idx.as_ref().map(|idx| &**idx)) {
+          None => false,
+          Some(value) => {
+            let mut y = y.get_mut(txn);
+            *y = value;
+            true
+          }
+        }
+      }))
+    },
+    adjoint:  adjoint,
+    batch:    None,
+  };
+  let thunk = Thunk::new(DataCode{
+    alloc:    Some(Arc::new(|_txn| Tensor::default())),
+    nbytes:   Some(Arc::new(|y: &Tensor<T>| y.nbytes())),
+  }, freevars, code);
+  thunk._put_obj()
+}
+
+pub fn _zeros_like_op<T: Float>(x: TensorRef<T>) -> TensorRef<T> {
+  tensor_op("zeros_like", vec![x], |xs| Tensor::zeros(xs[0].shape().to_vec()), None)
+}
+
+pub fn slice_op<T: Float>(x: TensorRef<T>, axis: usize, start: usize, end: usize) -> TensorRef<T> {
+  let x_ = x.clone();
+  _checked_op("slice", vec![x_], None, move |xs, _| slice(xs[0], axis, start, end), tensor_adjoint(move |_y, dy, sink| {
+    sink.put_adj(&x, _slice_grad_op(x.clone(), dy, axis, start, end));
+  }))
+}
+
+/// Embeds `dy` at `start .. end` along `axis` into zeros shaped like
+/// `x_like`; the transpose of `slice_op`.
+fn _slice_grad_op<T: Float>(x_like: TensorRef<T>, dy: TensorRef<T>, axis: usize, start: usize, end: usize) -> TensorRef<T> {
+  let dy_ = dy.clone();
+  _checked_op("slice_grad", vec![x_like, dy_], None, move |xs, _| {
+    let (x, dy) = (xs[0], xs[1]);
+    let (outer, dim, inner) = _split_axis(x.shape(), axis);
+    let mut dx = Tensor::zeros(x.shape().to_vec());
+    for o in 0 .. outer {
+      let n = (end - start) * inner;
+      dx.data_mut()[(o * dim + start) * inner .. (o * dim + end) * inner].copy_from_slice(&dy.data()[o * n .. (o + 1) * n]);
+    }
+    Some(dx)
+  }, tensor_adjoint(move |_y, g, sink| {
+    sink.put_adj(&dy, slice_op(g, axis, start, end));
+  }))
+}
+
+/// Splits `x` along `axis` into consecutive parts of lengths `sizes`, as
+/// the outputs of a single op. Fails when forced if `sizes` do not add up to
+/// the length of `axis`.
+pub fn split_op<T: Float>(x: TensorRef<T>, axis: usize, sizes: Vec<usize>) -> Vec<TensorRef<T>> {
+  let entry: Arc<Fn(Txn, &[LData<Tensor<T>>]) -> bool> = {
+    let x = x._get_obj();
+    let sizes = sizes.clone();
+    Arc::new(move |txn, ys| {
+      let x = match x.try_get(txn) {
+        Err(_) => return false,
+        Ok(x) => x,
+      };
+      let x = x.contiguous();
+      if axis >= x.ndim() || sizes.iter().sum::<usize>() != x.shape()[axis] {
+        return false;
+      }
+      let mut start = 0;
+      for (y, &size) in ys.iter().zip(sizes.iter()) {
+        *y.get_mut(txn) = slice(&x, axis, start, start + size).unwrap();
+        start += size;
+      }
+      true
+    })
+  };
+  let adjoint: MultiAdjoint<Tensor<T>> = {
+    let (x, sizes) = (x.clone(), sizes.clone());
+    Arc::new(move |_pass, _ys, dys, sink| {
+      // Parts without a cotangent contribute zeros.
+      let present: Vec<bool> = dys.iter().map(|dy| dy.is_some()).collect();
+      let mut xs = vec![x.clone()];
+      xs.extend(dys.iter().filter_map(|dy| dy.clone()));
+      // The transpose slices each present part back out.
+      let grad_adjoint = {
+        let (dys, sizes) = (dys.to_vec(), sizes.clone());
+        tensor_adjoint(move |_y, g, sink| {
+          let mut start = 0;
+          for (dy, &size) in dys.iter().zip(sizes.iter()) {
+            if let Some(ref dy) = *dy {
+              sink.put_adj(dy, slice_op(g.clone(), axis, start, start + size));
+            }
+            start += size;
+          }
+        })
+      };
+      let sizes = sizes.clone();
+      let dx = tensor_op("split_grad", xs, move |xs| {
+        let (outer, dim, inner) = _split_axis(xs[0].shape(), axis);
+        let mut dx = Tensor::zeros(xs[0].shape().to_vec());
+        let (mut start, mut k) = (0, 1);
+        for (&size, &present) in sizes.iter().zip(present.iter()) {
+          if present {
+            let n = size * inner;
+            for o in 0 .. outer {
+              dx.data_mut()[(o * dim + start) * inner .. (o * dim + start + size) * inner].copy_from_slice(&xs[k].data()[o * n .. (o + 1) * n]);
+            }
+            k += 1;
+          }
+          start += size;
+        }
+        dx
+      }, grad_adjoint);
+      sink.put_adj(&x, dx);
+    })
+  };
+  MultiOp::build_thunks("split", vec![x], sizes.len(), DataCode{
+    alloc:    Some(Arc::new(|_txn| Tensor::default())),
+    nbytes:   Some(Arc::new(|y: &Tensor<T>| y.nbytes())),
+  }, entry, Some(adjoint)).into_iter().map(|thunk| thunk._put_obj()).collect()
+}
+
+pub fn index_select_op<T: Float>(x: TensorRef<T>, axis: usize, idx: IndexRef) -> TensorRef<T> {
+  let (x_, idx_) = (x.clone(), idx.clone());
+  _checked_op("index_select", vec![x_], Some(idx_), move |xs, idx| index_select(xs[0], axis, idx.unwrap()), tensor_adjoint(move |_y, dy, sink| {
+    sink.put_adj(&x, _index_add_op(_zeros_like_op(x.clone()), axis, idx.clone(), dy));
+  }))
+}
+
+fn _index_add_op<T: Float>(x: TensorRef<T>, axis: usize, idx: IndexRef, src: TensorRef<T>) -> TensorRef<T> {
+  let (x_, src_, idx_) = (x.clone(), src.clone(), idx.clone());
+  _checked_op("index_add", vec![x_, src_], Some(idx_), move |xs, idx| index_add(xs[0], axis, idx.unwrap(), xs[1]), tensor_adjoint(move |_y, dy, sink| {
+    sink.put_adj(&x, dy.clone());
+    sink.put_adj(&src, index_select_op(dy, axis, idx.clone()));
+  }))
+}
+
+pub fn gather_op<T: Float>(x: TensorRef<T>, axis: usize, idx: IndexRef) -> TensorRef<T> {
+  let (x_, idx_) = (x.clone(), idx.clone());
+  _checked_op("gather", vec![x_], Some(idx_), move |xs, idx| gather(xs[0], axis, idx.unwrap()), tensor_adjoint(move |_y, dy, sink| {
+    sink.put_adj(&x, scatter_add_op(_zeros_like_op(x.clone()), axis, idx.clone(), dy));
+  }))
+}
+
+pub fn scatter_add_op<T: Float>(x: TensorRef<T>, axis: usize, idx: IndexRef, src: TensorRef<T>) -> TensorRef<T> {
+  let (x_, src_, idx_) = (x.clone(), src.clone(), idx.clone());
+  _checked_op("scatter_add", vec![x_, src_], Some(idx_), move |xs, idx| scatter_add(xs[0], axis, idx.unwrap(), xs[1]), tensor_adjoint(move |_y, dy, sink| {
+    sink.put_adj(&x, dy.clone());
+    sink.put_adj(&src, gather_op(dy, axis, idx.clone()));
+  }))
+}
@@ -0,0 +1,89 @@
+use experimental::rt1::*;
+use nn::{TensorRef};
+use num::{Float};
+use tensor::*;
+
+fn _force<T: Float>(x: &TensorRef<T>, txn: Txn) -> Result<Tensor<T>, EvalError> {
+  let mut roots = TagVec::new();
+  roots.push(x);
+  for result in roots.force_all(txn) {
+    result?;
+  }
+  let x = x._get_obj();
+  let x = x.get(txn);
+  Ok(x.contiguous().into_owned())
+}
+
+fn _one_hot<T: Float>(shape: &[usize], idx: usize) -> Tensor<T> {
+  let mut e = Tensor::zeros(shape.to_vec());
+  e.data_mut()[idx] = T::one();
+  e
+}
+
+/// Forces the cotangent `dx` of an input of length `len`; a missing or
+/// scalar cotangent is broadcast, and one of any other length fails with
+/// `EvalError::ShapeMismatch`.
+fn _force_adj<T: Float>(dx: Option<TensorRef<T>>, len: usize, txn: Txn) -> Result<Vec<T>, EvalError> {
+  match dx {
+    None => Ok(vec![T::zero(); len]),
+    Some(dx) => {
+      let dx_val = _force(&dx, txn)?;
+      if dx_val.len() == len {
+        Ok(dx_val.into_data())
+      } else if dx_val.len() == 1 {
+        Ok(vec![dx_val.data()[0]; len])
+      } else {
+        Err(EvalError::ShapeMismatch(dx._stable()))
+      }
+    }
+  }
+}
+
+/// Assembles the dense Jacobian of `y` with respect to `x` as a
+/// `[y.len(), x.len()]` tensor, one row per reverse pass. All passes run in
+/// `txn`, so the forward payloads are computed once and shared.
+pub fn jacobian_rev<T: Float>(y: &TensorRef<T>, x: &TensorRef<T>, txn: Txn) -> Result<Tensor<T>, EvalError> {
+  let y_val = _force(y, txn)?;
+  let n = _force(x, txn)?.len();
+  let m = y_val.len();
+  let mut jac = Vec::with_capacity(m * n);
+  for i in 0 .. m {
+    let sink = reverse(y, constant_op(_one_hot(y_val.shape(), i)))?;
+    jac.extend(_force_adj(sink.get_adj(x), n, txn)?);
+  }
+  Ok(Tensor::new(vec![m, n], jac))
+}
+
+/// Assembles the dense Jacobian of `y` with respect to `x` as a
+/// `[y.len(), x.len()]` tensor, one column per pass.
+///
+/// The runtime has no forward mode, so each column is a reverse pass over a
+/// reverse pass: the cotangent of `x` under a seed `w` is the linear map
+/// `J^T w`, and its cotangent with respect to `w` under a seed `u` is `J u`.
+/// This needs the adjoints of the ops between `x` and `y` to be
+/// differentiable in their cotangent; if the seed does not reach back through
+/// them, this fails with `EvalError::NotDifferentiable` rather than returning
+/// a zero column. All passes run in `txn`.
+pub fn jacobian_rev_rev<T: Float>(y: &TensorRef<T>, x: &TensorRef<T>, txn: Txn) -> Result<Tensor<T>, EvalError> {
+  let y_val = _force(y, txn)?;
+  let x_val = _force(x, txn)?;
+  let (m, n) = (y_val.len(), x_val.len());
+  let w = constant_op(Tensor::zeros(y_val.shape().to_vec()));
+  let mut jac = vec![T::zero(); m * n];
+  let dx = match reverse(y, w.clone())?.get_adj(x) {
+    None => return Ok(Tensor::new(vec![m, n], jac)),
+    Some(dx) => dx,
+  };
+  for j in 0 .. n {
+    let sink = reverse(&dx, constant_op(_one_hot(x_val.shape(), j)))?;
+    let dw = match sink.get_adj(&w) {
+      None => return Err(EvalError::NotDifferentiable(dx._stable())),
+      Some(dw) => dw,
+    };
+    let col = _force_adj(Some(dw), m, txn)?;
+    for i in 0 .. m {
+      jac[i * n + j] = col[i];
+    }
+  }
+  Ok(Tensor::new(vec![m, n], jac))
+}
@@ -1,4 +1,15 @@
 #[macro_use] extern crate lazy_static;
 extern crate parking_lot;
 
+pub mod conv;
 pub mod experimental;
+pub mod gradcheck;
+pub mod index;
+pub mod jacobian;
+pub mod linalg;
+pub mod nn;
+pub mod num;
+pub mod optim;
+pub mod rng;
+pub mod tensor;
+pub mod vmap;
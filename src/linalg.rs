@@ -0,0 +1,295 @@
+use experimental::rt1::*;
+use index::{_checked_op, _zeros_like_op};
+use nn::{TensorRef, tensor_adjoint, tensor_op, transpose_op};
+use num::{Float};
+use tensor::*;
+
+use std::cmp::{Ordering};
+use std::sync::{Arc};
+
+pub use nn::{matmul_op};
+
+fn _square_dim<T>(a: &Tensor<T>) -> Option<usize> {
+  if a.ndim() != 2 || a.shape()[0] != a.shape()[1] {
+    return None;
+  }
+  Some(a.shape()[0])
+}
+
+/// Solves `A X = B`, or `A^T X = B` if `trans`, where `A` is lower or upper
+/// triangular; only that triangle of `A` is read. Returns `None` if `A` is
+/// not square or `B` does not have as many rows.
+pub fn solve_triangular<T: Float>(a: &Tensor<T>, b: &Tensor<T>, lower: bool, trans: bool) -> Option<Tensor<T>> {
+  let n = _square_dim(a)?;
+  let (rows, k) = b.rows_cols();
+  if n != rows {
+    return None;
+  }
+  // Transposing swaps which triangle is populated.
+  let lower_eff = lower != trans;
+  let at = |i: usize, j: usize| if trans { a.data()[j * n + i] } else { a.data()[i * n + j] };
+  let mut x = b.clone();
+  for c in 0 .. k {
+    let order: Vec<usize> = if lower_eff { (0 .. n).collect() } else { (0 .. n).rev().collect() };
+    for &i in order.iter() {
+      let mut s = x.data()[i * k + c];
+      let js: Vec<usize> = if lower_eff { (0 .. i).collect() } else { (i + 1 .. n).collect() };
+      for j in js {
+        s = s - at(i, j) * x.data()[j * k + c];
+      }
+      x.data_mut()[i * k + c] = s / at(i, i);
+    }
+  }
+  Some(x)
+}
+
+/// Solves `A X = B` by LU factorization with partial pivoting. Returns
+/// `None` if `A` is singular or the shapes do not match.
+pub fn solve<T: Float>(a: &Tensor<T>, b: &Tensor<T>) -> Option<Tensor<T>> {
+  let n = _square_dim(a)?;
+  let (rows, k) = b.rows_cols();
+  if n != rows {
+    return None;
+  }
+  let mut lu = a.data().to_vec();
+  let mut x = b.data().to_vec();
+  for p in 0 .. n {
+    let mut piv = p;
+    for i in p + 1 .. n {
+      if lu[i * n + p].abs() > lu[piv * n + p].abs() {
+        piv = i;
+      }
+    }
+    if lu[piv * n + p] == T::zero() {
+      return None;
+    }
+    if piv != p {
+      for j in 0 .. n {
+        lu.swap(p * n + j, piv * n + j);
+      }
+      for c in 0 .. k {
+        x.swap(p * k + c, piv * k + c);
+      }
+    }
+    for i in p + 1 .. n {
+      let f = lu[i * n + p] / lu[p * n + p];
+      for j in p .. n {
+        lu[i * n + j] = lu[i * n + j] - f * lu[p * n + j];
+      }
+      for c in 0 .. k {
+        x[i * k + c] = x[i * k + c] - f * x[p * k + c];
+      }
+    }
+  }
+  let u = Tensor::new(vec![n, n], lu);
+  solve_triangular(&u, &Tensor::new(b.shape().to_vec(), x), false, false)
+}
+
+/// The lower-triangular `L` with `A = L L^T`, for symmetric positive
+/// definite `A`; only the lower triangle of `A` is read. Returns `None` if
+/// `A` is not square or not positive definite.
+pub fn cholesky<T: Float>(a: &Tensor<T>) -> Option<Tensor<T>> {
+  let n = _square_dim(a)?;
+  let mut l = vec![T::zero(); n * n];
+  for j in 0 .. n {
+    let mut d = a.data()[j * n + j];
+    for p in 0 .. j {
+      d = d - l[j * n + p] * l[j * n + p];
+    }
+    // A NaN pivot is not positive either.
+    if d.partial_cmp(&T::zero()) != Some(Ordering::Greater) {
+      return None;
+    }
+    let d = d.sqrt();
+    l[j * n + j] = d;
+    for i in j + 1 .. n {
+      let mut s = a.data()[i * n + j];
+      for p in 0 .. j {
+        s = s - l[i * n + p] * l[j * n + p];
+      }
+      l[i * n + j] = s / d;
+    }
+  }
+  Some(Tensor::new(vec![n, n], l))
+}
+
+/// Reduced QR factorization of an `[m, n]` matrix with `m >= n` by
+/// Householder reflections: `Q` is `[m, n]` with orthonormal columns and `R`
+/// is `[n, n]` upper triangular. Returns `None` if `A` is not a matrix with
+/// at least as many rows as columns.
+pub fn qr<T: Float>(a: &Tensor<T>) -> Option<(Tensor<T>, Tensor<T>)> {
+  if a.ndim() != 2 || a.shape()[0] < a.shape()[1] {
+    return None;
+  }
+  let (m, n) = (a.shape()[0], a.shape()[1]);
+  let mut r = a.data().to_vec();
+  let mut q = vec![T::zero(); m * m];
+  for i in 0 .. m {
+    q[i * m + i] = T::one();
+  }
+  for k in 0 .. n {
+    let norm = (k .. m).fold(T::zero(), |s, i| s + r[i * n + k] * r[i * n + k]).sqrt();
+    if norm == T::zero() {
+      continue;
+    }
+    let alpha = if r[k * n + k] > T::zero() { -norm } else { norm };
+    let mut v: Vec<T> = (k .. m).map(|i| r[i * n + k]).collect();
+    v[0] = v[0] - alpha;
+    let vv = v.iter().fold(T::zero(), |s, &x| s + x * x);
+    if vv == T::zero() {
+      continue;
+    }
+    let two = T::from_f64(2.0);
+    // R <- H R and Q <- Q H, with H = I - 2 v v^T / (v^T v).
+    for j in k .. n {
+      let dot = (k .. m).fold(T::zero(), |s, i| s + v[i - k] * r[i * n + j]);
+      let f = two * dot / vv;
+      for i in k .. m {
+        r[i * n + j] = r[i * n + j] - f * v[i - k];
+      }
+    }
+    for i in 0 .. m {
+      let dot = (k .. m).fold(T::zero(), |s, j| s + q[i * m + j] * v[j - k]);
+      let f = two * dot / vv;
+      for j in k .. m {
+        q[i * m + j] = q[i * m + j] - f * v[j - k];
+      }
+    }
+  }
+  let q_thin = (0 .. m).flat_map(|i| q[i * m .. i * m + n].to_vec()).collect();
+  let mut r_thin = r[.. n * n].to_vec();
+  for i in 0 .. n {
+    for j in 0 .. i {
+      r_thin[i * n + j] = T::zero();
+    }
+  }
+  Some((Tensor::new(vec![m, n], q_thin), Tensor::new(vec![n, n], r_thin)))
+}
+
+/// Zeroes the upper (or lower) triangle of a square matrix and scales its
+/// diagonal.
+fn _triangle<T: Float>(x: &Tensor<T>, lower: bool, diag_scale: T) -> Tensor<T> {
+  let n = x.shape()[0];
+  let mut y = x.clone();
+  for i in 0 .. n {
+    for j in 0 .. n {
+      let v = &mut y.data_mut()[i * n + j];
+      if i == j {
+        *v = *v * diag_scale;
+      } else if (j > i) == lower {
+        *v = T::zero();
+      }
+    }
+  }
+  y
+}
+
+/// Keeps the lower (or upper) triangle of a square matrix; this is
+/// self-adjoint.
+fn _triangle_op<T: Float>(x: TensorRef<T>, lower: bool) -> TensorRef<T> {
+  let x_ = x.clone();
+  tensor_op("triangle", vec![x_], move |xs| _triangle(xs[0], lower, T::one()), tensor_adjoint(move |_y, dy, sink| {
+    sink.put_adj(&x, _triangle_op(dy, lower));
+  }))
+}
+
+/// Solves `A X = B` for triangular `A`. Forcing fails with
+/// `EvalError::EntryFailed` if the shapes do not match.
+pub fn solve_triangular_op<T: Float>(a: TensorRef<T>, b: TensorRef<T>, lower: bool) -> TensorRef<T> {
+  let (a_, b_) = (a.clone(), b.clone());
+  _checked_op("solve_triangular", vec![a_, b_], None, move |xs, _| solve_triangular(xs[0], xs[1], lower, false), tensor_adjoint(move |x, dx, sink| {
+    // `dB = A^-T dX` and `dA = -dB X^T`, restricted to the triangle of `A`.
+    let db = solve_triangular_op(transpose_op(a.clone()), dx, !lower);
+    let da = _triangle_op(neg_op(matmul_op(db.clone(), transpose_op(x))), lower);
+    sink.put_adj(&a, da);
+    sink.put_adj(&b, db);
+  }))
+}
+
+/// Solves `A X = B` for general square `A`. Forcing fails with
+/// `EvalError::EntryFailed` if `A` is singular or the shapes do not match.
+pub fn solve_op<T: Float>(a: TensorRef<T>, b: TensorRef<T>) -> TensorRef<T> {
+  let (a_, b_) = (a.clone(), b.clone());
+  _checked_op("solve", vec![a_, b_], None, |xs, _| solve(xs[0], xs[1]), tensor_adjoint(move |x, dx, sink| {
+    // `dB = A^-T dX` and `dA = -dB X^T`.
+    let db = solve_op(transpose_op(a.clone()), dx);
+    let da = neg_op(matmul_op(db.clone(), transpose_op(x)));
+    sink.put_adj(&a, da);
+    sink.put_adj(&b, db);
+  }))
+}
+
+/// Cholesky factor of a symmetric positive definite matrix; forcing fails
+/// with `EvalError::EntryFailed` if it is not positive definite. The
+/// cotangent of `A` is symmetrized, i.e. `A` is treated as a symmetric
+/// input. It can only be differentiated once.
+pub fn cholesky_op<T: Float>(a: TensorRef<T>) -> TensorRef<T> {
+  let a_ = a.clone();
+  _checked_op("cholesky", vec![a_], None, |xs, _| cholesky(xs[0]), tensor_adjoint(move |l, dl, sink| {
+    // With `Phi` taking the lower triangle and halving the diagonal:
+    // `S = L^-T Phi(L^T dL) L^-1` and `dA = (S + S^T) / 2`.
+    let da = _checked_op("cholesky_grad", vec![l, dl], None, |xs, _| {
+      let (l, dl) = (xs[0], xs[1]);
+      let p = _triangle(&l.transpose().matmul(dl), true, T::from_f64(0.5));
+      let s = solve_triangular(l, &p, true, true)?;
+      let s = solve_triangular(l, &s.transpose(), true, true)?.transpose();
+      let half = Tensor::scalar(T::from_f64(0.5));
+      Some((s.clone() + s.transpose()) * half)
+    }, Some(non_differentiable()));
+    sink.put_adj(&a, da);
+  }))
+}
+
+/// Reduced QR factorization of an `[m, n]` matrix with `m >= n` and full
+/// column rank, returning `(Q, R)` as the two outputs of one op. Forcing
+/// fails with `EvalError::EntryFailed` on any other shape. It can only be
+/// differentiated once.
+pub fn qr_op<T: Float>(a: TensorRef<T>) -> (TensorRef<T>, TensorRef<T>) {
+  let entry: Arc<Fn(Txn, &[LData<Tensor<T>>]) -> bool> = {
+    let a = a._get_obj();
+    Arc::new(move |txn, ys| {
+      let a = match a.try_get(txn) {
+        Err(_) => return false,
+        Ok(a) => a,
+      };
+      match qr(&a.contiguous()) {
+        None => false,
+        Some((q, r)) => {
+          *ys[0].get_mut(txn) = q;
+          *ys[1].get_mut(txn) = r;
+          true
+        }
+      }
+    })
+  };
+  let adjoint: MultiAdjoint<Tensor<T>> = {
+    let a = a.clone();
+    Arc::new(move |_pass, ys, dys, sink| {
+      // An output without a cotangent contributes zeros.
+      let mut xs = ys.to_vec();
+      for (y, dy) in ys.iter().zip(dys.iter()) {
+        xs.push(dy.clone().unwrap_or_else(|| _zeros_like_op(y.clone())));
+      }
+      // `dA = Q (dR + tril(M - M^T) R^-T) + (dQ - Q Q^T dQ) R^-T`, with
+      // `M = Q^T dQ + R dR^T`.
+      let da = _checked_op("qr_grad", xs, None, |xs, _| {
+        let (q, r, dq, dr) = (xs[0], xs[1], xs[2], xs[3]);
+        let qdq = q.transpose().matmul(dq);
+        let m = qdq.clone() + r.matmul(&dr.transpose());
+        let t = _triangle(&(m.clone() - m.transpose()), true, T::zero());
+        let r_inv_t = |x: Tensor<T>| solve_triangular(r, &x.transpose(), false, false).map(|x| x.transpose());
+        let ga = q.matmul(&(dr.clone() + r_inv_t(t)?));
+        let gb = r_inv_t(dq.clone() - q.matmul(&qdq))?;
+        Some(ga + gb)
+      }, Some(non_differentiable()));
+      sink.put_adj(&a, da);
+    })
+  };
+  let mut ys = MultiOp::build_thunks("qr", vec![a], 2, DataCode{
+    alloc:    Some(Arc::new(|_txn| Tensor::default())),
+    nbytes:   Some(Arc::new(|y: &Tensor<T>| y.nbytes())),
+  }, entry, Some(adjoint)).into_iter().map(|thunk| thunk._put_obj());
+  let q = ys.next().unwrap();
+  let r = ys.next().unwrap();
+  (q, r)
+}
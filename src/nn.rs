@@ -0,0 +1,526 @@
+use experimental::rt1::*;
+use num::{Float};
+use rng::{_mix64};
+use tensor::*;
+
+use std::f64::consts::{PI};
+use std::sync::{Arc};
+
+pub type TensorRef<T> = ThunkRef<Tensor<T>>;
+
+pub type TensorAdjoint<T> = Option<Arc<Fn(Pass, TensorRef<T>, &mut Sink)>>;
+
+/// Like `map_op`, but accounts for the tensor buffer in the payload size.
+pub fn tensor_op<T, F>(name: &'static str, xs: Vec<TensorRef<T>>, f: F, adjoint: TensorAdjoint<T>) -> TensorRef<T>
+where T: Float, F: Fn(&[&Tensor<T>]) -> Tensor<T> + 'static {
+  tensor_thunk(name, xs, f, adjoint)._put_obj()
+}
+
+/// Like `tensor_op`, but returns the thunk before it is put on the heap,
+/// e.g. to attach a batching rule with `Thunk::with_batch`.
+pub fn tensor_thunk<T, F>(name: &'static str, xs: Vec<TensorRef<T>>, f: F, adjoint: TensorAdjoint<T>) -> Thunk<Tensor<T>>
+where T: Float, F: Fn(&[&Tensor<T>]) -> Tensor<T> + 'static {
+  // Kernels index `data` directly, so views are made contiguous first.
+  MapOp::build_thunk(name, xs, move |xs| {
+    let xs: Vec<_> = xs.iter().map(|x| x.contiguous()).collect();
+    let xs: Vec<&Tensor<T>> = xs.iter().map(|x| &**x).collect();
+    (f)(&xs)
+  }, adjoint, Some(Arc::new(|y: &Tensor<T>| y.nbytes())))
+}
+
+/// Wraps an adjoint which only needs the op's output and its cotangent.
+pub fn tensor_adjoint<T, F>(f: F) -> TensorAdjoint<T>
+where T: Float, F: Fn(TensorRef<T>, TensorRef<T>, &mut Sink) + 'static {
+  Some(Arc::new(move |_pass, y, sink| {
+    let dy = match sink.get_adj(&y) {
+      None => return,
+      Some(dy) => dy,
+    };
+    (f)(y, dy, sink)
+  }))
+}
+
+fn _batch_size<T>(x: &Tensor<T>) -> usize {
+  if x.ndim() <= 1 { 1 } else { x.rows_cols().0 }
+}
+
+/// Transposes a matrix, or each matrix of a batch.
+pub fn transpose_op<T: Float>(x: TensorRef<T>) -> TensorRef<T> {
+  let batch = _map_batch_rule(vec![x.clone()], |xs| transpose_op(xs[0].clone()));
+  tensor_thunk("transpose", vec![x.clone()], |xs| xs[0].transpose(), tensor_adjoint(move |_y, dy, sink| {
+    sink.put_adj(&x, transpose_op(dy));
+  })).with_batch(batch)._put_obj()
+}
+
+/// Matrix product of `[m, k]` and `[k, n]` matrices.
+pub fn matmul_op<T: Float>(a: TensorRef<T>, b: TensorRef<T>) -> TensorRef<T> {
+  let (a_, b_) = (a.clone(), b.clone());
+  let batch: Arc<Fn(TensorRef<T>, &mut Batcher) -> Result<(), EvalError>> = {
+    let (a, b) = (a.clone(), b.clone());
+    Arc::new(move |y, batcher| {
+      let ab = batcher.get(&a).unwrap_or_else(|| a.clone());
+      let bb = batcher.get(&b).unwrap_or_else(|| b.clone());
+      batcher.put(&y, batch_matmul_op(ab, bb));
+      Ok(())
+    })
+  };
+  tensor_thunk("matmul", vec![a_, b_], |xs| xs[0].matmul(xs[1]), tensor_adjoint(move |_y, dy, sink| {
+    sink.put_adj(&a, matmul_op(dy.clone(), transpose_op(b.clone())));
+    sink.put_adj(&b, matmul_op(transpose_op(a.clone()), dy));
+  })).with_batch(batch)._put_obj()
+}
+
+/// Matrix product where either operand may be a batch of matrices; see
+/// `Tensor::batch_matmul`.
+pub fn batch_matmul_op<T: Float>(a: TensorRef<T>, b: TensorRef<T>) -> TensorRef<T> {
+  let (a_, b_) = (a.clone(), b.clone());
+  tensor_op("batch_matmul", vec![a_, b_], |xs| xs[0].batch_matmul(xs[1]), tensor_adjoint(move |_y, dy, sink| {
+    sink.put_adj(&a, sum_batch_op(batch_matmul_op(dy.clone(), transpose_op(b.clone())), a.clone()));
+    sink.put_adj(&b, sum_batch_op(batch_matmul_op(transpose_op(a.clone()), dy), b.clone()));
+  }))
+}
+
+/// Repeats `x` along a new leading axis of the size of the leading axis of
+/// `like`. Scalars are left alone, as they broadcast anyway. This is the
+/// broadcast of `vmap`, which the `Batcher` only applies to values it has not
+/// batched, so `x` never has a batch axis yet.
+pub fn broadcast_batch_op<T: Float>(x: TensorRef<T>, like: TensorRef<T>) -> TensorRef<T> {
+  let x_ = x.clone();
+  tensor_op("broadcast_batch", vec![x_, like], |xs| {
+    let (x, like) = (xs[0], xs[1]);
+    if x.ndim() == 0 {
+      return x.view();
+    }
+    let batch = like.shape()[0];
+    let mut shape = vec![batch];
+    shape.extend_from_slice(x.shape());
+    let mut y = Vec::with_capacity(batch * x.len());
+    for _ in 0 .. batch {
+      y.extend_from_slice(x.data());
+    }
+    Tensor::new(shape, y)
+  }, tensor_adjoint(move |_y, dy, sink| {
+    sink.put_adj(&x, sum_batch_op(dy, x.clone()));
+  }))
+}
+
+/// Sums `x` over its leading axis if it has more axes than `like`, e.g. to
+/// reduce the cotangent of an operand which a batched op broadcast.
+pub fn sum_batch_op<T: Float>(x: TensorRef<T>, like: TensorRef<T>) -> TensorRef<T> {
+  let x_ = x.clone();
+  // Built as a view op, so that an `x` which is left alone is not copied.
+  _view_thunk("sum_batch", vec![x_, like], |xs| {
+    let (x, like) = (xs[0], xs[1]);
+    if x.ndim() <= like.ndim() {
+      return x.view();
+    }
+    let x = x.contiguous();
+    let inner = x.len() / x.shape()[0].max(1);
+    let mut y = vec![T::zero(); inner];
+    for (i, &v) in x.data().iter().enumerate() {
+      y[i % inner] = y[i % inner] + v;
+    }
+    Tensor::new(x.shape()[1 ..].to_vec(), y)
+  }, tensor_adjoint(move |_y, dy, sink| {
+    sink.put_adj(&x, _unsum_batch_op(dy, x.clone()));
+  }))._put_obj()
+}
+
+/// Broadcasts `x` to the shape of `like` without copying it; the reverse of
+/// `sum_batch_op`, which leaves `x` alone if it already has that shape.
+fn _unsum_batch_op<T: Float>(x: TensorRef<T>, like: TensorRef<T>) -> TensorRef<T> {
+  let x_ = x.clone();
+  _view_thunk("unsum_batch", vec![x_, like], |xs| xs[0].broadcast_view(xs[1].shape().to_vec()), tensor_adjoint(move |_y, dy, sink| {
+    sink.put_adj(&x, sum_batch_op(dy, x.clone()));
+  }))._put_obj()
+}
+
+/// Moves axis `src` of `x` to position `dst`.
+pub fn move_axis_op<T: Float>(x: TensorRef<T>, src: usize, dst: usize) -> TensorRef<T> {
+  let x_ = x.clone();
+  tensor_op("move_axis", vec![x_], move |xs| xs[0].move_axis(src, dst), tensor_adjoint(move |_y, dy, sink| {
+    sink.put_adj(&x, move_axis_op(dy, dst, src));
+  }))
+}
+
+/// Builds an op whose output is a view aliasing the buffer of its input,
+/// so unlike `tensor_op` its inputs are not made contiguous.
+fn _view_thunk<T, F>(name: &'static str, xs: Vec<TensorRef<T>>, f: F, adjoint: TensorAdjoint<T>) -> Thunk<Tensor<T>>
+where T: Float, F: Fn(&[&Tensor<T>]) -> Tensor<T> + 'static {
+  MapOp::build_thunk(name, xs, f, adjoint, Some(Arc::new(|y: &Tensor<T>| y.nbytes())))
+}
+
+/// Reshapes `x` without copying it, unless `x` is itself a strided view.
+pub fn reshape_op<T: Float>(x: TensorRef<T>, shape: Vec<usize>) -> TensorRef<T> {
+  let x_ = x.clone();
+  _view_thunk("reshape", vec![x_], move |xs| xs[0].reshape(shape.clone()), tensor_adjoint(move |_y, dy, sink| {
+    sink.put_adj(&x, _reshape_like_op(dy, x.clone()));
+  }))._put_obj()
+}
+
+fn _reshape_like_op<T: Float>(x: TensorRef<T>, like: TensorRef<T>) -> TensorRef<T> {
+  let x_ = x.clone();
+  _view_thunk("reshape_like", vec![x_, like], |xs| xs[0].reshape(xs[1].shape().to_vec()), tensor_adjoint(move |_y, dy, sink| {
+    sink.put_adj(&x, _reshape_like_op(dy, x.clone()));
+  }))._put_obj()
+}
+
+/// Swaps the last two axes of `x` without copying it.
+pub fn transpose_view_op<T: Float>(x: TensorRef<T>) -> TensorRef<T> {
+  let batch = _map_batch_rule(vec![x.clone()], |xs| transpose_view_op(xs[0].clone()));
+  _view_thunk("transpose_view", vec![x.clone()], |xs| xs[0].transpose_view(), tensor_adjoint(move |_y, dy, sink| {
+    sink.put_adj(&x, transpose_view_op(dy));
+  })).with_batch(batch)._put_obj()
+}
+
+/// Broadcasts `x` to `shape` without copying it.
+pub fn broadcast_op<T: Float>(x: TensorRef<T>, shape: Vec<usize>) -> TensorRef<T> {
+  let x_ = x.clone();
+  let shape_ = shape.clone();
+  _view_thunk("broadcast", vec![x_], move |xs| xs[0].broadcast_view(shape_.clone()), tensor_adjoint(move |_y, dy, sink| {
+    sink.put_adj(&x, _sum_to_op(dy, x.clone(), shape.clone()));
+  }))._put_obj()
+}
+
+fn _sum_to_op<T: Float>(x: TensorRef<T>, like: TensorRef<T>, shape: Vec<usize>) -> TensorRef<T> {
+  let x_ = x.clone();
+  tensor_op("sum_to", vec![x_, like], |xs| xs[0].sum_to(xs[1].shape().to_vec()), tensor_adjoint(move |_y, dy, sink| {
+    sink.put_adj(&x, broadcast_op(dy, shape.clone()));
+  }))
+}
+
+/// Sums over all but the last axis.
+pub fn sum_rows_op<T: Float>(x: TensorRef<T>) -> TensorRef<T> {
+  let x_ = x.clone();
+  tensor_op("sum_rows", vec![x_], |xs| {
+    let x = xs[0];
+    let (rows, cols) = x.rows_cols();
+    let mut y = vec![T::zero(); cols];
+    for r in 0 .. rows {
+      for c in 0 .. cols {
+        y[c] = y[c] + x.data()[r * cols + c];
+      }
+    }
+    Tensor::new(vec![cols], y)
+  }, tensor_adjoint(move |_y, dy, sink| {
+    sink.put_adj(&x, broadcast_rows_op(dy, x.clone()));
+  }))
+}
+
+/// Repeats the vector `b` along all but the last axis of `like`.
+pub fn broadcast_rows_op<T: Float>(b: TensorRef<T>, like: TensorRef<T>) -> TensorRef<T> {
+  let b_ = b.clone();
+  // Only `like` may be batched; its extra leading axis just adds rows.
+  let batch: Arc<Fn(TensorRef<T>, &mut Batcher) -> Result<(), EvalError>> = {
+    let (b, like) = (b.clone(), like.clone());
+    Arc::new(move |y, batcher| {
+      if batcher.is_batched(&b) {
+        return Err(EvalError::MissingBatchRule(y._stable()));
+      }
+      let like_b = batcher.get_or_broadcast(&like)?;
+      batcher.put(&y, broadcast_rows_op(b.clone(), like_b));
+      Ok(())
+    })
+  };
+  tensor_thunk("broadcast_rows", vec![b_, like], |xs| {
+    let (b, like) = (xs[0], xs[1]);
+    let (rows, cols) = like.rows_cols();
+    assert_eq!(cols, b.len(), "broadcast_rows: shape mismatch");
+    let mut y = Vec::with_capacity(rows * cols);
+    for _ in 0 .. rows {
+      y.extend_from_slice(b.data());
+    }
+    Tensor::new(like.shape().to_vec(), y)
+  }, tensor_adjoint(move |_y, dy, sink| {
+    sink.put_adj(&b, sum_rows_op(dy));
+  })).with_batch(batch)._put_obj()
+}
+
+pub fn bias_add_op<T: Float>(x: TensorRef<T>, b: TensorRef<T>) -> TensorRef<T> {
+  add_op(x.clone(), broadcast_rows_op(b, x))
+}
+
+pub fn relu_op<T: Float>(x: TensorRef<T>) -> TensorRef<T> {
+  let x_ = x.clone();
+  let batch = _map_batch_rule(vec![x.clone()], |xs| relu_op(xs[0].clone()));
+  tensor_thunk("relu", vec![x_], |xs| xs[0].map(|&x| if x > T::zero() { x } else { T::zero() }), tensor_adjoint(move |_y, dy, sink| {
+    // The mask is piecewise constant, so it has no cotangent of its own.
+    let mask = tensor_op("relu_mask", vec![x.clone()], |xs| {
+      xs[0].map(|&x| if x > T::zero() { T::one() } else { T::zero() })
+    }, None);
+    sink.put_adj(&x, mul_op(dy, mask));
+  })).with_batch(batch)._put_obj()
+}
+
+pub fn tanh_op<T: Float>(x: TensorRef<T>) -> TensorRef<T> {
+  let x_ = x.clone();
+  let batch = _map_batch_rule(vec![x.clone()], |xs| tanh_op(xs[0].clone()));
+  tensor_thunk("tanh", vec![x_], |xs| xs[0].map(|&x| x.tanh()), tensor_adjoint(move |y, dy, sink| {
+    // `dx = dy (1 - y^2)`, built from differentiable ops.
+    sink.put_adj(&x, mul_op(dy, _affine_op(mul_op(y.clone(), y), -1.0, 1.0)));
+  })).with_batch(batch)._put_obj()
+}
+
+pub fn sigmoid_op<T: Float>(x: TensorRef<T>) -> TensorRef<T> {
+  let x_ = x.clone();
+  let batch = _map_batch_rule(vec![x.clone()], |xs| sigmoid_op(xs[0].clone()));
+  tensor_thunk("sigmoid", vec![x_], |xs| {
+    let one = T::one();
+    xs[0].map(|&x| one / (one + (-x).exp()))
+  }, tensor_adjoint(move |y, dy, sink| {
+    // `dx = dy y (1 - y)`.
+    sink.put_adj(&x, mul_op(dy, mul_op(y.clone(), _affine_op(y, -1.0, 1.0))));
+  })).with_batch(batch)._put_obj()
+}
+
+fn _softmax<T: Float>(x: &Tensor<T>) -> Tensor<T> {
+  let (rows, cols) = x.rows_cols();
+  let mut y = x.clone();
+  for r in 0 .. rows {
+    let row = &mut y.data_mut()[r * cols .. (r + 1) * cols];
+    let max = row.iter().fold(row[0], |m, &v| if v > m { v } else { m });
+    let mut sum = T::zero();
+    for v in row.iter_mut() {
+      *v = (*v - max).exp();
+      sum = sum + *v;
+    }
+    for v in row.iter_mut() {
+      *v = *v / sum;
+    }
+  }
+  y
+}
+
+fn _log_softmax<T: Float>(x: &Tensor<T>) -> Tensor<T> {
+  let (rows, cols) = x.rows_cols();
+  let mut y = x.clone();
+  for r in 0 .. rows {
+    let row = &mut y.data_mut()[r * cols .. (r + 1) * cols];
+    let max = row.iter().fold(row[0], |m, &v| if v > m { v } else { m });
+    let sum = row.iter().fold(T::zero(), |s, &v| s + (v - max).exp());
+    let lse = max + sum.ln();
+    for v in row.iter_mut() {
+      *v = *v - lse;
+    }
+  }
+  y
+}
+
+/// Softmax over the last axis.
+pub fn softmax_op<T: Float>(x: TensorRef<T>) -> TensorRef<T> {
+  let x_ = x.clone();
+  let batch = _map_batch_rule(vec![x.clone()], |xs| softmax_op(xs[0].clone()));
+  tensor_thunk("softmax", vec![x_], |xs| _softmax(xs[0]), tensor_adjoint(move |y, dy, sink| {
+    // `dx = y (dy - rowsum(y dy))`.
+    let dot = _row_sum_op(mul_op(y.clone(), dy.clone()));
+    sink.put_adj(&x, mul_op(y, sub_op(dy, dot)));
+  })).with_batch(batch)._put_obj()
+}
+
+/// Log-softmax over the last axis.
+fn _log_softmax_op<T: Float>(x: TensorRef<T>) -> TensorRef<T> {
+  let x_ = x.clone();
+  tensor_op("log_softmax", vec![x_], |xs| _log_softmax(xs[0]), tensor_adjoint(move |_y, dy, sink| {
+    // `dx = dy - softmax(x) rowsum(dy)`.
+    sink.put_adj(&x, sub_op(dy.clone(), mul_op(softmax_op(x.clone()), _row_sum_op(dy))));
+  }))
+}
+
+/// `a x + b`, elementwise.
+fn _affine_op<T: Float>(x: TensorRef<T>, a: f64, b: f64) -> TensorRef<T> {
+  let x_ = x.clone();
+  tensor_op("affine", vec![x_], move |xs| {
+    let (a, b) = (T::from_f64(a), T::from_f64(b));
+    xs[0].map(|&x| a * x + b)
+  }, tensor_adjoint(move |_y, dy, sink| {
+    sink.put_adj(&x, _affine_op(dy, a, 0.0));
+  }))
+}
+
+fn _ln_op<T: Float>(x: TensorRef<T>) -> TensorRef<T> {
+  let x_ = x.clone();
+  tensor_op("ln", vec![x_], |xs| xs[0].map(|&x| x.ln()), tensor_adjoint(move |_y, dy, sink| {
+    sink.put_adj(&x, div_op(dy, x.clone()));
+  }))
+}
+
+/// The sum over the last axis, repeated along it; this is self-adjoint.
+fn _row_sum_op<T: Float>(x: TensorRef<T>) -> TensorRef<T> {
+  let x_ = x.clone();
+  tensor_op("row_sum", vec![x_], |xs| {
+    let (rows, cols) = xs[0].rows_cols();
+    let mut y = xs[0].clone();
+    for r in 0 .. rows {
+      let row = &mut y.data_mut()[r * cols .. (r + 1) * cols];
+      let sum = row.iter().fold(T::zero(), |s, &v| s + v);
+      for v in row.iter_mut() {
+        *v = sum;
+      }
+    }
+    y
+  }, tensor_adjoint(move |_y, dy, sink| {
+    sink.put_adj(&x, _row_sum_op(dy));
+  }))
+}
+
+/// `x` scaled by the scalar tensor `s`.
+fn _scale_op<T: Float>(x: TensorRef<T>, s: TensorRef<T>) -> TensorRef<T> {
+  let (x_, s_) = (x.clone(), s.clone());
+  tensor_op("scale", vec![x_, s_], |xs| {
+    let s = xs[1].data()[0];
+    xs[0].map(|&x| x * s)
+  }, tensor_adjoint(move |_y, dy, sink| {
+    sink.put_adj(&x, _scale_op(dy.clone(), s.clone()));
+    sink.put_adj(&s, _dot_op(dy, x.clone()));
+  }))
+}
+
+/// The sum of the elementwise product of `a` and `b`, as a scalar tensor.
+fn _dot_op<T: Float>(a: TensorRef<T>, b: TensorRef<T>) -> TensorRef<T> {
+  let (a_, b_) = (a.clone(), b.clone());
+  tensor_op("dot", vec![a_, b_], |xs| Tensor::scalar(xs[0].zip_map(xs[1], |&a, &b| a * b).sum()), tensor_adjoint(move |_y, dy, sink| {
+    sink.put_adj(&a, _scale_op(b.clone(), dy.clone()));
+    sink.put_adj(&b, _scale_op(a.clone(), dy));
+  }))
+}
+
+/// `k / n` as a scalar tensor, where `n` is the number of elements of `x`,
+/// or its batch size if `batch`. It only depends on the shape of `x`, so it
+/// has no cotangent.
+fn _inv_count_op<T: Float>(x: TensorRef<T>, k: f64, batch: bool) -> TensorRef<T> {
+  tensor_op("inv_count", vec![x], move |xs| {
+    let n = if batch { _batch_size(xs[0]) } else { xs[0].len() };
+    Tensor::scalar(T::from_f64(k / n as f64))
+  }, None)
+}
+
+/// Mean squared error over all elements, as a scalar tensor.
+pub fn mse_op<T: Float>(pred: TensorRef<T>, target: TensorRef<T>) -> TensorRef<T> {
+  let (p_, t_) = (pred.clone(), target.clone());
+  tensor_op("mse", vec![p_, t_], |xs| {
+    let n = T::from_f64(xs[0].len() as f64);
+    Tensor::scalar(xs[0].zip_map(xs[1], |&p, &t| (p - t) * (p - t)).sum() / n)
+  }, tensor_adjoint(move |_y, dy, sink| {
+    // `dp = 2 dy (p - t) / n`.
+    let c = mul_op(dy, _inv_count_op(pred.clone(), 2.0, false));
+    let dp = _scale_op(sub_op(pred.clone(), target.clone()), c);
+    sink.put_adj(&pred, dp.clone());
+    sink.put_adj(&target, neg_op(dp));
+  }))
+}
+
+/// Cross-entropy `-sum(target * ln(prob))`, averaged over the batch.
+pub fn cross_entropy_op<T: Float>(prob: TensorRef<T>, target: TensorRef<T>) -> TensorRef<T> {
+  let (p_, t_) = (prob.clone(), target.clone());
+  tensor_op("cross_entropy", vec![p_, t_], |xs| {
+    let n = T::from_f64(_batch_size(xs[0]) as f64);
+    Tensor::scalar(-xs[0].zip_map(xs[1], |&p, &t| t * p.ln()).sum() / n)
+  }, tensor_adjoint(move |_y, dy, sink| {
+    // `dp = -c t / p` and `dt = -c ln(p)`, with `c = dy / batch`.
+    let c = mul_op(dy, _inv_count_op(prob.clone(), -1.0, true));
+    sink.put_adj(&prob, _scale_op(div_op(target.clone(), prob.clone()), c.clone()));
+    sink.put_adj(&target, _scale_op(_ln_op(prob.clone()), c));
+  }))
+}
+
+/// Fused softmax and cross-entropy over the last axis of `logits`, averaged
+/// over the batch; numerically stabler than composing the two ops.
+pub fn softmax_cross_entropy_op<T: Float>(logits: TensorRef<T>, target: TensorRef<T>) -> TensorRef<T> {
+  let (l_, t_) = (logits.clone(), target.clone());
+  tensor_op("softmax_cross_entropy", vec![l_, t_], |xs| {
+    let n = T::from_f64(_batch_size(xs[0]) as f64);
+    Tensor::scalar(-_log_softmax(xs[0]).zip_map(xs[1], |&lp, &t| t * lp).sum() / n)
+  }, tensor_adjoint(move |_y, dy, sink| {
+    // `dl = c (softmax(l) rowsum(t) - t)` and `dt = -c log_softmax(l)`, with
+    // `c = dy / batch`.
+    let c = mul_op(dy, _inv_count_op(logits.clone(), 1.0, true));
+    let mass = _row_sum_op(target.clone());
+    let dl = sub_op(mul_op(softmax_op(logits.clone()), mass), target.clone());
+    sink.put_adj(&logits, _scale_op(dl, c.clone()));
+    sink.put_adj(&target, _scale_op(_log_softmax_op(logits.clone()), neg_op(c)));
+  }))
+}
+
+/// A small deterministic generator (SplitMix64) for parameter initialization.
+struct InitRng {
+  state:    u64,
+}
+
+impl InitRng {
+  fn new(seed: u64) -> InitRng {
+    InitRng{state: seed}
+  }
+
+  fn next_u64(&mut self) -> u64 {
+    self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    _mix64(self.state)
+  }
+
+  fn next_f64(&mut self) -> f64 {
+    (self.next_u64() >> 11) as f64 / (1_u64 << 53) as f64
+  }
+}
+
+pub fn zeros_init<T: Float>(shape: Vec<usize>) -> Tensor<T> {
+  Tensor::zeros(shape)
+}
+
+pub fn uniform_init<T: Float>(shape: Vec<usize>, lo: f64, hi: f64, seed: u64) -> Tensor<T> {
+  let mut rng = InitRng::new(seed);
+  let len = shape.iter().product();
+  let data = (0 .. len).map(|_| T::from_f64(lo + (hi - lo) * rng.next_f64())).collect();
+  Tensor::new(shape, data)
+}
+
+pub fn normal_init<T: Float>(shape: Vec<usize>, mean: f64, std: f64, seed: u64) -> Tensor<T> {
+  let mut rng = InitRng::new(seed);
+  let len = shape.iter().product();
+  let data = (0 .. len).map(|_| {
+    // Box-Muller; `1 - u` keeps the logarithm finite.
+    let u1 = 1.0 - rng.next_f64();
+    let u2 = rng.next_f64();
+    T::from_f64(mean + std * (-2.0 * u1.ln()).sqrt() * (2.0 * PI * u2).cos())
+  }).collect();
+  Tensor::new(shape, data)
+}
+
+/// Glorot/Xavier uniform initialization of a `[fan_in, fan_out]` matrix.
+pub fn glorot_uniform_init<T: Float>(fan_in: usize, fan_out: usize, seed: u64) -> Tensor<T> {
+  let a = (6.0 / (fan_in + fan_out) as f64).sqrt();
+  uniform_init(vec![fan_in, fan_out], -a, a, seed)
+}
+
+/// He/Kaiming normal initialization of a `[fan_in, fan_out]` matrix, suited
+/// to ReLU layers.
+pub fn he_normal_init<T: Float>(fan_in: usize, fan_out: usize, seed: u64) -> Tensor<T> {
+  normal_init(vec![fan_in, fan_out], 0.0, (2.0 / fan_in as f64).sqrt(), seed)
+}
+
+/// A dense layer `y = x w + b` over `[batch, in_dim]` inputs.
+pub struct Linear<T> {
+  pub w:    TensorRef<T>,
+  pub b:    TensorRef<T>,
+}
+
+impl<T: Float> Linear<T> {
+  pub fn new(in_dim: usize, out_dim: usize, seed: u64) -> Linear<T> {
+    Linear::with_init(glorot_uniform_init(in_dim, out_dim, seed), zeros_init(vec![out_dim]))
+  }
+
+  pub fn with_init(w: Tensor<T>, b: Tensor<T>) -> Linear<T> {
+    assert_eq!(2, w.ndim(), "Linear: weights must be a matrix");
+    assert_eq!(&w.shape()[1 ..], b.shape(), "Linear: bias shape mismatch");
+    Linear{
+      w:    variable_op(w),
+      b:    variable_op(b),
+    }
+  }
+
+  pub fn params(&self) -> Vec<TensorRef<T>> {
+    vec![self.w.clone(), self.b.clone()]
+  }
+
+  pub fn apply(&self, x: TensorRef<T>) -> TensorRef<T> {
+    bias_add_op(matmul_op(x, self.w.clone()), self.b.clone())
+  }
+}
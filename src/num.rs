@@ -0,0 +1,167 @@
+use std::fmt::{Debug};
+use std::ops::{Add, Div, Mul, Neg, Sub};
+
+/// Values with addition, subtraction and multiplication. Arithmetic ops are
+/// written against this trait (and `Field` for division), so that one op
+/// library serves integers, floats, complex numbers and tensors of those.
+pub trait Ring: Clone + Default + Debug + Add<Output=Self> + Sub<Output=Self> + Mul<Output=Self> + 'static {
+  fn zero() -> Self;
+  fn one() -> Self;
+
+  /// Complex conjugate; the identity on real types. Adjoints conjugate the
+  /// forward values they multiply with, so that they remain correct for
+  /// complex values.
+  fn conj(self) -> Self {
+    self
+  }
+
+  /// Sums `self` down to the shape of `like`, undoing the broadcast of an
+  /// operand in a binary op; the identity on scalar types.
+  fn reduce_like(self, _like: &Self) -> Self {
+    self
+  }
+
+  /// Broadcasts `self` up to the shape of `like`; the adjoint of
+  /// `reduce_like`.
+  fn expand_like(self, _like: &Self) -> Self {
+    self
+  }
+}
+
+pub trait Field: Ring + Div<Output=Self> + Neg<Output=Self> {
+  fn recip(self) -> Self {
+    Self::one() / self
+  }
+}
+
+/// Real floating-point scalars.
+pub trait Float: Field + Copy + PartialOrd {
+  fn from_f64(x: f64) -> Self;
+  fn to_f64(self) -> f64;
+  fn abs(self) -> Self;
+  fn sqrt(self) -> Self;
+  fn exp(self) -> Self;
+  fn ln(self) -> Self;
+  fn tanh(self) -> Self;
+  fn powi(self, n: i32) -> Self;
+}
+
+macro_rules! impl_int_ring {
+  ($($ty:ty),*) => {$(
+    impl Ring for $ty {
+      fn zero() -> $ty { 0 }
+      fn one() -> $ty { 1 }
+    }
+  )*};
+}
+
+impl_int_ring!(i8, i16, i32, i64, isize, u8, u16, u32, u64, usize);
+
+macro_rules! impl_float {
+  ($($ty:ident),*) => {$(
+    impl Ring for $ty {
+      fn zero() -> $ty { 0.0 }
+      fn one() -> $ty { 1.0 }
+    }
+
+    impl Field for $ty {
+    }
+
+    impl Float for $ty {
+      fn from_f64(x: f64) -> $ty { x as $ty }
+      fn to_f64(self) -> f64 { self as f64 }
+      fn abs(self) -> $ty { $ty::abs(self) }
+      fn sqrt(self) -> $ty { $ty::sqrt(self) }
+      fn exp(self) -> $ty { $ty::exp(self) }
+      fn ln(self) -> $ty { $ty::ln(self) }
+      fn tanh(self) -> $ty { $ty::tanh(self) }
+      fn powi(self, n: i32) -> $ty { $ty::powi(self, n) }
+    }
+  )*};
+}
+
+impl_float!(f32, f64);
+
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct Complex<F> {
+  pub re:   F,
+  pub im:   F,
+}
+
+impl<F> Complex<F> {
+  pub fn new(re: F, im: F) -> Complex<F> {
+    Complex{re, im}
+  }
+}
+
+impl<F: Float> Complex<F> {
+  pub fn norm_sqr(&self) -> F {
+    self.re * self.re + self.im * self.im
+  }
+
+  pub fn norm(&self) -> F {
+    self.norm_sqr().sqrt()
+  }
+}
+
+impl<F: Float> Add for Complex<F> {
+  type Output = Complex<F>;
+
+  fn add(self, other: Complex<F>) -> Complex<F> {
+    Complex::new(self.re + other.re, self.im + other.im)
+  }
+}
+
+impl<F: Float> Sub for Complex<F> {
+  type Output = Complex<F>;
+
+  fn sub(self, other: Complex<F>) -> Complex<F> {
+    Complex::new(self.re - other.re, self.im - other.im)
+  }
+}
+
+impl<F: Float> Mul for Complex<F> {
+  type Output = Complex<F>;
+
+  fn mul(self, other: Complex<F>) -> Complex<F> {
+    Complex::new(
+        self.re * other.re - self.im * other.im,
+        self.re * other.im + self.im * other.re)
+  }
+}
+
+impl<F: Float> Div for Complex<F> {
+  type Output = Complex<F>;
+
+  fn div(self, other: Complex<F>) -> Complex<F> {
+    let d = other.norm_sqr();
+    Complex::new(
+        (self.re * other.re + self.im * other.im) / d,
+        (self.im * other.re - self.re * other.im) / d)
+  }
+}
+
+impl<F: Float> Neg for Complex<F> {
+  type Output = Complex<F>;
+
+  fn neg(self) -> Complex<F> {
+    Complex::new(-self.re, -self.im)
+  }
+}
+
+impl<F: Float> Ring for Complex<F> {
+  fn zero() -> Complex<F> {
+    Complex::new(F::zero(), F::zero())
+  }
+
+  fn one() -> Complex<F> {
+    Complex::new(F::one(), F::zero())
+  }
+
+  fn conj(self) -> Complex<F> {
+    Complex::new(self.re, -self.im)
+  }
+}
+
+impl<F: Float> Field for Complex<F> {
+}
@@ -0,0 +1,351 @@
+use experimental::rt1::*;
+use num::{Float, Ring};
+use tensor::{Tensor};
+
+use std::borrow::{Cow};
+use std::fmt::{Debug};
+use std::slice;
+use std::sync::{Arc};
+
+/// Values which optimizers can update elementwise.
+pub trait OptimParam: Clone + Debug + 'static {
+  type Scalar: Float;
+
+  fn flat(&self) -> &[Self::Scalar];
+  fn flat_mut(&mut self) -> &mut [Self::Scalar];
+
+  /// `self` in a layout where `flat` is valid, e.g. a contiguous copy of a
+  /// tensor view.
+  fn dense(&self) -> Cow<Self> {
+    Cow::Borrowed(self)
+  }
+
+  fn zeros_like(&self) -> Self {
+    let mut z = self.clone();
+    for x in z.flat_mut().iter_mut() {
+      *x = Self::Scalar::zero();
+    }
+    z
+  }
+}
+
+impl OptimParam for f32 {
+  type Scalar = f32;
+
+  fn flat(&self) -> &[f32] { slice::from_ref(self) }
+  fn flat_mut(&mut self) -> &mut [f32] { slice::from_mut(self) }
+}
+
+impl OptimParam for f64 {
+  type Scalar = f64;
+
+  fn flat(&self) -> &[f64] { slice::from_ref(self) }
+  fn flat_mut(&mut self) -> &mut [f64] { slice::from_mut(self) }
+}
+
+impl<T: Float> OptimParam for Tensor<T> {
+  type Scalar = T;
+
+  fn flat(&self) -> &[T] { self.data() }
+  fn flat_mut(&mut self) -> &mut [T] { self.data_mut() }
+  fn dense(&self) -> Cow<Tensor<T>> { self.contiguous() }
+}
+
+pub trait Optimizer {
+  /// Updates the parameters in place using their cotangents in `sink`,
+  /// evaluated in `txn`. Parameters without a cotangent are left unchanged.
+  fn step(&mut self, sink: &Sink, txn: Txn) -> Result<(), EvalError>;
+}
+
+/// Builds a learning-rate schedule as a thunk of the step counter.
+pub fn schedule_op<S: Float, F: Fn(u64) -> S + 'static>(step: ThunkRef<u64>, f: F) -> ThunkRef<S> {
+  let code = ThunkCode{
+    name:     "schedule",
+    entry:    {
+      let step = step.clone();
+      Some(Arc::new(move |txn, y| {
+        let step = step._get_obj();
+        let step = match step.try_get(txn) {
+          Err(_) => return false,
+          Ok(step) => step,
+        };
+        let mut y = y.get_mut(txn);
+        *y = (f)(*step);
+        true
+      }))
+    },
+    adjoint:  None,
+    batch:    None,
+  };
+  let thunk = Thunk::new(DataCode{
+    alloc:    Some(Arc::new(|_txn| S::zero())),
+    nbytes:   None,
+  }, vec![step._into_tag()], code);
+  thunk._put_obj()
+}
+
+/// `lr0 * gamma^(step / period)`.
+pub fn step_decay_op<S: Float>(step: ThunkRef<u64>, lr0: S, gamma: S, period: u64) -> ThunkRef<S> {
+  schedule_op(step, move |t| lr0 * gamma.powi((t / period) as i32))
+}
+
+/// `lr0 * gamma^step`.
+pub fn exp_decay_op<S: Float>(step: ThunkRef<u64>, lr0: S, gamma: S) -> ThunkRef<S> {
+  schedule_op(step, move |t| lr0 * gamma.powi(t as i32))
+}
+
+pub fn step_counter() -> ThunkRef<u64> {
+  variable_op(0)
+}
+
+fn _state_data<V: OptimParam>(param: &ThunkRef<V>) -> STag {
+  let param = param._get_data();
+  let data = Data::new(DataCode{
+    alloc:    Some(Arc::new(move |txn| param.get(txn).zeros_like())),
+    nbytes:   None,
+  });
+  data._put_obj()
+}
+
+fn _force_grads<V: OptimParam, S: Float>(params: &[ThunkRef<V>], lr: &ThunkRef<S>, sink: &Sink, txn: Txn) -> Result<Vec<Option<ThunkRef<V>>>, EvalError> {
+  let grads: Vec<_> = params.iter().map(|p| sink.get_adj(p)).collect();
+  let mut roots = TagVec::new();
+  roots.push(lr);
+  for g in grads.iter() {
+    if let Some(ref g) = *g {
+      roots.push(g);
+    }
+  }
+  for result in roots.force_all(txn) {
+    result?;
+  }
+  Ok(grads)
+}
+
+fn _check_len<V: OptimParam>(p: &V, g: &V, gtag: &ThunkRef<V>) -> Result<(), EvalError> {
+  if p.flat().len() != g.flat().len() {
+    return Err(EvalError::ShapeMismatch(gtag._stable()));
+  }
+  Ok(())
+}
+
+fn _incr_step(step: &ThunkRef<u64>, txn: Txn) -> u64 {
+  let data = step._get_data();
+  let mut t = data.get_mut(txn);
+  *t += 1;
+  *t
+}
+
+pub struct Sgd<V: OptimParam> {
+  params:   Vec<ThunkRef<V>>,
+  lr:       ThunkRef<V::Scalar>,
+  step:     ThunkRef<u64>,
+}
+
+impl<V: OptimParam> Sgd<V> {
+  pub fn new(params: Vec<ThunkRef<V>>, lr: ThunkRef<V::Scalar>) -> Sgd<V> {
+    Sgd{
+      params:   params,
+      lr:       lr,
+      step:     step_counter(),
+    }
+  }
+
+  /// Uses `step` (e.g. shared with a learning-rate schedule) as the step
+  /// counter.
+  pub fn with_step_counter(mut self, step: ThunkRef<u64>) -> Sgd<V> {
+    self.step = step;
+    self
+  }
+
+  pub fn step_counter(&self) -> ThunkRef<u64> {
+    self.step.clone()
+  }
+}
+
+impl<V: OptimParam> Optimizer for Sgd<V> {
+  fn step(&mut self, sink: &Sink, txn: Txn) -> Result<(), EvalError> {
+    let grads = _force_grads(&self.params, &self.lr, sink, txn)?;
+    let lr = self.lr._get_obj();
+    let lr = *lr.get(txn);
+    for (p, g) in self.params.iter().zip(grads.iter()) {
+      let gtag = match *g {
+        None => continue,
+        Some(ref g) => g,
+      };
+      let g = gtag._get_obj();
+      let g = g.get(txn);
+      let g = g.dense();
+      let p = p._get_data();
+      let mut p = p.get_mut(txn);
+      _check_len(&*p, &*g, gtag)?;
+      for (w, &dw) in p.flat_mut().iter_mut().zip(g.flat().iter()) {
+        *w = *w - lr * dw;
+      }
+    }
+    _incr_step(&self.step, txn);
+    Ok(())
+  }
+}
+
+pub struct Momentum<V: OptimParam> {
+  params:   Vec<ThunkRef<V>>,
+  lr:       ThunkRef<V::Scalar>,
+  mu:       V::Scalar,
+  step:     ThunkRef<u64>,
+  bufs:     Vec<STag>,
+}
+
+impl<V: OptimParam> Momentum<V> {
+  pub fn new(params: Vec<ThunkRef<V>>, lr: ThunkRef<V::Scalar>, mu: V::Scalar) -> Momentum<V> {
+    let bufs = params.iter().map(|p| _state_data(p)).collect();
+    Momentum{
+      params:   params,
+      lr:       lr,
+      mu:       mu,
+      step:     step_counter(),
+      bufs:     bufs,
+    }
+  }
+
+  pub fn with_step_counter(mut self, step: ThunkRef<u64>) -> Momentum<V> {
+    self.step = step;
+    self
+  }
+
+  pub fn step_counter(&self) -> ThunkRef<u64> {
+    self.step.clone()
+  }
+}
+
+impl<V: OptimParam> Optimizer for Momentum<V> {
+  fn step(&mut self, sink: &Sink, txn: Txn) -> Result<(), EvalError> {
+    let grads = _force_grads(&self.params, &self.lr, sink, txn)?;
+    let lr = self.lr._get_obj();
+    let lr = *lr.get(txn);
+    let mu = self.mu;
+    for ((p, g), &buf) in self.params.iter().zip(grads.iter()).zip(self.bufs.iter()) {
+      let gtag = match *g {
+        None => continue,
+        Some(ref g) => g,
+      };
+      let g = gtag._get_obj();
+      let g = g.get(txn);
+      let g = g.dense();
+      let buf = LDataRef::<V>::_from_stag(buf)._get_obj();
+      let mut buf = buf.get_mut(txn);
+      let p = p._get_data();
+      let mut p = p.get_mut(txn);
+      _check_len(&*p, &*g, gtag)?;
+      for ((w, b), &dw) in p.flat_mut().iter_mut().zip(buf.flat_mut().iter_mut()).zip(g.flat().iter()) {
+        *b = mu * *b + dw;
+        *w = *w - lr * *b;
+      }
+    }
+    _incr_step(&self.step, txn);
+    Ok(())
+  }
+}
+
+pub struct Adam<V: OptimParam> {
+  params:   Vec<ThunkRef<V>>,
+  lr:       ThunkRef<V::Scalar>,
+  beta1:    V::Scalar,
+  beta2:    V::Scalar,
+  eps:      V::Scalar,
+  step:     ThunkRef<u64>,
+  // Adam keeps its own count of updates for bias correction, separate from
+  // the (possibly shared) step counter.
+  count:    STag,
+  moments1: Vec<STag>,
+  moments2: Vec<STag>,
+}
+
+impl<V: OptimParam> Adam<V> {
+  pub fn new(params: Vec<ThunkRef<V>>, lr: ThunkRef<V::Scalar>) -> Adam<V> {
+    let moments1 = params.iter().map(|p| _state_data(p)).collect();
+    let moments2 = params.iter().map(|p| _state_data(p)).collect();
+    let count = Data::new(DataCode{
+      alloc:    Some(Arc::new(|_txn| 0_i32)),
+      nbytes:   None,
+    });
+    Adam{
+      params:   params,
+      lr:       lr,
+      beta1:    V::Scalar::from_f64(0.9),
+      beta2:    V::Scalar::from_f64(0.999),
+      eps:      V::Scalar::from_f64(1.0e-8),
+      step:     step_counter(),
+      count:    count._put_obj(),
+      moments1: moments1,
+      moments2: moments2,
+    }
+  }
+
+  pub fn with_betas(mut self, beta1: V::Scalar, beta2: V::Scalar) -> Adam<V> {
+    self.beta1 = beta1;
+    self.beta2 = beta2;
+    self
+  }
+
+  pub fn with_eps(mut self, eps: V::Scalar) -> Adam<V> {
+    self.eps = eps;
+    self
+  }
+
+  pub fn with_step_counter(mut self, step: ThunkRef<u64>) -> Adam<V> {
+    self.step = step;
+    self
+  }
+
+  pub fn step_counter(&self) -> ThunkRef<u64> {
+    self.step.clone()
+  }
+}
+
+impl<V: OptimParam> Optimizer for Adam<V> {
+  fn step(&mut self, sink: &Sink, txn: Txn) -> Result<(), EvalError> {
+    let grads = _force_grads(&self.params, &self.lr, sink, txn)?;
+    let lr = self.lr._get_obj();
+    let lr = *lr.get(txn);
+    let t = {
+      let count = LDataRef::<i32>::_from_stag(self.count)._get_obj();
+      let mut count = count.get_mut(txn);
+      *count += 1;
+      *count
+    };
+    let one = V::Scalar::one();
+    let (beta1, beta2, eps) = (self.beta1, self.beta2, self.eps);
+    let c1 = one - beta1.powi(t);
+    let c2 = one - beta2.powi(t);
+    for (((p, g), &m), &v) in self.params.iter().zip(grads.iter()).zip(self.moments1.iter()).zip(self.moments2.iter()) {
+      let gtag = match *g {
+        None => continue,
+        Some(ref g) => g,
+      };
+      let g = gtag._get_obj();
+      let g = g.get(txn);
+      let g = g.dense();
+      let m = LDataRef::<V>::_from_stag(m)._get_obj();
+      let mut m = m.get_mut(txn);
+      let v = LDataRef::<V>::_from_stag(v)._get_obj();
+      let mut v = v.get_mut(txn);
+      let p = p._get_data();
+      let mut p = p.get_mut(txn);
+      _check_len(&*p, &*g, gtag)?;
+      let elems = p.flat_mut().iter_mut()
+        .zip(m.flat_mut().iter_mut())
+        .zip(v.flat_mut().iter_mut())
+        .zip(g.flat().iter());
+      for (((w, m), v), &dw) in elems {
+        *m = beta1 * *m + (one - beta1) * dw;
+        *v = beta2 * *v + (one - beta2) * dw * dw;
+        let m_hat = *m / c1;
+        let v_hat = *v / c2;
+        *w = *w - lr * m_hat / (v_hat.sqrt() + eps);
+      }
+    }
+    _incr_step(&self.step, txn);
+    Ok(())
+  }
+}
@@ -0,0 +1,148 @@
+use experimental::rt1::*;
+use nn::{TensorRef};
+use num::{Float};
+use tensor::*;
+
+use std::cell::{Cell};
+use std::f64::consts::{PI};
+use std::sync::{Arc};
+
+thread_local! {
+  static GRAPH_SEED: Cell<u64> = Cell::new(0);
+}
+
+/// Sets the seed used by random ops built afterwards on this thread.
+pub fn set_graph_seed(seed: u64) {
+  GRAPH_SEED.with(|s| s.set(seed));
+}
+
+pub fn graph_seed() -> u64 {
+  GRAPH_SEED.with(|s| s.get())
+}
+
+/// The SplitMix64 finalizer, a bijective 64-bit mixer.
+pub fn _mix64(mut z: u64) -> u64 {
+  z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+  z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+  z ^ (z >> 31)
+}
+
+const PHILOX_M0: u32 = 0xd251_1f53;
+const PHILOX_M1: u32 = 0xcd9e_8d57;
+const PHILOX_W0: u32 = 0x9e37_79b9;
+const PHILOX_W1: u32 = 0xbb67_ae85;
+
+fn _mulhilo(a: u32, b: u32) -> (u32, u32) {
+  let p = (a as u64) * (b as u64);
+  ((p >> 32) as u32, p as u32)
+}
+
+/// Philox4x32-10 (Salmon et al., "Parallel random numbers: as easy as 1, 2,
+/// 3"), a counter-based generator: the output is a pure function of the key
+/// and the counter, so any element of a stream can be regenerated on demand.
+pub fn philox4x32(key: [u32; 2], ctr: [u32; 4]) -> [u32; 4] {
+  let (mut k0, mut k1) = (key[0], key[1]);
+  let mut c = ctr;
+  for _ in 0 .. 10 {
+    let (hi0, lo0) = _mulhilo(PHILOX_M0, c[0]);
+    let (hi1, lo1) = _mulhilo(PHILOX_M1, c[2]);
+    c = [hi1 ^ c[1] ^ k0, lo1, hi0 ^ c[3] ^ k1, lo0];
+    k0 = k0.wrapping_add(PHILOX_W0);
+    k1 = k1.wrapping_add(PHILOX_W1);
+  }
+  c
+}
+
+/// A stream of random numbers keyed by a seed, a thunk, and a transaction.
+pub struct CounterRng {
+  key:      [u32; 2],
+  txn:      u64,
+  ctr:      u64,
+  buf:      [u32; 4],
+  pos:      usize,
+}
+
+impl CounterRng {
+  pub fn new(seed: u64, stable: STag, txn: Txn) -> CounterRng {
+    let key = _mix64(seed ^ _mix64(stable._uid()));
+    CounterRng{
+      key:      [key as u32, (key >> 32) as u32],
+      txn:      txn._id(),
+      ctr:      0,
+      buf:      [0; 4],
+      pos:      4,
+    }
+  }
+
+  pub fn next_u32(&mut self) -> u32 {
+    if self.pos == 4 {
+      let ctr = [self.ctr as u32, (self.ctr >> 32) as u32, self.txn as u32, (self.txn >> 32) as u32];
+      self.buf = philox4x32(self.key, ctr);
+      self.ctr += 1;
+      self.pos = 0;
+    }
+    let x = self.buf[self.pos];
+    self.pos += 1;
+    x
+  }
+
+  /// Uniform on `[0, 1)` with 53 bits of precision.
+  pub fn next_f64(&mut self) -> f64 {
+    let hi = (self.next_u32() >> 5) as u64;
+    let lo = (self.next_u32() >> 6) as u64;
+    ((hi << 26) | lo) as f64 / (1_u64 << 53) as f64
+  }
+
+  pub fn next_normal(&mut self) -> f64 {
+    // Box-Muller; `1 - u` keeps the logarithm finite.
+    let u1 = 1.0 - self.next_f64();
+    let u2 = self.next_f64();
+    (-2.0 * u1.ln()).sqrt() * (2.0 * PI * u2).cos()
+  }
+}
+
+/// Builds a leaf thunk whose payload is sampled elementwise by `f`. The
+/// sample is a function of the graph seed, the thunk, and the transaction:
+/// re-forcing (or rematerializing) in the same `Txn` reproduces it, while a
+/// new `Txn` draws fresh samples.
+pub fn random_op<T, F>(name: &'static str, shape: Vec<usize>, f: F) -> TensorRef<T>
+where T: Float, F: Fn(&mut CounterRng) -> T + 'static {
+  let seed = graph_seed();
+  let code = ThunkCode{
+    name:     name,
+    entry:    {
+      let shape = shape.clone();
+      Some(Arc::new(move |txn, y| {
+        // Keyed by the thunk, which unlike its data object is what the
+        // caller holds on to.
+        let stable = EntryCtx::current().unwrap().thunk();
+        let mut rng = CounterRng::new(seed, stable, txn);
+        let len = shape.iter().product();
+        let data = (0 .. len).map(|_| (f)(&mut rng)).collect();
+        let mut y = y.get_mut(txn);
+        *y = Tensor::new(shape.clone(), data);
+        true
+      }))
+    },
+    adjoint:  None,
+    batch:    None,
+  };
+  let thunk = Thunk::new(DataCode{
+    alloc:    Some(Arc::new(|_txn| Tensor::default())),
+    nbytes:   Some(Arc::new(|y: &Tensor<T>| y.nbytes())),
+  }, Vec::new(), code);
+  thunk._put_obj()
+}
+
+pub fn uniform_op<T: Float>(shape: Vec<usize>, lo: f64, hi: f64) -> TensorRef<T> {
+  random_op("uniform", shape, move |rng| T::from_f64(lo + (hi - lo) * rng.next_f64()))
+}
+
+pub fn normal_op<T: Float>(shape: Vec<usize>, mean: f64, std: f64) -> TensorRef<T> {
+  random_op("normal", shape, move |rng| T::from_f64(mean + std * rng.next_normal()))
+}
+
+/// Samples ones with probability `p` and zeros otherwise.
+pub fn bernoulli_op<T: Float>(shape: Vec<usize>, p: f64) -> TensorRef<T> {
+  random_op("bernoulli", shape, move |rng| T::from_f64(if rng.next_f64() < p { 1.0 } else { 0.0 }))
+}
@@ -0,0 +1,442 @@
+use num::{Field, Ring};
+
+use std::borrow::{Cow};
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::mem::{size_of};
+use std::ops::{Add, Div, Mul, Neg, Sub};
+use std::sync::{Arc};
+
+/// A strided CPU tensor. Arithmetic is elementwise, where a scalar
+/// (zero-dimensional) tensor broadcasts against a tensor of any shape.
+///
+/// The element buffer is shared and copy-on-write: clones and views
+/// (`reshape`, `transpose_view`, `broadcast_view`) alias the buffer of the
+/// tensor they came from, and `data_mut` copies it first if it is shared.
+/// `data` needs a contiguous row-major layout; use `contiguous` to get one
+/// from an arbitrary view.
+#[derive(Clone)]
+pub struct Tensor<T> {
+  shape:    Vec<usize>,
+  strides:  Vec<usize>,
+  offset:   usize,
+  buf:      Arc<Vec<T>>,
+  // Whether `buf` is accounted to this tensor rather than to the tensor it
+  // is a view of; see `nbytes`.
+  owner:    bool,
+}
+
+fn _dense_strides(shape: &[usize]) -> Vec<usize> {
+  let mut strides = vec![1; shape.len()];
+  for a in (0 .. shape.len().saturating_sub(1)).rev() {
+    strides[a] = strides[a + 1] * shape[a + 1];
+  }
+  strides
+}
+
+impl<T> Tensor<T> {
+  pub fn new(shape: Vec<usize>, data: Vec<T>) -> Tensor<T> {
+    assert_eq!(shape.iter().product::<usize>(), data.len(), "Tensor: new: shape does not match data");
+    Tensor{
+      strides:  _dense_strides(&shape),
+      shape:    shape,
+      offset:   0,
+      buf:      Arc::new(data),
+      owner:    true,
+    }
+  }
+
+  pub fn scalar(x: T) -> Tensor<T> {
+    Tensor::new(Vec::new(), vec![x])
+  }
+
+  pub fn shape(&self) -> &[usize] {
+    &self.shape
+  }
+
+  /// Buffer strides of each axis, in elements; a broadcast axis has stride
+  /// zero.
+  pub fn strides(&self) -> &[usize] {
+    &self.strides
+  }
+
+  pub fn ndim(&self) -> usize {
+    self.shape.len()
+  }
+
+  pub fn len(&self) -> usize {
+    self.shape.iter().product()
+  }
+
+  /// Whether the elements are exactly the whole buffer in row-major order.
+  pub fn is_contiguous(&self) -> bool {
+    self.offset == 0 && self.buf.len() == self.len()
+        && self.shape.iter().zip(self.strides.iter().zip(_dense_strides(&self.shape).iter()))
+             .all(|(&n, (&s, &d))| n <= 1 || s == d)
+  }
+
+  /// Whether `self` and `other` alias the same element buffer.
+  pub fn shares_buffer<U>(&self, other: &Tensor<U>) -> bool {
+    // Empty `Vec`s all have the same dangling pointer, so compare the
+    // shared allocations instead of the elements.
+    Arc::as_ptr(&self.buf) as *const u8 == Arc::as_ptr(&other.buf) as *const u8
+  }
+
+  pub fn data(&self) -> &[T] {
+    assert!(self.is_contiguous(), "Tensor: data: non-contiguous view, see `contiguous`");
+    &self.buf
+  }
+
+  /// The size of the tensor. A buffer is counted by the tensor which
+  /// allocated it (and by its clones, which stand for it); views only count
+  /// their own shape and strides, however many of them are alive.
+  pub fn nbytes(&self) -> usize {
+    let buf = if self.owner { self.buf.len() * size_of::<T>() } else { 0 };
+    size_of::<Tensor<T>>() + 2 * self.shape.len() * size_of::<usize>() + buf
+  }
+
+  /// Returns the number of rows and columns when viewed as a matrix whose
+  /// rows are indexed by all but the last axis.
+  pub fn rows_cols(&self) -> (usize, usize) {
+    match self.shape.last() {
+      None => (1, 1),
+      Some(&cols) => (self.shape[.. self.shape.len() - 1].iter().product(), cols),
+    }
+  }
+
+  /// Buffer offsets of the elements in row-major order.
+  fn _offsets(&self) -> Vec<usize> {
+    let len = self.len();
+    if self.is_contiguous() {
+      return (0 .. len).collect();
+    }
+    let nd = self.ndim();
+    let mut offsets = Vec::with_capacity(len);
+    let mut idx = vec![0; nd];
+    for _ in 0 .. len {
+      offsets.push(self.offset + idx.iter().zip(self.strides.iter()).map(|(&i, &s)| i * s).sum::<usize>());
+      for a in (0 .. nd).rev() {
+        idx[a] += 1;
+        if idx[a] < self.shape[a] {
+          break;
+        }
+        idx[a] = 0;
+      }
+    }
+    offsets
+  }
+
+  pub fn map<U, F: Fn(&T) -> U>(&self, f: F) -> Tensor<U> {
+    Tensor::new(self.shape.clone(), self._offsets().into_iter().map(|o| f(&self.buf[o])).collect())
+  }
+
+  pub fn zip_map<U, W, F: Fn(&T, &U) -> W>(&self, other: &Tensor<U>, f: F) -> Tensor<W> {
+    assert_eq!(self.shape, other.shape, "Tensor: zip_map: shape mismatch");
+    let data = self._offsets().into_iter().zip(other._offsets().into_iter())
+      .map(|(o, p)| f(&self.buf[o], &other.buf[p]))
+      .collect();
+    Tensor::new(self.shape.clone(), data)
+  }
+}
+
+impl<T: Clone> Tensor<T> {
+  pub fn fill(shape: Vec<usize>, x: T) -> Tensor<T> {
+    let len = shape.iter().product();
+    Tensor::new(shape, vec![x; len])
+  }
+
+  /// Mutable access to the elements, first copying them into a contiguous
+  /// buffer owned by this tensor alone if they are a view or shared.
+  pub fn data_mut(&mut self) -> &mut [T] {
+    if !self.is_contiguous() {
+      *self = self.map(|x| x.clone());
+    }
+    // The buffer is not shared once `make_mut` returns.
+    self.owner = true;
+    &mut Arc::make_mut(&mut self.buf)[..]
+  }
+
+  pub fn into_data(self) -> Vec<T> {
+    if !self.is_contiguous() {
+      return self._offsets().into_iter().map(|o| self.buf[o].clone()).collect();
+    }
+    Arc::try_unwrap(self.buf).unwrap_or_else(|buf| (*buf).clone())
+  }
+
+  /// `self` if it is contiguous, otherwise a contiguous copy.
+  pub fn contiguous(&self) -> Cow<'_, Tensor<T>> {
+    if self.is_contiguous() {
+      Cow::Borrowed(self)
+    } else {
+      Cow::Owned(self.map(|x| x.clone()))
+    }
+  }
+
+  /// A view of the whole of `self`, sharing its buffer.
+  pub fn view(&self) -> Tensor<T> {
+    let mut y = self.clone();
+    y.owner = false;
+    y
+  }
+
+  /// A view with a new shape of the same length; the buffer is only copied
+  /// if `self` is not contiguous.
+  pub fn reshape(&self, shape: Vec<usize>) -> Tensor<T> {
+    assert_eq!(shape.iter().product::<usize>(), self.len(), "Tensor: reshape: length mismatch");
+    let copied = !self.is_contiguous();
+    let x = self.contiguous().into_owned();
+    Tensor{
+      strides:  _dense_strides(&shape),
+      shape:    shape,
+      offset:   0,
+      buf:      x.buf,
+      owner:    copied,
+    }
+  }
+
+  /// A view swapping the last two axes.
+  pub fn transpose_view(&self) -> Tensor<T> {
+    assert!(self.ndim() >= 2, "Tensor: transpose_view: expected a matrix");
+    let nd = self.ndim();
+    let mut y = self.view();
+    y.shape.swap(nd - 2, nd - 1);
+    y.strides.swap(nd - 2, nd - 1);
+    y
+  }
+
+  /// A view broadcasting `self` to `shape`, aligning trailing axes; axes of
+  /// extent one, and missing leading axes, get stride zero.
+  pub fn broadcast_view(&self, shape: Vec<usize>) -> Tensor<T> {
+    assert!(shape.len() >= self.ndim(), "Tensor: broadcast_view: cannot broadcast {:?} to {:?}", self.shape, shape);
+    let lead = shape.len() - self.ndim();
+    let mut strides = vec![0; shape.len()];
+    for a in 0 .. self.ndim() {
+      if self.shape[a] == shape[lead + a] {
+        strides[lead + a] = self.strides[a];
+      } else {
+        assert_eq!(1, self.shape[a], "Tensor: broadcast_view: cannot broadcast {:?} to {:?}", self.shape, shape);
+      }
+    }
+    Tensor{
+      shape:    shape,
+      strides:  strides,
+      offset:   self.offset,
+      buf:      self.buf.clone(),
+      owner:    false,
+    }
+  }
+}
+
+impl<T: PartialEq> PartialEq for Tensor<T> {
+  fn eq(&self, other: &Tensor<T>) -> bool {
+    self.shape == other.shape
+        && self._offsets().into_iter().zip(other._offsets().into_iter()).all(|(o, p)| self.buf[o] == other.buf[p])
+  }
+}
+
+impl<T: Debug> Debug for Tensor<T> {
+  fn fmt(&self, f: &mut Formatter) -> FmtResult {
+    let data: Vec<&T> = self._offsets().into_iter().map(|o| &self.buf[o]).collect();
+    f.debug_struct("Tensor")
+      .field("shape", &self.shape)
+      .field("data", &data)
+      .finish()
+  }
+}
+
+impl<T: Default> Default for Tensor<T> {
+  fn default() -> Tensor<T> {
+    Tensor::scalar(T::default())
+  }
+}
+
+impl<T: Ring> Tensor<T> {
+  pub fn zeros(shape: Vec<usize>) -> Tensor<T> {
+    Tensor::fill(shape, T::zero())
+  }
+
+  pub fn sum(&self) -> T {
+    self._offsets().into_iter().fold(T::zero(), |acc, o| acc + self.buf[o].clone())
+  }
+
+  /// Sums over the axes along which `shape` broadcasts to `self.shape()`;
+  /// the reverse of `broadcast_view`.
+  pub fn sum_to(&self, shape: Vec<usize>) -> Tensor<T> {
+    let len = shape.iter().product();
+    let target = Tensor::new(shape.clone(), vec![(); len]).broadcast_view(self.shape.clone());
+    let mut data = vec![T::zero(); len];
+    for (o, p) in target._offsets().into_iter().zip(self._offsets().into_iter()) {
+      data[o] = data[o].clone() + self.buf[p].clone();
+    }
+    Tensor::new(shape, data)
+  }
+
+  fn _zip_broadcast<F: Fn(T, T) -> T>(self, other: Tensor<T>, f: F) -> Tensor<T> {
+    if self.shape == other.shape {
+      let shape = self.shape.clone();
+      let data = self.into_data().into_iter().zip(other.into_data().into_iter()).map(|(x, y)| f(x, y)).collect();
+      Tensor::new(shape, data)
+    } else if other.shape.is_empty() {
+      let y = other.buf[other.offset].clone();
+      self.map(|x| f(x.clone(), y.clone()))
+    } else if self.shape.is_empty() {
+      let x = self.buf[self.offset].clone();
+      other.map(|y| f(x.clone(), y.clone()))
+    } else {
+      panic!("Tensor: shape mismatch: {:?} vs {:?}", self.shape, other.shape);
+    }
+  }
+}
+
+impl<T: Ring> Tensor<T> {
+  /// Transposes a matrix, or each matrix of a batch (swapping the last two
+  /// axes).
+  pub fn transpose(&self) -> Tensor<T> {
+    assert!(self.ndim() >= 2, "Tensor: transpose: expected a matrix");
+    let nd = self.ndim();
+    let (m, n) = (self.shape[nd - 2], self.shape[nd - 1]);
+    let batch = self.len() / (m * n).max(1);
+    let x = self.contiguous();
+    let mut data = Vec::with_capacity(self.len());
+    for b in 0 .. batch {
+      let off = b * m * n;
+      for j in 0 .. n {
+        for i in 0 .. m {
+          data.push(x.data()[off + i * n + j].clone());
+        }
+      }
+    }
+    let mut shape = self.shape.clone();
+    shape.swap(nd - 2, nd - 1);
+    Tensor::new(shape, data)
+  }
+
+  /// Moves axis `src` to position `dst`, shifting the axes in between.
+  pub fn move_axis(&self, src: usize, dst: usize) -> Tensor<T> {
+    let nd = self.ndim();
+    assert!(src < nd && dst < nd, "Tensor: move_axis: axis out of range");
+    let mut perm: Vec<usize> = (0 .. nd).filter(|&a| a != src).collect();
+    perm.insert(dst, src);
+    let mut y = self.view();
+    y.shape = perm.iter().map(|&a| self.shape[a]).collect();
+    y.strides = perm.iter().map(|&a| self.strides[a]).collect();
+    y.contiguous().into_owned()
+  }
+
+  /// Matrix product where either operand may be a batch of matrices
+  /// (`[b, m, k]` or `[b, k, n]`); an unbatched operand is shared by the
+  /// whole batch.
+  pub fn batch_matmul(&self, other: &Tensor<T>) -> Tensor<T> {
+    if self.ndim() == 2 && other.ndim() == 2 {
+      return self.matmul(other);
+    }
+    let batch = if self.ndim() == 3 { self.shape[0] } else { other.shape[0] };
+    assert!(self.ndim() == 2 || (self.ndim() == 3 && self.shape[0] == batch), "Tensor: batch_matmul: bad shape {:?}", self.shape);
+    assert!(other.ndim() == 2 || (other.ndim() == 3 && other.shape[0] == batch), "Tensor: batch_matmul: bad shape {:?}", other.shape);
+    let (m, k) = (self.shape[self.ndim() - 2], self.shape[self.ndim() - 1]);
+    let n = other.shape[other.ndim() - 1];
+    assert_eq!(k, other.shape[other.ndim() - 2], "Tensor: batch_matmul: inner dimension mismatch");
+    let (x, y) = (self.contiguous(), other.contiguous());
+    let mut data = Vec::with_capacity(batch * m * n);
+    for b in 0 .. batch {
+      let a = if self.ndim() == 3 { &x.data()[b * m * k .. (b + 1) * m * k] } else { x.data() };
+      let a = Tensor::new(vec![m, k], a.to_vec());
+      let o = if other.ndim() == 3 { &y.data()[b * k * n .. (b + 1) * k * n] } else { y.data() };
+      let o = Tensor::new(vec![k, n], o.to_vec());
+      data.extend(a.matmul(&o).into_data());
+    }
+    Tensor::new(vec![batch, m, n], data)
+  }
+
+  pub fn matmul(&self, other: &Tensor<T>) -> Tensor<T> {
+    assert_eq!(2, self.ndim(), "Tensor: matmul: expected a matrix");
+    assert_eq!(2, other.ndim(), "Tensor: matmul: expected a matrix");
+    let (m, k) = (self.shape[0], self.shape[1]);
+    let n = other.shape[1];
+    assert_eq!(k, other.shape[0], "Tensor: matmul: inner dimension mismatch");
+    let (x, y) = (self.contiguous(), other.contiguous());
+    let (x, y) = (x.data(), y.data());
+    let mut data = vec![T::zero(); m * n];
+    for i in 0 .. m {
+      for p in 0 .. k {
+        let a = &x[i * k + p];
+        for j in 0 .. n {
+          data[i * n + j] = data[i * n + j].clone() + a.clone() * y[p * n + j].clone();
+        }
+      }
+    }
+    Tensor::new(vec![m, n], data)
+  }
+}
+
+impl<T: Ring> Add for Tensor<T> {
+  type Output = Tensor<T>;
+
+  fn add(self, other: Tensor<T>) -> Tensor<T> {
+    self._zip_broadcast(other, |x, y| x + y)
+  }
+}
+
+impl<T: Ring> Sub for Tensor<T> {
+  type Output = Tensor<T>;
+
+  fn sub(self, other: Tensor<T>) -> Tensor<T> {
+    self._zip_broadcast(other, |x, y| x - y)
+  }
+}
+
+impl<T: Ring> Mul for Tensor<T> {
+  type Output = Tensor<T>;
+
+  fn mul(self, other: Tensor<T>) -> Tensor<T> {
+    self._zip_broadcast(other, |x, y| x * y)
+  }
+}
+
+impl<T: Field> Div for Tensor<T> {
+  type Output = Tensor<T>;
+
+  fn div(self, other: Tensor<T>) -> Tensor<T> {
+    self._zip_broadcast(other, |x, y| x / y)
+  }
+}
+
+impl<T: Field> Neg for Tensor<T> {
+  type Output = Tensor<T>;
+
+  fn neg(self) -> Tensor<T> {
+    self.map(|x| -x.clone())
+  }
+}
+
+impl<T: Ring> Ring for Tensor<T> {
+  fn zero() -> Tensor<T> {
+    Tensor::scalar(T::zero())
+  }
+
+  fn one() -> Tensor<T> {
+    Tensor::scalar(T::one())
+  }
+
+  fn conj(self) -> Tensor<T> {
+    self.map(|x| x.clone().conj())
+  }
+
+  fn reduce_like(self, like: &Tensor<T>) -> Tensor<T> {
+    if self.shape == like.shape {
+      self
+    } else {
+      self.sum_to(like.shape.clone())
+    }
+  }
+
+  fn expand_like(self, like: &Tensor<T>) -> Tensor<T> {
+    if self.shape == like.shape {
+      self
+    } else {
+      self.broadcast_view(like.shape.clone())
+    }
+  }
+}
+
+impl<T: Field> Field for Tensor<T> {
+}
@@ -0,0 +1,46 @@
+use experimental::rt1::*;
+use nn::{TensorRef, broadcast_batch_op, move_axis_op};
+use num::{Float};
+use tensor::*;
+
+use std::sync::{Arc};
+
+fn _placeholder<T: Float>() -> TensorRef<T> {
+  let code = ThunkCode{
+    name:     "placeholder",
+    entry:    None,
+    adjoint:  None,
+    batch:    None,
+  };
+  let thunk = Thunk::new(DataCode{
+    alloc:    Some(Arc::new(|_txn| Tensor::default())),
+    nbytes:   None,
+  }, Vec::new(), code);
+  thunk._put_obj()
+}
+
+/// Vectorizes the single-example graph built by `f_builder`.
+///
+/// The returned function takes an input whose axis `batch_axis` indexes the
+/// examples, and builds a graph computing `f_builder` on every example at
+/// once, with the batch as the leading axis of the result. The example graph
+/// is built over a placeholder and then rewritten op by op with the ops'
+/// batching rules; building fails with `EvalError::MissingBatchRule` if an
+/// op on the path from the input has none.
+pub fn vmap<T, F>(f_builder: F, batch_axis: usize) -> Box<Fn(TensorRef<T>) -> Result<TensorRef<T>, EvalError>>
+where T: Float, F: Fn(TensorRef<T>) -> TensorRef<T> + 'static {
+  Box::new(move |xs| {
+    let xb = if batch_axis == 0 { xs } else { move_axis_op(xs, batch_axis, 0) };
+    let x = _placeholder();
+    let y = f_builder(x.clone());
+    let mut batcher = Batcher::new().with_broadcast({
+      let xb = xb.clone();
+      Arc::new(move |v: TensorRef<T>| broadcast_batch_op(v, xb.clone())) as Arc<Fn(TensorRef<T>) -> TensorRef<T>>
+    });
+    match batch_graph(&y, &x, xb.clone(), &mut batcher)? {
+      Some(yb) => Ok(yb),
+      // The result does not depend on the input.
+      None => batcher.get_or_broadcast(&y),
+    }
+  })
+}
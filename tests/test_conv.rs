@@ -0,0 +1,106 @@
+extern crate hebb;
+
+use hebb::conv::*;
+use hebb::experimental::rt1::*;
+use hebb::gradcheck::*;
+use hebb::nn::*;
+use hebb::tensor::*;
+
+fn _ramp(shape: Vec<usize>, scale: f64) -> Tensor<f64> {
+  let len: usize = shape.iter().product();
+  // Distinct values, so that max pooling has no ties.
+  Tensor::new(shape, (0 .. len).map(|i| scale * (((i * 7) % 11) as f64 - 5.0 + 0.01 * i as f64)).collect())
+}
+
+#[test]
+fn test_conv_kernels() {
+  // A 3x3 input with a 2x2 all-ones kernel sums each 2x2 window.
+  let x = Tensor::new(vec![1, 1, 3, 3], (1 .. 10).map(|v| v as f64).collect());
+  let w = Tensor::fill(vec![1, 1, 2, 2], 1.0);
+  let y = conv2d(&x, &w, &Conv2dConfig::default()).unwrap();
+  assert_eq!(&[1, 1, 2, 2], y.shape());
+  assert_eq!(&[12.0, 16.0, 24.0, 28.0], y.data());
+  let cfg = Conv2dConfig{padding: (1, 1), stride: (2, 2), ..Conv2dConfig::default()};
+  let y = conv2d(&x, &w, &cfg).unwrap();
+  assert_eq!(&[1.0, 5.0, 11.0, 28.0], y.data());
+  assert_eq!(&[5.0, 6.0, 8.0, 9.0], max_pool2d(&x, &Pool2dConfig{kernel: (2, 2), stride: (1, 1), padding: (0, 0)}).unwrap().data());
+  let cfg = Pool2dConfig{kernel: (2, 2), stride: (2, 2), padding: (1, 1)};
+  assert_eq!(&[1.0, 2.5, 5.5, 7.0], avg_pool2d(&x, &cfg).unwrap().data());
+  // Invalid configs and shapes are rejected instead of panicking.
+  assert!(conv2d(&x, &Tensor::fill(vec![1, 1, 0, 2], 1.0), &Conv2dConfig::default()).is_none());
+  assert!(conv2d(&x, &w, &Conv2dConfig{stride: (0, 1), ..Conv2dConfig::default()}).is_none());
+  assert!(conv2d(&x, &Tensor::fill(vec![1, 1, 4, 4], 1.0), &Conv2dConfig::default()).is_none());
+  assert!(max_pool2d(&x, &Pool2dConfig{kernel: (1, 1), stride: (1, 1), padding: (1, 1)}).is_none());
+  assert!(avg_pool2d(&x, &Pool2dConfig::new((0, 2))).is_none());
+}
+
+#[test]
+fn test_conv_entry_failed() {
+  let t = txn();
+  let x = constant_op(_ramp(vec![1, 2, 4, 4], 0.1));
+  let w = constant_op(_ramp(vec![2, 2, 3, 3], 0.1));
+  let ys = vec![
+    conv2d_op(x.clone(), w.clone(), Conv2dConfig{stride: (1, 0), ..Conv2dConfig::default()}),
+    conv2d_op(x.clone(), transpose_op(w), Conv2dConfig{groups: 0, ..Conv2dConfig::default()}),
+    max_pool2d_op(x.clone(), Pool2dConfig{kernel: (2, 2), stride: (2, 2), padding: (2, 0)}),
+    avg_pool2d_op(x.clone(), Pool2dConfig::new((0, 0))),
+    max_pool1d_op(x, Pool1dConfig::new(2)),
+  ];
+  for y in ys.into_iter() {
+    let mut roots = TagVec::new();
+    roots.push(&tanh_op(y.clone()));
+    match roots.force_all(t).pop().unwrap() {
+      Err(EvalError::EntryFailed(s)) => assert_eq!(y._stable(), s),
+      r => panic!("{:?}", r),
+    }
+  }
+}
+
+#[test]
+fn test_conv_gradcheck() {
+  let x2 = _ramp(vec![2, 4, 5, 5], 0.1);
+  let w2 = _ramp(vec![6, 2, 3, 2], 0.05);
+  let x1 = _ramp(vec![2, 4, 9], 0.1);
+  let w1 = _ramp(vec![4, 1, 3], 0.05);
+  let cfg2 = Conv2dConfig{stride: (2, 1), padding: (1, 2), dilation: (1, 2), groups: 2};
+  let cfg1 = Conv1dConfig{stride: 2, padding: 1, dilation: 2, groups: 4};
+  let builders: Vec<Box<Fn(&[TensorRef<f64>]) -> TensorRef<f64>>> = vec![
+    Box::new(move |xs| {
+      let y = conv2d_op(xs[0].clone(), xs[1].clone(), cfg2);
+      let y = max_pool2d_op(tanh_op(y), Pool2dConfig{kernel: (2, 2), stride: (1, 1), padding: (1, 0)});
+      mse_op(y, constant_op(_ramp(vec![2, 6, 4, 6], 0.1)))
+    }),
+    Box::new(move |xs| mse_op(avg_pool2d_op(xs[0].clone(), Pool2dConfig{kernel: (3, 2), stride: (2, 2), padding: (1, 1)}), constant_op(_ramp(vec![2, 4, 3, 3], 0.1)))),
+    Box::new(move |xs| {
+      let y = conv1d_op(xs[2].clone(), xs[3].clone(), cfg1);
+      let y = add_op(max_pool1d_op(y.clone(), Pool1dConfig::new(2)), avg_pool1d_op(y, Pool1dConfig::new(2)));
+      mse_op(y, constant_op(_ramp(vec![2, 4, 2], 0.1)))
+    }),
+  ];
+  for f in builders.into_iter() {
+    let report = check_grad(move |xs| f(xs), vec![x2.clone(), w2.clone(), x1.clone(), w1.clone()], 1.0e-6, 1.0e-5).unwrap();
+    if !report.passed() {
+      report.print();
+    }
+    assert!(report.passed());
+  }
+}
+
+#[test]
+fn test_conv_second_order() {
+  let x = _ramp(vec![1, 2, 4, 4], 0.1);
+  let w = _ramp(vec![2, 2, 2, 2], 0.05);
+  let cfg = Conv2dConfig{padding: (1, 0), ..Conv2dConfig::default()};
+  let report = check_grad(move |xs| {
+    let y = conv2d_op(xs[0].clone(), xs[1].clone(), cfg);
+    let y = add_op(max_pool2d_op(y.clone(), Pool2dConfig::new((1, 3))), avg_pool2d_op(y, Pool2dConfig::new((1, 3))));
+    let y = mul_op(y.clone(), y);
+    let loss = mse_op(y, constant_op(_ramp(vec![1, 2, 5, 1], 0.1)));
+    let gs = grad(&loss, constant_op(Tensor::scalar(1.0)), xs).unwrap();
+    add_op(mse_op(gs[0].clone(), constant_op(Tensor::zeros(vec![1, 2, 4, 4]))), mse_op(gs[1].clone(), constant_op(Tensor::zeros(vec![2, 2, 2, 2]))))
+  }, vec![x, w], 1.0e-6, 1.0e-5).unwrap();
+  if !report.passed() {
+    report.print();
+  }
+  assert!(report.passed());
+}
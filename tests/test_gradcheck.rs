@@ -0,0 +1,58 @@
+extern crate hebb;
+
+use hebb::experimental::rt1::*;
+use hebb::gradcheck::*;
+use hebb::nn::*;
+use hebb::tensor::*;
+
+#[test]
+fn test_gradcheck_nn_ops() {
+  let x = Tensor::new(vec![2, 3], vec![0.5, -1.0, 2.0, 0.25, 1.5, -0.75_f64]);
+  let w = Tensor::new(vec![3, 2], vec![0.1, -0.2, 0.3, 0.4, -0.5, 0.6_f64]);
+  let target = Tensor::new(vec![2, 2], vec![0.0, 1.0, 1.0, 0.0_f64]);
+  let report = check_grad(move |xs| {
+    let h = tanh_op(matmul_op(xs[0].clone(), xs[1].clone()));
+    softmax_cross_entropy_op(h, constant_op(target.clone()))
+  }, vec![x, w], 1.0e-6, 1.0e-6).unwrap();
+  report.print();
+  assert_eq!(12, report.entries.len());
+  assert!(report.passed());
+}
+
+#[test]
+fn test_gradcheck_detects_wrong_adjoint() {
+  let x = Tensor::new(vec![3], vec![1.0, 2.0, 3.0_f64]);
+  let report = check_grad(|xs| {
+    // Doubles its input, but claims a gradient of one.
+    let x = xs[0].clone();
+    let y = tensor_op("bad_double", vec![x.clone()], |xs| xs[0].map(|&v| 2.0 * v), tensor_adjoint(move |_y, dy, sink| {
+      sink.put_adj(&x, dy);
+    }));
+    mse_op(y, constant_op(Tensor::zeros(vec![3])))
+  }, vec![x], 1.0e-6, 1.0e-6).unwrap();
+  assert!(!report.passed());
+  assert_eq!(3, report.failures().len());
+}
+
+#[test]
+fn test_gradcheck_scalar_broadcast() {
+  let s = Tensor::scalar(0.5_f64);
+  let x = Tensor::new(vec![3], vec![1.0, 2.0, 3.0_f64]);
+  let build = |xs: &[TensorRef<f64>]| {
+    let (s, x) = (xs[0].clone(), xs[1].clone());
+    let y = div_op(add_op(mul_op(s.clone(), x.clone()), s.clone()), sub_op(x, s));
+    mse_op(y, constant_op(Tensor::zeros(vec![3])))
+  };
+  let report = check_grad(build, vec![s.clone(), x.clone()], 1.0e-6, 1.0e-6).unwrap();
+  assert_eq!(4, report.entries.len());
+  assert!(report.passed());
+
+  // The cotangent of the scalar operand is summed back to a scalar.
+  let xs = vec![constant_op(s), constant_op(x)];
+  let t = txn();
+  let sink = backward(&build(&xs), constant_op(Tensor::scalar(1.0)), t, CheckpointPolicy::KeepAll).unwrap();
+  let ds = sink.get_adj(&xs[0]).unwrap();
+  let dx = sink.get_adj(&xs[1]).unwrap();
+  assert_eq!(Vec::<usize>::new(), ds._get_obj().get(t).shape().to_vec());
+  assert_eq!(vec![3], dx._get_obj().get(t).shape().to_vec());
+}
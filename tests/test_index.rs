@@ -0,0 +1,97 @@
+extern crate hebb;
+
+use hebb::experimental::rt1::*;
+use hebb::gradcheck::*;
+use hebb::index::*;
+use hebb::nn::*;
+use hebb::tensor::*;
+
+#[test]
+fn test_index_kernels() {
+  let x = Tensor::new(vec![2, 3], vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0_f64]);
+  assert_eq!(&[2.0, 3.0, 5.0, 6.0], slice(&x, 1, 1, 3).unwrap().data());
+  let idx = Tensor::new(vec![3], vec![2, 0, 2]);
+  assert_eq!(&[3.0, 1.0, 3.0, 6.0, 4.0, 6.0], index_select(&x, 1, &idx).unwrap().data());
+  let idx = Tensor::new(vec![2, 2], vec![1, 0, 0, 0]);
+  let g = gather(&x, 0, &idx).unwrap();
+  assert_eq!(&[4.0, 2.0, 1.0, 2.0], g.data());
+  let s = scatter_add(&Tensor::zeros(vec![2, 3]), 0, &idx, &g).unwrap();
+  assert_eq!(&[1.0, 4.0, 0.0, 4.0, 0.0, 0.0], s.data());
+  assert!(slice(&x, 1, 2, 4).is_none());
+  assert!(gather(&x, 0, &Tensor::new(vec![1, 1], vec![2])).is_none());
+}
+
+#[test]
+fn test_index_gradcheck() {
+  let x = Tensor::new(vec![3, 4], (0 .. 12).map(|i| 0.1 * i as f64 - 0.5).collect());
+  let s = Tensor::new(vec![2, 4], (0 .. 8).map(|i| 0.2 * i as f64 - 0.3).collect());
+  let report = check_grad(|xs| {
+    let gidx = constant_op(Tensor::new(vec![2, 4], vec![2, 0, 1, 2, 0, 0, 2, 1]));
+    let y = gather_op(xs[0].clone(), 0, gidx.clone());
+    let y = scatter_add_op(xs[0].clone(), 0, gidx, mul_op(y, xs[1].clone()));
+    let y = index_select_op(y, 1, constant_op(Tensor::new(vec![5], vec![3, 1, 1, 0, 2])));
+    let y = slice_op(y, 0, 1, 3);
+    mse_op(tanh_op(y), constant_op(Tensor::fill(vec![2, 5], 0.1)))
+  }, vec![x, s], 1.0e-6, 1.0e-6).unwrap();
+  if !report.passed() {
+    report.print();
+  }
+  assert!(report.passed());
+}
+
+#[test]
+fn test_index_out_of_bounds() {
+  let t = txn();
+  let x = constant_op(Tensor::new(vec![3], vec![1.0, 2.0, 3.0_f64]));
+  let idx = variable_op(Tensor::new(vec![2], vec![0, 2]));
+  let y = index_select_op(x.clone(), 0, idx.clone());
+  let z = tanh_op(y.clone());
+  let mut roots = TagVec::new();
+  roots.push(&z);
+  assert!(roots.force_all(t)[0].is_ok());
+  // Out-of-bounds indices only show up when forced, as an error.
+  *idx._get_data().get_mut(t) = Tensor::new(vec![2], vec![0, 3]);
+  let t = txn();
+  match roots.force_all(t).pop().unwrap() {
+    Err(EvalError::EntryFailed(s)) => assert_eq!(y._stable(), s),
+    r => panic!("{:?}", r),
+  }
+  let mut roots = TagVec::new();
+  roots.push(&slice_op(x, 0, 2, 4));
+  assert!(roots.force_all(t)[0].is_err());
+}
+
+#[test]
+fn test_index_split() {
+  let x = constant_op(Tensor::new(vec![2, 3], vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0_f64]));
+  let parts = split_op(x, 1, vec![1, 2]);
+  let t = txn();
+  assert_eq!(&[2.0, 3.0, 5.0, 6.0], parts[1]._get_obj().get(t).data());
+  assert_eq!(&[1.0, 4.0], parts[0]._get_obj().get(t).data());
+
+  // Only the last part is used; the first gets no cotangent.
+  let report = check_grad(|xs| {
+    let parts = split_op(xs[0].clone(), 1, vec![1, 1, 2]);
+    mse_op(parts[2].clone(), constant_op(Tensor::new(vec![3, 2], vec![0.5; 6])))
+  }, vec![Tensor::new(vec![3, 4], (0 .. 12).map(|i| 0.1 * i as f64).collect())], 1.0e-6, 1.0e-6).unwrap();
+  assert!(report.passed());
+
+  let bad = split_op(constant_op(Tensor::<f64>::zeros(vec![2, 3])), 1, vec![1, 1]);
+  let mut roots = TagVec::new();
+  roots.push(&bad[1]);
+  assert!(roots.force_all(txn())[0].is_err());
+}
+
+#[test]
+fn test_index_split_custom_grad() {
+  // `custom_grad` around a non-first output of a multi-output op keeps the
+  // value of that output.
+  let t = txn();
+  let x = constant_op(Tensor::new(vec![4], vec![1.0, 2.0, 3.0, 4.0_f64]));
+  let y = custom_grad(x.clone(), |x| split_op(x, 0, vec![1, 3])[1].clone(), |_pass, x, _y, _dy, sink| {
+    sink.put_adj(&x, constant_op(Tensor::new(vec![4], vec![7.0; 4])));
+  });
+  let sink = backward(&y, constant_op(Tensor::new(vec![3], vec![1.0; 3])), t, CheckpointPolicy::KeepAll).unwrap();
+  assert_eq!(&[2.0, 3.0, 4.0], y._get_obj().get(t).data());
+  assert_eq!(&[7.0; 4], sink.get_adj(&x).unwrap()._get_obj().get(t).data());
+}
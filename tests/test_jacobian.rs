@@ -0,0 +1,63 @@
+extern crate hebb;
+
+use hebb::experimental::rt1::*;
+use hebb::jacobian::*;
+use hebb::nn::*;
+use hebb::tensor::*;
+
+#[test]
+fn test_jacobian_rev() {
+  let x = constant_op(Tensor::new(vec![1, 3], vec![1.0, -2.0, 0.5_f64]));
+  let w = constant_op(Tensor::new(vec![3, 2], vec![0.5, -1.0, 2.0, 0.25, -0.5, 1.5_f64]));
+  let h = matmul_op(x.clone(), w.clone());
+  let y = mul_op(h.clone(), h.clone());
+  let t = txn();
+  let hv = h._get_obj().get(t).clone();
+  let wv = w._get_obj().get(t).clone();
+  // dy_i/dx_j = 2 h_i w_ji.
+  let mut expected = Vec::new();
+  for i in 0 .. 2 {
+    for j in 0 .. 3 {
+      expected.push(2.0 * hv.data()[i] * wv.data()[j * 2 + i]);
+    }
+  }
+  let jr = jacobian_rev(&y, &x, t).unwrap();
+  let jrr = jacobian_rev_rev(&y, &x, t).unwrap();
+  assert_eq!(&[2, 3], jr.shape());
+  assert_eq!(&[2, 3], jrr.shape());
+  for k in 0 .. 6 {
+    assert!((jr.data()[k] - expected[k]).abs() < 1.0e-12);
+    assert!((jrr.data()[k] - expected[k]).abs() < 1.0e-12);
+  }
+}
+
+#[test]
+fn test_jacobian_rev_rev_nonlinear() {
+  let x = constant_op(Tensor::new(vec![3], vec![0.1, 0.2, 0.5_f64]));
+  let y = tanh_op(x.clone());
+  let t = txn();
+  let jr = jacobian_rev(&y, &x, t).unwrap();
+  let jrr = jacobian_rev_rev(&y, &x, t).unwrap();
+  assert_eq!(&[3, 3], jrr.shape());
+  for i in 0 .. 3 {
+    for j in 0 .. 3 {
+      let x_i = [0.1, 0.2, 0.5_f64][i];
+      let expected = if i == j { 1.0 - x_i.tanh() * x_i.tanh() } else { 0.0 };
+      assert!((jr.data()[i * 3 + j] - expected).abs() < 1.0e-12);
+      assert!((jrr.data()[i * 3 + j] - expected).abs() < 1.0e-12);
+    }
+  }
+}
+
+#[test]
+fn test_jacobian_shape_mismatch() {
+  // An adjoint which returns a cotangent of the wrong length.
+  let x = constant_op(Tensor::new(vec![3], vec![0.1, 0.2, 0.5_f64]));
+  let y = custom_grad(x.clone(), tanh_op, |_pass, x, _y, _dy, sink| {
+    sink.put_adj(&x, constant_op(Tensor::zeros(vec![2])));
+  });
+  match jacobian_rev(&y, &x, txn()) {
+    Err(EvalError::ShapeMismatch(_)) => {}
+    other => panic!("expected ShapeMismatch: {:?}", other.map(|_| ())),
+  }
+}
@@ -0,0 +1,121 @@
+extern crate hebb;
+
+use hebb::experimental::rt1::*;
+use hebb::gradcheck::*;
+use hebb::linalg::*;
+use hebb::nn::*;
+use hebb::tensor::*;
+
+fn _spd(m: TensorRef<f64>) -> TensorRef<f64> {
+  // `M M^T + 3 I` is symmetric positive definite.
+  let eye = constant_op(Tensor::new(vec![3, 3], vec![3.0, 0.0, 0.0, 0.0, 3.0, 0.0, 0.0, 0.0, 3.0]));
+  add_op(matmul_op(m.clone(), transpose_op(m)), eye)
+}
+
+fn _weights(shape: Vec<usize>) -> TensorRef<f64> {
+  let len: usize = shape.iter().product();
+  constant_op(Tensor::new(shape, (0 .. len).map(|i| 0.3 + 0.17 * i as f64).collect()))
+}
+
+#[test]
+fn test_linalg_kernels() {
+  let m = Tensor::new(vec![3, 3], vec![1.0, 0.5, -0.2, 0.3, 2.0, 0.1, -0.4, 0.2, 1.5_f64]);
+  let a = m.matmul(&m.transpose());
+  let b = Tensor::new(vec![3, 2], vec![1.0, 2.0, -1.0, 0.5, 0.25, 3.0_f64]);
+  let l = cholesky(&a).unwrap();
+  let err = (l.matmul(&l.transpose()) - a.clone()).into_data().iter().fold(0.0_f64, |e, &v| e.max(v.abs()));
+  assert!(err < 1.0e-12);
+  let x = solve(&m, &b).unwrap();
+  let err = (m.matmul(&x) - b.clone()).into_data().iter().fold(0.0_f64, |e, &v| e.max(v.abs()));
+  assert!(err < 1.0e-12);
+  let x = solve_triangular(&l, &b, true, true).unwrap();
+  let err = (l.transpose().matmul(&x) - b.clone()).into_data().iter().fold(0.0_f64, |e, &v| e.max(v.abs()));
+  assert!(err < 1.0e-12);
+  let c = Tensor::new(vec![4, 3], vec![1.0, 0.5, -0.2, 0.3, 2.0, 0.1, -0.4, 0.2, 1.5, 0.7, -1.1, 0.9_f64]);
+  let (q, r) = qr(&c).unwrap();
+  let err = (q.matmul(&r) - c.clone()).into_data().iter().fold(0.0_f64, |e, &v| e.max(v.abs()));
+  assert!(err < 1.0e-12);
+  assert_eq!(0.0, r.data()[3]);
+  // Singular and indefinite inputs are rejected.
+  let s = Tensor::new(vec![3, 3], vec![1.0, 2.0, 3.0, 2.0, 4.0, 6.0, 0.5, 0.0, 1.0_f64]);
+  assert!(solve(&s, &b).is_none());
+  assert!(cholesky(&(a.clone() * Tensor::scalar(-1.0))).is_none());
+  // So are inputs of the wrong shape.
+  assert!(solve(&c, &b).is_none());
+  assert!(solve(&m, &c).is_none());
+  assert!(solve_triangular(&l, &c, true, false).is_none());
+  assert!(cholesky(&b).is_none());
+  assert!(qr(&c.transpose()).is_none());
+}
+
+#[test]
+fn test_linalg_entry_failed() {
+  let t = txn();
+  let s = constant_op(Tensor::new(vec![2, 2], vec![1.0, 2.0, 2.0, 4.0_f64]));
+  let x = solve_op(s.clone(), constant_op(Tensor::new(vec![2, 1], vec![1.0, 1.0_f64])));
+  let l = cholesky_op(neg_op(s.clone()));
+  let c = constant_op(Tensor::new(vec![2, 3], vec![1.0, 0.5, -0.2, 0.3, 2.0, 0.1_f64]));
+  let xt = solve_triangular_op(s, transpose_op(c.clone()), true);
+  let (q, _) = qr_op(c);
+  // A singular, indefinite or misshapen input shows up as an error of the
+  // op's thunk.
+  for y in vec![x, l, xt, q].into_iter() {
+    let mut roots = TagVec::new();
+    roots.push(&tanh_op(y.clone()));
+    match roots.force_all(t).pop().unwrap() {
+      Err(EvalError::EntryFailed(s)) => assert_eq!(y._stable(), s),
+      r => panic!("{:?}", r),
+    }
+  }
+}
+
+#[test]
+fn test_linalg_gradcheck() {
+  let m = Tensor::new(vec![3, 3], vec![1.0, 0.5, -0.2, 0.3, 2.0, 0.1, -0.4, 0.2, 1.5_f64]);
+  let b = Tensor::new(vec![3, 2], vec![1.0, 2.0, -1.0, 0.5, 0.25, 3.0_f64]);
+  let c = Tensor::new(vec![4, 3], vec![1.0, 0.5, -0.2, 0.3, 2.0, 0.1, -0.4, 0.2, 1.5, 0.7, -1.1, 0.9_f64]);
+  let builders: Vec<Box<Fn(&[TensorRef<f64>]) -> TensorRef<f64>>> = vec![
+    Box::new(|xs| mse_op(cholesky_op(_spd(xs[0].clone())), _weights(vec![3, 3]))),
+    Box::new(|xs| mse_op(solve_op(xs[0].clone(), xs[1].clone()), _weights(vec![3, 2]))),
+    Box::new(|xs| mse_op(solve_triangular_op(cholesky_op(_spd(xs[0].clone())), xs[1].clone(), true), _weights(vec![3, 2]))),
+    Box::new(|xs| mse_op(solve_triangular_op(transpose_op(cholesky_op(_spd(xs[0].clone()))), xs[1].clone(), false), _weights(vec![3, 2]))),
+    Box::new(|xs| {
+      let (q, r) = qr_op(xs[2].clone());
+      add_op(mse_op(q, _weights(vec![4, 3])), mse_op(r, _weights(vec![3, 3])))
+    }),
+    // Only one of the two outputs is used.
+    Box::new(|xs| mse_op(qr_op(xs[2].clone()).0, _weights(vec![4, 3]))),
+    Box::new(|xs| mse_op(qr_op(xs[2].clone()).1, _weights(vec![3, 3]))),
+  ];
+  for f in builders.into_iter() {
+    let report = check_grad(move |xs| f(xs), vec![m.clone(), b.clone(), c.clone()], 1.0e-6, 1.0e-5).unwrap();
+    if !report.passed() {
+      report.print();
+    }
+    assert!(report.passed());
+  }
+}
+
+#[test]
+fn test_linalg_second_order() {
+  let m = Tensor::new(vec![3, 3], vec![1.0, 0.5, -0.2, 0.3, 2.0, 0.1, -0.4, 0.2, 1.5_f64]);
+  let b = Tensor::new(vec![3, 2], vec![1.0, 2.0, -1.0, 0.5, 0.25, 3.0_f64]);
+  let report = check_grad(|xs| {
+    let l = add_op(xs[0].clone(), constant_op(Tensor::new(vec![3, 3], vec![3.0, 0.0, 0.0, 0.0, 3.0, 0.0, 0.0, 0.0, 3.0])));
+    let loss = mse_op(solve_triangular_op(l, xs[1].clone(), true), _weights(vec![3, 2]));
+    let gs = grad(&loss, constant_op(Tensor::scalar(1.0)), xs).unwrap();
+    add_op(mse_op(gs[0].clone(), _weights(vec![3, 3])), mse_op(gs[1].clone(), _weights(vec![3, 2])))
+  }, vec![m.clone(), b], 1.0e-6, 1.0e-5).unwrap();
+  if !report.passed() {
+    report.print();
+  }
+  assert!(report.passed());
+  // Cholesky has no second-order rule, so differentiating twice fails.
+  let x = constant_op(m);
+  let loss = mse_op(cholesky_op(_spd(x.clone())), _weights(vec![3, 3]));
+  let gs = grad(&loss, constant_op(Tensor::scalar(1.0)), &[x.clone()]).unwrap();
+  match grad(&mse_op(gs[0].clone(), _weights(vec![3, 3])), constant_op(Tensor::scalar(1.0)), &[x]) {
+    Err(EvalError::NotDifferentiable(_)) => {}
+    other => panic!("expected NotDifferentiable: {:?}", other.map(|_| ())),
+  }
+}
@@ -0,0 +1,97 @@
+extern crate hebb;
+
+use hebb::experimental::rt1::*;
+use hebb::gradcheck::*;
+use hebb::nn::*;
+use hebb::optim::*;
+use hebb::tensor::*;
+
+#[test]
+fn test_nn_linear_mse() {
+  let x = constant_op(Tensor::new(vec![4, 2], vec![0.0, 1.0, 1.0, 0.0, 1.0, 1.0, 2.0, 1.0_f32]));
+  let y = constant_op(Tensor::new(vec![4, 1], vec![1.0, 2.0, 3.0, 5.0_f32]));
+  let layer = Linear::new(2, 1, 1);
+  let loss = mse_op(layer.apply(x), y);
+  let mut opt = Sgd::new(layer.params(), constant_op(0.1_f32));
+  let mut losses = Vec::new();
+  for _ in 0 .. 200 {
+    let t = txn();
+    let sink = backward(&loss, constant_op(Tensor::scalar(1.0_f32)), t, CheckpointPolicy::KeepAll).unwrap();
+    losses.push(loss._get_obj().get(t).data()[0]);
+    opt.step(&sink, t).unwrap();
+  }
+  assert!(losses[199] < 1.0e-3 * losses[0]);
+}
+
+#[test]
+fn test_nn_softmax_cross_entropy() {
+  let logits = constant_op(Tensor::new(vec![1, 3], vec![1.0, 2.0, 3.0_f64]));
+  let target = constant_op(Tensor::new(vec![1, 3], vec![0.0, 0.0, 1.0_f64]));
+  let fused = softmax_cross_entropy_op(logits.clone(), target.clone());
+  let unfused = cross_entropy_op(softmax_op(logits.clone()), target);
+  let t = txn();
+  let fused_sink = backward(&fused, constant_op(Tensor::scalar(1.0)), t, CheckpointPolicy::KeepAll).unwrap();
+  let unfused_sink = backward(&unfused, constant_op(Tensor::scalar(1.0)), t, CheckpointPolicy::KeepAll).unwrap();
+  let (l1, l2) = (fused._get_obj(), unfused._get_obj());
+  assert!((l1.get(t).data()[0] - l2.get(t).data()[0]).abs() < 1.0e-12);
+  let (g1, g2) = (fused_sink.get_adj(&logits).unwrap()._get_obj(), unfused_sink.get_adj(&logits).unwrap()._get_obj());
+  let (g1, g2) = (g1.get(t), g2.get(t));
+  for (a, b) in g1.data().iter().zip(g2.data().iter()) {
+    assert!((a - b).abs() < 1.0e-12);
+  }
+}
+
+#[test]
+fn test_nn_views() {
+  let x = constant_op(Tensor::new(vec![2, 3], vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0_f64]));
+  let v = transpose_view_op(reshape_op(x.clone(), vec![3, 2]));
+  let t = txn();
+  let (xv, vv) = (x._get_obj(), v._get_obj());
+  let (xv, vv) = (xv.get(t), vv.get(t));
+  assert!(vv.shares_buffer(&*xv));
+  assert!(!vv.is_contiguous());
+  assert_eq!(&[1.0, 3.0, 5.0, 2.0, 4.0, 6.0], vv.contiguous().data());
+  // The shared buffer is only counted by `x`, however many views exist.
+  let x_bytes = xv.nbytes();
+  let extra = vv.clone();
+  assert_eq!(x_bytes, xv.nbytes());
+  assert_eq!(6 * 8, x_bytes - vv.nbytes());
+  drop(extra);
+  let mut w = vv.clone();
+  w.data_mut()[0] = 0.0;
+  assert!(!w.shares_buffer(&*xv));
+  assert_eq!(x_bytes, w.nbytes());
+  assert_eq!(1.0, xv.data()[0]);
+  let (e1, e2) = (Tensor::<f64>::new(vec![0], vec![]), Tensor::<f64>::new(vec![0, 2], vec![]));
+  assert!(!e1.shares_buffer(&e2));
+  assert!(e1.shares_buffer(&e1.clone()));
+
+  let target = Tensor::new(vec![2, 3], vec![0.5; 6]);
+  let report = check_grad(|xs| {
+    let y = add_op(transpose_view_op(reshape_op(xs[0].clone(), vec![3, 2])), broadcast_op(xs[1].clone(), vec![2, 3]));
+    mse_op(y, constant_op(target.clone()))
+  }, vec![Tensor::new(vec![2, 3], vec![0.1, -0.2, 0.3, 0.4, -0.5, 0.6]), Tensor::new(vec![3], vec![1.0, 2.0, 3.0])], 1.0e-6, 1.0e-6).unwrap();
+  assert!(report.passed());
+}
+
+#[test]
+fn test_nn_second_order() {
+  // Checks the gradient of `mean(grad(loss)^2)`, which differentiates the
+  // adjoints of every op in `loss`.
+  let x = Tensor::new(vec![2, 3], vec![0.3, -0.8, 1.2, -0.1, 0.5, 0.9_f64]);
+  let p = Tensor::new(vec![2, 3], vec![0.2, 0.5, 0.3, 0.6, 0.1, 0.3_f64]);
+  let target = Tensor::new(vec![2, 3], vec![0.0, 0.0, 1.0, 0.0, 1.0, 0.0_f64]);
+  let report = check_grad(move |xs| {
+    let t = constant_op(target.clone());
+    let h = add_op(tanh_op(xs[0].clone()), sigmoid_op(relu_op(xs[0].clone())));
+    let loss = add_op(
+        softmax_cross_entropy_op(h, t.clone()),
+        add_op(mse_op(softmax_op(xs[0].clone()), t.clone()), cross_entropy_op(xs[1].clone(), xs[0].clone())));
+    let gs = grad(&loss, constant_op(Tensor::scalar(1.0)), xs).unwrap();
+    add_op(mse_op(gs[0].clone(), constant_op(Tensor::zeros(vec![2, 3]))), mse_op(gs[1].clone(), constant_op(Tensor::zeros(vec![2, 3]))))
+  }, vec![x, p], 1.0e-6, 1.0e-5).unwrap();
+  if !report.passed() {
+    report.print();
+  }
+  assert!(report.passed());
+}
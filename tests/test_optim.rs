@@ -0,0 +1,64 @@
+extern crate hebb;
+
+use hebb::experimental::rt1::*;
+use hebb::nn::*;
+use hebb::optim::*;
+use hebb::tensor::*;
+
+#[test]
+fn test_optim_sgd() {
+  let w = variable_op(1.0_f32);
+  let y = add_op(w.clone(), w.clone());
+  let mut opt = Sgd::new(vec![w.clone()], constant_op(0.25_f32));
+  for _ in 0 .. 2 {
+    let t = txn();
+    let sink = backward(&y, constant_op(1.0_f32), t, CheckpointPolicy::KeepAll).unwrap();
+    opt.step(&sink, t).unwrap();
+  }
+  let t = txn();
+  assert_eq!(0.0, *w._get_obj().get(t));
+  assert_eq!(0.0, *y._get_obj().get(t));
+  assert_eq!(2, *opt.step_counter()._get_obj().get(t));
+}
+
+#[test]
+fn test_optim_adam_schedule() {
+  let w = variable_op(1.0_f64);
+  let y = add_op(w.clone(), w.clone());
+  let step = step_counter();
+  let lr = exp_decay_op(step.clone(), 0.1, 0.5);
+  let mut opt = Adam::new(vec![w.clone()], lr.clone()).with_step_counter(step);
+  let t = txn();
+  let sink = backward(&y, constant_op(1.0_f64), t, CheckpointPolicy::KeepAll).unwrap();
+  opt.step(&sink, t).unwrap();
+  let t = txn();
+  // The first Adam step moves by roughly the learning rate.
+  assert!((*w._get_obj().get(t) - 0.9).abs() < 1.0e-6);
+  assert_eq!(0.05, *lr._get_obj().get(t));
+}
+
+#[test]
+fn test_optim_shape_mismatch() {
+  let w = variable_op(Tensor::new(vec![3], vec![1.0, 2.0, 3.0_f64]));
+  // Claims a cotangent of the wrong length for `w`.
+  let y = {
+    let w = w.clone();
+    tensor_op("bad_sum", vec![w.clone()], |xs| Tensor::scalar(xs[0].sum()), tensor_adjoint(move |_y, _dy, sink| {
+      sink.put_adj(&w, constant_op(Tensor::new(vec![2], vec![1.0, 1.0])));
+    }))
+  };
+  let mut opts: Vec<Box<Optimizer>> = vec![
+    Box::new(Sgd::new(vec![w.clone()], constant_op(0.1))),
+    Box::new(Momentum::new(vec![w.clone()], constant_op(0.1), 0.9)),
+    Box::new(Adam::new(vec![w.clone()], constant_op(0.1))),
+  ];
+  for opt in opts.iter_mut() {
+    let t = txn();
+    let sink = backward(&y, constant_op(Tensor::scalar(1.0)), t, CheckpointPolicy::KeepAll).unwrap();
+    match opt.step(&sink, t) {
+      Err(EvalError::ShapeMismatch(_)) => {}
+      _ => panic!("expected a shape mismatch"),
+    }
+  }
+  assert_eq!(vec![1.0, 2.0, 3.0], w._get_obj().get(txn()).data().to_vec());
+}
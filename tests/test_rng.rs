@@ -0,0 +1,28 @@
+extern crate hebb;
+
+use hebb::experimental::rt1::*;
+use hebb::rng::*;
+
+#[test]
+fn test_rng_txn_determinism() {
+  set_graph_seed(1234);
+  let x = uniform_op::<f32>(vec![16], 0.0, 1.0);
+  let t1 = txn();
+  let s1 = x._get_obj().get(t1).clone();
+  // Re-forcing in the same transaction reproduces the sample.
+  x.force_eval(t1);
+  assert_eq!(s1, *x._get_obj().get(t1));
+  let t2 = txn();
+  assert!(s1 != *x._get_obj().get(t2));
+  assert!(s1.data().iter().all(|&u| u >= 0.0 && u < 1.0));
+  // The stream is keyed by the thunk itself.
+  let mut rng = CounterRng::new(1234, x._stable(), t1);
+  assert_eq!(s1.data()[0], rng.next_f64() as f32);
+}
+
+#[test]
+fn test_rng_philox_kat() {
+  // Known-answer test from the Random123 distribution.
+  assert_eq!([0x6627e8d5, 0xe169c58d, 0xbc57ac4c, 0x9b00dbd8], philox4x32([0, 0], [0, 0, 0, 0]));
+  assert_eq!([0xd16cfe09, 0x94fdcceb, 0x5001e420, 0x24126ea1], philox4x32([0xa4093822, 0x299f31d0], [0x243f6a88, 0x85a308d3, 0x13198a2e, 0x03707344]));
+}
@@ -1,6 +1,18 @@
 extern crate hebb;
 
 use hebb::experimental::rt1::*;
+use hebb::num::*;
+
+use std::env;
+use std::fs::{File};
+use std::io::{Read};
+use std::panic::{self, AssertUnwindSafe};
+use std::cell::{RefCell};
+use std::rc::{Rc};
+use std::sync::{Mutex};
+
+// Serializes tests which modify the global `DefaultConfig`.
+static CFG_LOCK: Mutex<()> = Mutex::new(());
 
 #[test]
 fn test_rt1_add() {
@@ -28,3 +40,470 @@ fn test_rt1_switch() {
   //println!("DEBUG: y: {:?}", y.get(t));
   panic!();
 }
+
+#[test]
+fn test_rt1_force_all() {
+  let x = constant_op(1.0_f32);
+  let a = add_op(x.clone(), x.clone());
+  let b = add_op(a.clone(), x.clone());
+  let mut roots = TagVec::new();
+  roots.push(&a);
+  roots.push(&b);
+  let t = txn();
+  let results = roots.force_all(t);
+  assert_eq!(results.len(), 2);
+  assert!(results.iter().all(|r| r.is_ok()));
+  assert_eq!(2.0, *a._get_obj().get(t));
+  assert_eq!(3.0, *b._get_obj().get(t));
+
+  // Branches and loop captures are only forced when the entries demand
+  // them: the loop runs no iterations and the switch picks `x`.
+  let never = map_op("never", vec![], |_: &[&f32]| panic!("lazy free variable forced"), None);
+  let never_ = never.clone();
+  let l = while_op(|s| predicate_op("lt", s, |&s| s < 0.0), move |s| mul_op(s, never_.clone()), x.clone());
+  let s = switch_op(constant_op(true), never, x.clone());
+  let c = add_op(l, s);
+  let mut roots = TagVec::new();
+  roots.push(&c);
+  let t = txn();
+  assert!(roots.force_all(t).pop().unwrap().is_ok());
+  assert_eq!(2.0, *c._get_obj().get(t));
+}
+
+#[test]
+fn test_rt1_profile() {
+  let ctx = Rc::new(ProfileCtx::new());
+  push_ctx(ctx.clone());
+  let x1 = constant_op(1.0_f32);
+  let x2 = constant_op(2.0_f32);
+  let y = add_op(x1, x2);
+  let mut roots = TagVec::new();
+  roots.push(&y);
+  assert!(roots.force_all(txn()).iter().all(|r| r.is_ok()));
+  pop_ctx();
+  let report = ctx.profiler().report();
+  assert_eq!(3, report.by_thunk.len());
+  assert_eq!(2, report.by_op.len());
+  assert!(report.by_op.windows(2).all(|w| w[0].1.time >= w[1].1.time));
+  let &(_, ref const_stats) = report.by_op.iter().find(|&&(name, _)| name == "constant").unwrap();
+  assert_eq!(2, const_stats.count);
+  let &(_, ref add_stats) = report.by_op.iter().find(|&&(name, _)| name == "add").unwrap();
+  assert_eq!(1, add_stats.count);
+  assert_eq!(1, add_stats.alloc_count);
+  assert_eq!(4, add_stats.alloc_bytes);
+}
+
+#[test]
+fn test_rt1_trace() {
+  let _lock = CFG_LOCK.lock().unwrap();
+  let path = env::temp_dir().join("hebb_test_rt1_trace.json");
+  let mut cfg = default_cfg();
+  cfg.trace_path = Some(path.clone());
+  set_default_cfg(cfg.clone()).unwrap();
+  let x1 = constant_op(1.0_f32);
+  let x2 = constant_op(2.0_f32);
+  let y = add_op(x1, x2);
+  let mut roots = TagVec::new();
+  roots.push(&y);
+  assert!(roots.force_all(txn()).iter().all(|r| r.is_ok()));
+  cfg.trace_path = None;
+  set_default_cfg(cfg).unwrap();
+  let mut trace = String::new();
+  File::open(&path).unwrap().read_to_string(&mut trace).unwrap();
+  assert!(trace.starts_with("["));
+  assert!(trace.contains("\"name\":\"add\",\"cat\":\"thunk\",\"ph\":\"B\""));
+  assert!(trace.contains("\"name\":\"add\",\"cat\":\"thunk\",\"ph\":\"E\""));
+  // A bad trace path is reported when it is set, and does not stop evaluation.
+  let mut cfg = default_cfg();
+  cfg.trace_path = Some(env::temp_dir().join("hebb_no_such_dir").join("trace.json"));
+  assert!(set_default_cfg(cfg.clone()).is_err());
+  let mut roots = TagVec::new();
+  roots.push(&add_op(constant_op(1.0_f32), constant_op(2.0_f32)));
+  assert!(roots.force_all(txn()).iter().all(|r| r.is_ok()));
+  cfg.trace_path = None;
+  set_default_cfg(cfg).unwrap();
+}
+
+#[test]
+fn test_rt1_mem_budget() {
+  let _lock = CFG_LOCK.lock().unwrap();
+  let mut cfg = default_cfg();
+  cfg.mem_budget = Some(8);
+  set_default_cfg(cfg.clone()).unwrap();
+  let x = constant_op(1.0_f32);
+  let a = add_op(x.clone(), x.clone());
+  let b = add_op(a.clone(), x.clone());
+  let c = add_op(b.clone(), x.clone());
+  let mut roots = TagVec::new();
+  roots.push(&c);
+  let t = txn();
+  assert!(roots.force_all(t).iter().all(|r| r.is_ok()));
+  assert!(resident_bytes() <= 8);
+  // Evicted payloads are recomputed on demand.
+  assert_eq!(2.0, *a._get_obj().get(t));
+  assert_eq!(3.0, *b._get_obj().get(t));
+  assert_eq!(4.0, *c._get_obj().get(t));
+  cfg.mem_budget = None;
+  set_default_cfg(cfg).unwrap();
+}
+
+#[test]
+fn test_rt1_backward_checkpoint() {
+  // The adjoint of `mul` reads the forward values, so evicted links of the
+  // chain must be recomputed during the backward pass.
+  let mut recomputes = Vec::new();
+  let mut resident = Vec::new();
+  for &policy in [CheckpointPolicy::KeepAll, CheckpointPolicy::Sqrt].iter() {
+    let x = constant_op(2.0_f64);
+    let mut chain = vec![x.clone()];
+    for _ in 0 .. 8 {
+      let h = mul_op(chain.last().unwrap().clone(), x.clone());
+      chain.push(h);
+    }
+    let h = chain.last().unwrap().clone();
+    let ctx = Rc::new(ProfileCtx::new());
+    push_ctx(ctx.clone());
+    let t = txn();
+    let bytes0 = resident_bytes();
+    let sink = backward(&h, constant_op(1.0), t, policy).unwrap();
+    let bytes1 = resident_bytes();
+    pop_ctx();
+    assert_eq!(2304.0, *sink.get_adj(&x).unwrap()._get_obj().get(t));
+    assert_eq!(512.0, *h._get_obj().get(t));
+    let report = ctx.profiler().report();
+    let evals: u64 = report.by_thunk.iter()
+      .filter(|&&(stable, _, _)| chain.iter().any(|c| c._stable() == stable))
+      .map(|&(_, _, ref stats)| stats.count)
+      .sum();
+    recomputes.push(evals - chain.len() as u64);
+    resident.push(bytes1 - bytes0);
+  }
+  assert_eq!(0, recomputes[0]);
+  assert!(recomputes[1] > 0);
+  assert!(resident[1] < resident[0]);
+}
+
+#[test]
+fn test_rt1_arith_dtypes() {
+  let t = txn();
+  let n = sub_op(mul_op(constant_op(6_i64), constant_op(7_i64)), constant_op(2_i64));
+  assert_eq!(40, *n._get_obj().get(t));
+  let x1 = constant_op(Complex::new(1.0_f64, 2.0));
+  let x2 = constant_op(Complex::new(3.0_f64, -1.0));
+  let y = div_op(mul_op(x1.clone(), x2.clone()), x2.clone());
+  let sink = backward(&y, constant_op(Complex::one()), t, CheckpointPolicy::KeepAll).unwrap();
+  assert_eq!(Complex::new(1.0, 2.0), *y._get_obj().get(t));
+  let dx1 = sink.get_adj(&x1).unwrap()._get_obj().get(t).clone();
+  let dx2 = sink.get_adj(&x2).unwrap()._get_obj().get(t).clone();
+  assert!((dx1 - Complex::one()).norm() < 1.0e-12);
+  assert!(dx2.norm() < 1.0e-12);
+}
+
+#[test]
+fn test_rt1_stop_custom_grad() {
+  let t = txn();
+  let x = constant_op(3.0_f64);
+  let y = add_op(mul_op(x.clone(), x.clone()), stop_gradient(mul_op(x.clone(), x.clone())));
+  let sink = backward(&y, constant_op(1.0), t, CheckpointPolicy::KeepAll).unwrap();
+  assert_eq!(18.0, *y._get_obj().get(t));
+  assert_eq!(6.0, *sink.get_adj(&x).unwrap()._get_obj().get(t));
+
+  // Straight-through rounding: forward rounds, backward is the identity.
+  let x = constant_op(2.7_f64);
+  let r = custom_grad(x.clone(), |x| map_op("round", vec![x], |xs| xs[0].round(), None), |_pass, x, _y, dy, sink| {
+    sink.put_adj(&x, dy);
+  });
+  // Gradient clipping to [-1, 1].
+  let c = custom_grad(r.clone(), |r| mul_op(r.clone(), constant_op(5.0)), |_pass, r, _y, dy, sink| {
+    let dr = mul_op(dy, constant_op(5.0));
+    sink.put_adj(&r, map_op("clip", vec![dr], |xs| xs[0].max(-1.0).min(1.0), None));
+  });
+  let sink = backward(&c, constant_op(1.0), t, CheckpointPolicy::KeepAll).unwrap();
+  assert_eq!(15.0, *c._get_obj().get(t));
+  assert_eq!(1.0, *sink.get_adj(&x).unwrap()._get_obj().get(t));
+}
+
+#[test]
+fn test_rt1_hvp() {
+  // f(x, y) = x^2 y + y^3, with Hessian [[2y, 2x], [2x, 6y]].
+  let t = txn();
+  let x = constant_op(1.0_f64);
+  let y = constant_op(2.0_f64);
+  let f = add_op(mul_op(mul_op(x.clone(), x.clone()), y.clone()), mul_op(y.clone(), mul_op(y.clone(), y.clone())));
+  let v = vec![constant_op(1.0), constant_op(-1.0)];
+  let gs = grad(&f, constant_op(1.0), &[x.clone(), y.clone()]).unwrap();
+  let hv = hvp(&f, &[x.clone(), y.clone()], &v).unwrap();
+  assert_eq!(4.0, *gs[0]._get_obj().get(t));
+  assert_eq!(13.0, *gs[1]._get_obj().get(t));
+  assert_eq!(2.0, *hv[0]._get_obj().get(t));
+  assert_eq!(-10.0, *hv[1]._get_obj().get(t));
+  // Thunks are attributed to the pass which built them only while its sink
+  // is alive.
+  let sink = reverse(&f, constant_op(1.0)).unwrap();
+  let dx = sink.get_adj(&x).unwrap();
+  assert_eq!(Some(sink.pass()), dx.pass());
+  drop(sink);
+  assert_eq!(None, dx.pass());
+  assert_eq!(None, gs[0].pass());
+}
+
+#[test]
+fn test_rt1_redirect() {
+  let x = constant_op(3.0_f32);
+  let y = identity_op(x.clone());
+  let t = txn();
+  let (xv, yv) = (x._get_obj(), y._get_obj());
+  assert_eq!(3.0, *yv.get(t));
+  assert!(&*yv.get(t) as *const f32 == &*xv.get(t) as *const f32);
+  assert!(y._get_data().alias().is_some());
+  // Writing through the alias copies the shared payload first.
+  *y._get_data().get_mut(t) += 1.0;
+  assert_eq!(4.0, *yv.get(t));
+  assert_eq!(3.0, *xv.get(t));
+  assert!(y._get_data().alias().is_none());
+
+  // Only the selected branch of a switch is evaluated.
+  let c = constant_op(true);
+  let never = map_op("never", vec![], |_: &[&f32]| panic!("unselected branch evaluated"), None);
+  let s = switch_op(c, never.clone(), x.clone());
+  assert_eq!(3.0, *s._get_obj().get(t));
+  // Nor by `force_all`, which leaves the branches to the switch's entry.
+  let s = switch_op(constant_op(true), never, x.clone());
+  let mut roots = TagVec::new();
+  roots.push(&add_op(s.clone(), s.clone()));
+  for result in roots.force_all(t) {
+    result.unwrap();
+  }
+  assert_eq!(3.0, *s._get_obj().get(t));
+}
+
+#[test]
+fn test_rt1_effects() {
+  let log = Rc::new(RefCell::new(Vec::new()));
+  let x = constant_op(1.0_f32);
+  let w = variable_op(0.0_f32);
+  let mut tok = token_op();
+  for &k in [1.0_f32, 2.0, 3.0].iter() {
+    let log = log.clone();
+    let y = add_op(x.clone(), constant_op(k));
+    tok = effect_op("log", tok, vec![y], move |_txn, xs| {
+      log.borrow_mut().push(*xs[0]);
+      true
+    });
+  }
+  tok = assign_op(tok, &w, constant_op(5.0_f32));
+  let t = txn();
+  assert_eq!(4, run_effects(&tok, t).unwrap().seq());
+  assert_eq!(vec![2.0, 3.0, 4.0], *log.borrow());
+  // Forcing again in the same txn does not repeat the effects.
+  run_effects(&tok, t).unwrap();
+  assert_eq!(3, log.borrow().len());
+  assert_eq!(5.0, *w._get_data().get(t));
+  let t = txn();
+  run_effects(&tok, t).unwrap();
+  assert_eq!(6, log.borrow().len());
+}
+
+#[test]
+fn test_rt1_loops() {
+  // Squares 2 until it exceeds 100: 2^8, with derivative 8 * 2^7.
+  let x = constant_op(2.0_f64);
+  let y = while_op(|s| predicate_op("lt", s, |&s| s < 100.0), |s| mul_op(s.clone(), s), x.clone());
+  let t = txn();
+  assert_eq!(256.0, *y._get_obj().get(t));
+  let dx = grad(&y, constant_op(1.0), &[x.clone()]).unwrap();
+  assert_eq!(1024.0, *dx[0]._get_obj().get(t));
+
+  // Running product of the inputs, with each partial product as output.
+  let xs: Vec<_> = [2.0_f64, 3.0, 4.0].iter().map(|&v| constant_op(v)).collect();
+  let c0 = constant_op(1.0_f64);
+  let (c, ys) = scan_op(|c, x| {
+    let next = mul_op(c, x);
+    (next.clone(), next)
+  }, c0.clone(), xs.clone());
+  let t = txn();
+  assert_eq!(24.0, *c._get_obj().get(t));
+  let vals: Vec<f64> = ys.iter().map(|y| *y._get_obj().get(t)).collect();
+  assert_eq!(vec![2.0, 6.0, 24.0], vals);
+  let gs = grad(&c, constant_op(1.0), &[c0.clone(), xs[0].clone(), xs[1].clone(), xs[2].clone()]).unwrap();
+  let gs: Vec<f64> = gs.iter().map(|g| *g._get_obj().get(t)).collect();
+  assert_eq!(vec![24.0, 12.0, 8.0, 6.0], gs);
+  let gs = grad(&ys[1], constant_op(1.0), &[c0, xs[0].clone(), xs[1].clone(), xs[2].clone()]).unwrap();
+  let gs: Vec<f64> = gs.iter().map(|g| *g._get_obj().get(t)).collect();
+  assert_eq!(vec![6.0, 3.0, 2.0, 0.0], gs);
+}
+
+#[test]
+fn test_rt1_loop_captures() {
+  // Multiplies by the captured `w` until the state reaches 100: x * w^5.
+  let (x, w) = (constant_op(1.0_f64), constant_op(3.0_f64));
+  let w_ = w.clone();
+  let y = while_op(|s| predicate_op("lt", s, |&s| s < 100.0), move |s| mul_op(s, w_.clone()), x.clone());
+  let dx = grad(&y, constant_op(1.0), &[x.clone(), w.clone()]).unwrap();
+  // Cotangents replay the evaluation in their own txn, in any number of txns.
+  for _ in 0 .. 2 {
+    let t = txn();
+    assert_eq!(243.0, *y._get_obj().get(t));
+    assert_eq!(243.0, *dx[0]._get_obj().get(t));
+    assert_eq!(405.0, *dx[1]._get_obj().get(t));
+  }
+  let t = txn();
+  assert_eq!(405.0, *dx[1]._get_obj().get(t));
+
+  // Adds each input scaled by the captured `w`: c0 + w * (2 + 3 + 4).
+  let xs: Vec<_> = [2.0_f64, 3.0, 4.0].iter().map(|&v| constant_op(v)).collect();
+  let (c0, w_) = (constant_op(1.0_f64), w.clone());
+  let (c, _) = scan_op(move |c, x| {
+    let next = add_op(c, mul_op(x, w_.clone()));
+    (next.clone(), next)
+  }, c0.clone(), xs.clone());
+  let gs = grad(&c, constant_op(1.0), &[c0, xs[1].clone(), w.clone()]).unwrap();
+  let t = txn();
+  assert_eq!(28.0, *c._get_obj().get(t));
+  let gs: Vec<f64> = gs.iter().map(|g| *g._get_obj().get(t)).collect();
+  assert_eq!(vec![1.0, 3.0, 9.0], gs);
+
+  // Iterations after the first capture `v`, which building the loop did
+  // not see, so its cotangent cannot be passed on.
+  let v = constant_op(2.0_f64);
+  let (w_, v_) = (w.clone(), v.clone());
+  let calls = Rc::new(RefCell::new(0));
+  let y = while_op(|s| predicate_op("lt", s, |&s| s < 100.0), move |s| {
+    *calls.borrow_mut() += 1;
+    mul_op(s, if *calls.borrow() == 1 { w_.clone() } else { v_.clone() })
+  }, x.clone());
+  let dx = grad(&y, constant_op(1.0), &[x]).unwrap();
+  match dx[0]._get_obj().try_get(txn()) {
+    Err(EvalError::NotDifferentiable(_)) => {}
+    other => panic!("expected NotDifferentiable: {:?}", other.map(|_| ())),
+  }
+
+  // The thunks of earlier evaluations are reclaimed.
+  let x = constant_op(2.0_f64);
+  let y = while_op(|s| predicate_op("lt", s, |&s| s < 100.0), |s| mul_op(s.clone(), s), x.clone());
+  let dx = grad(&y, constant_op(1.0), &[x]).unwrap();
+  let mut held = None;
+  for _ in 0 .. 100 {
+    let t = txn();
+    assert_eq!(256.0, *y._get_obj().get(t));
+    assert_eq!(1024.0, *dx[0]._get_obj().get(t));
+    // Each evaluation replaces the thunks of the previous one.
+    assert_eq!(*held.get_or_insert(heap_len()), heap_len());
+  }
+}
+
+#[test]
+fn test_rt1_letrec() {
+  // `a` refers to `b` and `b` to `a`, but `a` only forces `b` when `c` is
+  // false.
+  let one = constant_op(1.0_f32);
+  for &c in [true, false].iter() {
+    let cond = constant_op(c);
+    let (cond_, one_) = (cond.clone(), one.clone());
+    let group = letrec(2, move |xs| vec![
+      SwitchOp::build_thunk(cond_, xs[1].clone(), one_.clone()),
+      AddOp::build_thunk(xs[0].clone(), one_),
+    ]);
+    let mut roots = TagVec::new();
+    roots.push(&group[1]);
+    let t = txn();
+    let result = roots.force_all(t).pop().unwrap();
+    if c {
+      assert!(result.is_ok());
+      assert_eq!(2.0, *group[1]._get_obj().get(t));
+    } else {
+      match result {
+        Err(EvalError::BlackHole(_)) => {}
+        other => panic!("expected a black hole: {:?}", other),
+      }
+    }
+  }
+}
+
+#[test]
+fn test_rt1_lazy_stream() {
+  let nats = LazyStream::iterate(0_u64, |&n| n + 1);
+  let squares = nats.map(|&n| n * n);
+  let sums = squares.scan(0, |&acc, &x| acc + x);
+  let t = txn();
+  let pairs: Vec<_> = nats.zip(&sums).take(5).iter(t).collect::<Result<_, _>>().unwrap();
+  assert_eq!(vec![(0, 0), (1, 1), (2, 5), (3, 14), (4, 30)], pairs);
+  let evens: Vec<u64> = LazyStream::unfold(0_u64, |&n| if n < 10 { Some((n, n + 2)) } else { None })
+    .iter(t).collect::<Result<_, _>>().unwrap();
+  assert_eq!(vec![0, 2, 4, 6, 8], evens);
+  let xs = LazyStream::cons(1, LazyStream::cons(2, LazyStream::empty()));
+  assert_eq!(2, xs.iter(t).count());
+}
+
+#[test]
+fn test_rt1_lazy_stream_reclaim() {
+  let t = txn();
+  let before = heap_len();
+  // Only the iterator holds the streams, so consumed cells are reclaimed.
+  let mut it = {
+    let nats = LazyStream::iterate(0_u64, |&n| n + 1);
+    nats.zip(&nats.map(|&n| 2 * n).scan(0, |&acc, &x| acc + x)).take(1000).iter(t)
+  };
+  let mut peak = heap_len();
+  let mut count = 0;
+  for x in it.by_ref() {
+    assert_eq!(count * (count + 1), x.unwrap().1);
+    peak = peak.max(heap_len());
+    count += 1;
+  }
+  assert_eq!(1000, count);
+  assert!(peak < before + 20, "heap grew to {} objects", peak - before);
+  drop(it);
+  assert_eq!(before, heap_len());
+
+  // Forcing a held stream again in a new txn replaces the tails built by
+  // the previous force, and those are reclaimed.
+  let doubles = LazyStream::iterate(0_u64, |&n| n + 1).map(|&n| 2 * n);
+  let held = heap_len();
+  for _ in 0 .. 100 {
+    let cell = doubles.force(txn()).unwrap().unwrap();
+    assert_eq!(0, cell.head);
+  }
+  assert!(heap_len() <= held + 8, "heap grew to {} objects", heap_len() - held);
+}
+
+fn fact_op(n: ThunkRef<f64>) -> ThunkRef<f64> {
+  // The recursion depth is decided by the value of `n`.
+  dynamic_op("fact", vec![n.clone()], move |ctx, xs| {
+    let n = *xs[0];
+    Ok(ctx.build(|| if n <= 1.0 {
+      constant_op(1.0)
+    } else {
+      mul_op(constant_op(n), fact_op(constant_op(n - 1.0)))
+    }))
+  })
+}
+
+#[test]
+fn test_rt1_dynamic() {
+  let t = txn();
+  assert_eq!(120.0, *fact_op(constant_op(5.0))._get_obj().get(t));
+  assert_eq!(1.0, *fact_op(constant_op(0.0))._get_obj().get(t));
+  // Sub-thunks may also be forced from inside the entry.
+  let x = constant_op(3.0_f64);
+  let y = dynamic_op("pow", vec![x], |ctx, xs| {
+    let mut acc = constant_op(1.0);
+    for _ in 0 .. 4 {
+      acc = ctx.build(|| mul_op(acc.clone(), constant_op(*xs[0])));
+      assert!(ctx.get(&acc)? >= 1.0);
+    }
+    Ok(acc)
+  });
+  assert_eq!(81.0, *y._get_obj().get(t));
+}
+
+#[test]
+fn test_rt1_dynamic_panic() {
+  // A panicking entry does not leave its context behind.
+  let y = dynamic_op("boom", vec![constant_op(1.0_f64)], |_ctx, _xs| -> Result<ThunkRef<f64>, EvalError> {
+    panic!("boom")
+  });
+  let t = txn();
+  assert!(panic::catch_unwind(AssertUnwindSafe(|| y.force_eval(t))).is_err());
+  assert!(EntryCtx::current().is_none());
+}
@@ -0,0 +1,65 @@
+extern crate hebb;
+
+use hebb::experimental::rt1::*;
+use hebb::nn::*;
+use hebb::tensor::*;
+use hebb::vmap::*;
+
+#[test]
+fn test_vmap_mlp() {
+  let w = Tensor::new(vec![3, 2], vec![0.5, -1.0, 2.0, 0.25, -0.5, 1.5_f64]);
+  let b = Tensor::new(vec![2], vec![0.1, -0.2_f64]);
+  let (w_, b_) = (w.clone(), b.clone());
+  let f = move |x: TensorRef<f64>| {
+    let h = bias_add_op(matmul_op(x, constant_op(w_.clone())), constant_op(b_.clone()));
+    softmax_op(mul_op(tanh_op(h), constant_op(Tensor::scalar(2.0))))
+  };
+  let examples: Vec<Tensor<f64>> = (0 .. 4).map(|i| {
+    Tensor::new(vec![1, 3], vec![i as f64, 1.0 - i as f64, 0.5 * i as f64])
+  }).collect();
+  let mut flat = Vec::new();
+  for e in examples.iter() {
+    flat.extend_from_slice(e.data());
+  }
+  let t = txn();
+  // Batch along the middle axis, i.e. the examples are `xs[:, i, :]`.
+  let xs = Tensor::new(vec![4, 1, 3], flat).move_axis(0, 1);
+  let g = vmap(f.clone(), 1);
+  let ys = g(constant_op(xs)).unwrap();
+  let ys = ys._get_obj().get(t).clone();
+  assert_eq!(&[4, 1, 2], ys.shape());
+  for (i, e) in examples.iter().enumerate() {
+    let y = f(constant_op(e.clone()));
+    let y = y._get_obj().get(t).clone();
+    for k in 0 .. 2 {
+      assert!((ys.data()[i * 2 + k] - y.data()[k]).abs() < 1.0e-12);
+    }
+  }
+}
+
+#[test]
+fn test_vmap_missing_rule() {
+  let g = vmap(|x: TensorRef<f64>| sum_rows_op(x), 0);
+  match g(constant_op(Tensor::zeros(vec![2, 3, 3]))) {
+    Err(EvalError::MissingBatchRule(_)) => {}
+    _ => panic!(),
+  }
+}
+
+#[test]
+fn test_vmap_broadcast() {
+  // The captured value has as many axes as the batched input, but is not
+  // batched, so it is still repeated per example.
+  let c = Tensor::new(vec![2, 3], vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0_f64]);
+  let c_ = c.clone();
+  let g = vmap(move |x: TensorRef<f64>| add_op(mul_op(x, constant_op(Tensor::scalar(0.0))), constant_op(c_.clone())), 0);
+  let t = txn();
+  let ys = g(constant_op(Tensor::zeros(vec![4, 2, 3]))).unwrap();
+  let ys = ys._get_obj().get(t).clone();
+  assert_eq!(&[4, 2, 3], ys.shape());
+  let h = vmap(move |_x: TensorRef<f64>| constant_op(c.clone()), 0);
+  let zs = h(constant_op(Tensor::zeros(vec![4, 2]))).unwrap();
+  let zs = zs._get_obj().get(t).clone();
+  assert_eq!(&[4, 2, 3], zs.shape());
+  assert_eq!(&[1.0, 2.0, 3.0], &zs.contiguous().data()[6 .. 9]);
+}